@@ -0,0 +1,112 @@
+//! Benchmarks for decoding RESP2 input that arrives in chunks
+//!
+//! `resumed` keeps the partial-parse state between chunks (as the `StreamCodec` does), while
+//! `restarted` parses the buffered input from scratch whenever a chunk arrives.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Bencher, Criterion};
+
+use evredis::codecs::resp2::DecodeState;
+use evredis::codecs::Limits;
+
+/// The size of the chunks input arrives in
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A pipeline of `count` `SET` commands, all in a single array
+fn pipeline(count: usize) -> Vec<u8> {
+    let mut data = format!("*{}\r\n", count * 3).into_bytes();
+    for i in 0..count {
+        let key = format!("key:{}", i);
+        let value = format!("value:{}", i);
+        data.extend_from_slice(
+            format!(
+                "$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                key.len(),
+                key,
+                value.len(),
+                value
+            )
+            .as_bytes(),
+        );
+    }
+    data
+}
+
+/// A single `SET` command with a value of `size` bytes
+fn bulk(size: usize) -> Vec<u8> {
+    let mut data = format!("*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n${}\r\n", size).into_bytes();
+    data.resize(data.len() + size, b'x');
+    data.extend_from_slice(b"\r\n");
+    data
+}
+
+fn decode_resumed(input: &[u8]) {
+    let limits = Limits::default();
+    let mut state = DecodeState::default();
+    let mut buffer = BytesMut::new();
+    for chunk in input.chunks(CHUNK_SIZE) {
+        buffer.extend_from_slice(chunk);
+        if state.decode(&mut buffer, &limits).unwrap().is_some() {
+            return;
+        }
+    }
+    panic!("Incomplete input");
+}
+
+fn decode_restarted(input: &[u8]) {
+    let limits = Limits::default();
+    let mut buffer = BytesMut::new();
+    for chunk in input.chunks(CHUNK_SIZE) {
+        buffer.extend_from_slice(chunk);
+        let mut attempt = buffer.clone();
+        if DecodeState::default()
+            .decode(&mut attempt, &limits)
+            .unwrap()
+            .is_some()
+        {
+            return;
+        }
+    }
+    panic!("Incomplete input");
+}
+
+fn bench_pipelines(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "pipeline resumed",
+        |b: &mut Bencher, &count: &usize| {
+            let input = pipeline(count);
+            b.iter(|| decode_resumed(&input))
+        },
+        vec![1_000, 10_000, 100_000],
+    );
+    c.bench_function_over_inputs(
+        "pipeline restarted",
+        |b: &mut Bencher, &count: &usize| {
+            let input = pipeline(count);
+            b.iter(|| decode_restarted(&input))
+        },
+        vec![1_000, 10_000, 100_000],
+    );
+}
+
+fn bench_bulk_strings(c: &mut Criterion) {
+    c.bench_function_over_inputs(
+        "bulk resumed",
+        |b: &mut Bencher, &size: &usize| {
+            let input = bulk(size);
+            b.iter(|| decode_resumed(&input))
+        },
+        vec![1 << 20, 8 << 20],
+    );
+    c.bench_function_over_inputs(
+        "bulk restarted",
+        |b: &mut Bencher, &size: &usize| {
+            let input = bulk(size);
+            b.iter(|| decode_restarted(&input))
+        },
+        vec![1 << 20, 8 << 20],
+    );
+}
+
+criterion_group!(benches, bench_pipelines, bench_bulk_strings);
+criterion_main!(benches);
@@ -1,15 +1,21 @@
-use serde_derive::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use serde_derive::{Deserialize, Serialize};
+use structopt::StructOpt;
 
 use slog::slog_info;
 use slog_scope::info;
 
-use actix::System;
+use actix::{Actor, System};
 
-use evredis::server::ServerConfiguration;
-use evredis::utils::configuration::Configuration;
+use evredis::server::config::{LiveConfiguration, Settings};
+use evredis::server::reload::Reloader;
+use evredis::server::{self, ServerConfiguration};
+use evredis::utils::configuration::{Configuration, ConfigurationError, Sources};
 use evredis::utils::logging::LoggingConfiguration;
 
-#[derive(Debug, Default, Clone, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(default)]
 struct RootConfiguration {
     logging: LoggingConfiguration,
@@ -19,11 +25,137 @@ impl Configuration for RootConfiguration {
     const VERSION_REQUIREMENT: &'static str = "^0.1";
 }
 
+/// The port used with `--bind` if no port is configured
+const DEFAULT_PORT: u16 = 6379;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "evredis",
+    after_help = "Any configuration key can be overridden with `--<section>.<key> <value>` (like \
+                  `--server.databases 4`), where values are parsed as TOML if possible."
+)]
+struct Args {
+    /// Load the configuration from this TOML file (instead of the default locations)
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Listen on this port (on all configured addresses)
+    #[structopt(long = "port")]
+    port: Option<u16>,
+    /// Listen on these addresses (instead of the configured ones)
+    #[structopt(long = "bind")]
+    bind: Vec<IpAddr>,
+    /// The minimum logging level
+    #[structopt(long = "loglevel")]
+    loglevel: Option<String>,
+    /// Check the configuration and exit
+    #[structopt(long = "test-config")]
+    test_config: bool,
+    /// Print the effective configuration (as TOML) and exit
+    #[structopt(long = "print-config")]
+    print_config: bool,
+}
+
+/// Split `--<section>.<key> <value>` (or `--<section>.<key>=<value>`) overrides from the other
+/// arguments
+fn split_overrides(
+    args: impl Iterator<Item = String>,
+) -> Result<(Vec<String>, Vec<(String, String)>), String> {
+    let mut args = args;
+    let mut remaining = Vec::new();
+    let mut overrides = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            remaining.push(arg);
+            remaining.extend(args);
+            break;
+        }
+        let key = match arg.get(2..) {
+            Some(key) if arg.starts_with("--") && key.split('=').next().unwrap().contains('.') => {
+                key
+            }
+            _ => {
+                remaining.push(arg);
+                continue;
+            }
+        };
+        let (key, value) = match key.find('=') {
+            Some(index) => (key[..index].to_owned(), key[index + 1..].to_owned()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for --{}", key))?;
+                (key.to_owned(), value)
+            }
+        };
+        overrides.push((key, value));
+    }
+    Ok((remaining, overrides))
+}
+
+impl Args {
+    /// Load the configuration, applying the command-line arguments on top of it
+    fn load(
+        &self,
+        overrides: &[(String, String)],
+    ) -> Result<RootConfiguration, ConfigurationError> {
+        let mut sources = Sources {
+            file: self.config.clone(),
+            overrides: overrides.to_vec(),
+        };
+        if let Some(ref level) = self.loglevel {
+            sources
+                .overrides
+                .push(("logging.level".to_owned(), level.clone()));
+        }
+        let mut config = RootConfiguration::load_from(&sources)?;
+
+        if !self.bind.is_empty() || self.port.is_some() {
+            let listen_on = &config.server.listen_on;
+            let port = self
+                .port
+                .or_else(|| listen_on.first().map(|x| x.port()))
+                .unwrap_or(DEFAULT_PORT);
+            let mut addresses = self.bind.clone();
+            if addresses.is_empty() {
+                addresses = listen_on.iter().map(|x| x.ip()).collect();
+                addresses.sort();
+                addresses.dedup();
+            }
+            config.server.listen_on = addresses
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+        }
+        Ok(config)
+    }
+}
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() -> Result<(), Box<std::error::Error>> {
-    let config = RootConfiguration::load()?;
-    let handle = config.logging.create_global_logger();
+    let (args, overrides) = split_overrides(std::env::args())?;
+    let args = Args::from_iter(args);
+    let config = args.load(&overrides)?;
+
+    if args.print_config {
+        print!(
+            "{}",
+            toml::to_string_pretty(&toml::Value::try_from(&config)?)?
+        );
+    }
+    if args.test_config {
+        Settings {
+            server: config.server.clone(),
+            logging: config.logging.clone(),
+        }
+        .validate()?;
+        eprintln!("Configuration is valid");
+    }
+    if args.print_config || args.test_config {
+        return Ok(());
+    }
+
+    let (guard, logging) = config.logging.create_global_logger()?;
 
     let system = System::new("evredis");
 
@@ -34,11 +166,27 @@ fn main() -> Result<(), Box<std::error::Error>> {
 
     info!("evredis v{}", VERSION);
 
-    config.server.start_server()?;
+    let mut live = LiveConfiguration::new(config.server).with_logging(logging);
+    let file = match args.config {
+        Some(ref path) => Some(path.clone()),
+        None => RootConfiguration::file()?,
+    };
+    if let Some(path) = file {
+        live = live.with_file(path);
+    }
+    let server = server::start(live)?;
+    Reloader::new(
+        server,
+        Box::new(move || {
+            let config = args.load(&overrides)?;
+            Ok((config.server, config.logging))
+        }),
+    )
+    .start();
 
     let code = system.run();
 
     info!("Shutting down...");
-    drop(handle);
+    drop(guard);
     std::process::exit(code);
 }
@@ -1,9 +1,12 @@
 //! Codecs for Redis commands/responses
 
+use std::fmt;
 use std::io;
 use std::marker::PhantomData;
 use std::num::ParseIntError;
 use std::str::Utf8Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use slog::slog_debug;
 use slog_scope::debug;
@@ -13,9 +16,11 @@ use quick_error::quick_error;
 use bytes::{Bytes, BytesMut};
 use tokio_codec::{Decoder, Encoder};
 
+use crate::protocol::commands;
 use crate::protocol::{Command, Response};
 
 pub mod resp2;
+pub mod resp3;
 
 quick_error! {
     /// An error encountered during value encoding
@@ -37,12 +42,26 @@ quick_error! {
         UnrecognizedCommand(cmd: Bytes) {}
         /// Unexpected number of arguments to a command
         UnexpectedNumberOfArguments {}
-        /// Invalid length value for bulk string/array
+        /// A number of arguments that doesn't match the command's arity
+        WrongArity(cmd: &'static str) {
+            display("Wrong number of arguments for '{}'", cmd)
+        }
+        /// Unrecognized subcommand of a container command
+        UnknownSubcommand(cmd: &'static str, subcommand: Bytes) {}
+        /// Invalid (or too large) length value for bulk string
         InvalidLength {}
+        /// Invalid (or too large) length value for array
+        InvalidMultibulkLength {}
+        /// An inline command that exceeds the maximum line length
+        InlineTooLong {}
+        /// More pending input than the query buffer limit allows
+        QueryBufferLimit {}
         /// Invalid datatype for command
         InvalidDataType {}
         /// Invalid argument
         InvalidArgument {}
+        /// Unbalanced quotes in an inline command
+        UnbalancedQuotes {}
         /// Invalid string value
         InvalidString(err: Utf8Error) {
             display("Invalid string: {}", err)
@@ -56,9 +75,65 @@ quick_error! {
     }
 }
 
+impl DecodeError {
+    /// The description of a protocol violation, after which the connection is closed
+    ///
+    /// Other errors only affect a single, well-formed command.
+    pub fn protocol_error(&self) -> Option<String> {
+        Some(match self {
+            DecodeError::UnexpectedByte(byte) => {
+                format!("unexpected byte '{}'", char::from(*byte).escape_default())
+            }
+            DecodeError::InvalidDataType => "expected an array of bulk strings".to_owned(),
+            DecodeError::InvalidLength => "invalid bulk length".to_owned(),
+            DecodeError::InvalidMultibulkLength => "invalid multibulk length".to_owned(),
+            DecodeError::InlineTooLong => "too big inline request".to_owned(),
+            DecodeError::UnbalancedQuotes => "unbalanced quotes in request".to_owned(),
+            DecodeError::QueryBufferLimit => "query buffer limit exceeded".to_owned(),
+            _ => return None,
+        })
+    }
+}
+
+/// Limits on the size of incoming commands, so that clients can't exhaust the server's memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum length of a bulk string
+    pub max_bulk_len: usize,
+    /// The maximum number of elements in an array
+    pub max_multibulk_len: usize,
+    /// The maximum number of bytes buffered for commands that haven't been fully received
+    pub max_query_buffer: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
+            max_query_buffer: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Parse a command from its name and arguments, as defined by the command table
+pub fn parse_command(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    commands::parse(elems)
+}
+
 /// A codec that translates between high-level Redis commands/responses and a low-level wire format
 pub trait ProtocolCodec {
-    fn decode_from(buffer: &mut BytesMut) -> Result<Option<Command>, DecodeError>;
+    /// The progress of decoding a partially received command
+    type DecodeState: Default + fmt::Debug;
+
+    /// The number of bytes of a partially received command that were taken from the buffer
+    /// already (and count towards the query buffer limit)
+    fn consumed(state: &Self::DecodeState) -> usize;
+
+    fn decode_from(
+        state: &mut Self::DecodeState,
+        limits: &Limits,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<Command>, DecodeError>;
     fn encode_to(response: Response, buffer: &mut BytesMut) -> Result<(), EncodeError>;
 }
 
@@ -71,6 +146,8 @@ where
     E: From<DecodeError>,
     E: From<io::Error>,
 {
+    limits: Limits,
+    state: P::DecodeState,
     __protocol: PhantomData<P>,
     __err: PhantomData<E>,
 }
@@ -81,8 +158,11 @@ where
     E: From<DecodeError>,
     E: From<io::Error>,
 {
+    /// Clones start without any partially decoded command
     fn clone(&self) -> Self {
         StreamCodec {
+            limits: self.limits,
+            state: P::DecodeState::default(),
             __protocol: self.__protocol,
             __err: self.__err,
         }
@@ -97,11 +177,26 @@ where
 {
     fn default() -> Self {
         StreamCodec {
+            limits: Limits::default(),
+            state: P::DecodeState::default(),
             __protocol: PhantomData,
             __err: PhantomData,
         }
     }
 }
+impl<P, E> StreamCodec<P, E>
+where
+    P: ProtocolCodec,
+    E: From<EncodeError>,
+    E: From<DecodeError>,
+    E: From<io::Error>,
+{
+    /// Reject commands that exceed the given limits
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
 
 impl<P, E> Encoder for StreamCodec<P, E>
 where
@@ -133,9 +228,199 @@ where
             return Ok(None);
         }
 
-        let value = P::decode_from(buffer)?;
+        let value = P::decode_from(&mut self.state, &self.limits, buffer)?;
+        debug!("Decoded value {:?}", value);
+
+        let pending = buffer.len() + P::consumed(&self.state);
+        if value.is_none() && pending > self.limits.max_query_buffer {
+            return Err(DecodeError::QueryBufferLimit.into());
+        }
+        Ok(value)
+    }
+}
+
+/// The protocol version a connection speaks, shared between its codec and its handler
+#[derive(Debug, Clone)]
+pub struct ProtocolVersion(Arc<AtomicUsize>);
+impl ProtocolVersion {
+    /// The current protocol version (2 or 3)
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Switch the protocol version used for all following responses
+    pub fn set(&self, version: usize) {
+        self.0.store(version, Ordering::SeqCst);
+    }
+}
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion(Arc::new(AtomicUsize::new(2)))
+    }
+}
+
+/// The number of bytes a connection has received but not yet decoded, shared between its codec
+/// and its handler
+#[derive(Debug, Clone, Default)]
+pub struct QueryBuffer(Arc<AtomicUsize>);
+impl QueryBuffer {
+    /// The number of buffered bytes
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, len: usize) {
+        self.0.store(len, Ordering::Relaxed);
+    }
+}
+
+/// A stream codec that speaks RESP2 until the connection switches to RESP3 (with `HELLO 3`)
+///
+/// Commands are always sent as arrays of bulk strings, so only responses are affected.
+#[derive(Debug)]
+pub struct NegotiatedStreamCodec<E>
+where
+    E: From<EncodeError>,
+    E: From<DecodeError>,
+    E: From<io::Error>,
+{
+    version: ProtocolVersion,
+    query_buffer: QueryBuffer,
+    limits: Limits,
+    state: resp2::DecodeState,
+    __err: PhantomData<E>,
+}
+impl<E> NegotiatedStreamCodec<E>
+where
+    E: From<EncodeError>,
+    E: From<DecodeError>,
+    E: From<io::Error>,
+{
+    /// A handle for switching the protocol version
+    pub fn version(&self) -> ProtocolVersion {
+        self.version.clone()
+    }
+
+    /// A handle for reading the size of the query buffer
+    pub fn query_buffer(&self) -> QueryBuffer {
+        self.query_buffer.clone()
+    }
+
+    /// Reject commands that exceed the given limits
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+impl<E> Default for NegotiatedStreamCodec<E>
+where
+    E: From<EncodeError>,
+    E: From<DecodeError>,
+    E: From<io::Error>,
+{
+    fn default() -> Self {
+        NegotiatedStreamCodec {
+            version: ProtocolVersion::default(),
+            query_buffer: QueryBuffer::default(),
+            limits: Limits::default(),
+            state: resp2::DecodeState::default(),
+            __err: PhantomData,
+        }
+    }
+}
+
+impl<E> Encoder for NegotiatedStreamCodec<E>
+where
+    E: From<EncodeError>,
+    E: From<DecodeError>,
+    E: From<io::Error>,
+{
+    type Item = Response;
+    type Error = E;
+
+    fn encode(&mut self, response: Response, buffer: &mut BytesMut) -> Result<(), E> {
+        match self.version.get() {
+            3 => resp3::Value::encode_to(response, buffer)?,
+            _ => resp2::Value::encode_to(response, buffer)?,
+        }
+        Ok(())
+    }
+}
+impl<E> Decoder for NegotiatedStreamCodec<E>
+where
+    E: From<EncodeError>,
+    E: From<DecodeError>,
+    E: From<io::Error>,
+{
+    type Item = Command;
+    type Error = E;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Command>, E> {
+        if buffer.is_empty() {
+            self.query_buffer.set(self.state.consumed());
+            return Ok(None);
+        }
+
+        let value = resp2::Value::decode_from(&mut self.state, &self.limits, buffer)?;
         debug!("Decoded value {:?}", value);
+        let pending = buffer.len() + self.state.consumed();
+        self.query_buffer.set(pending);
 
+        if value.is_none() && pending > self.limits.max_query_buffer {
+            return Err(DecodeError::QueryBufferLimit.into());
+        }
         Ok(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    quick_error! {
+        #[derive(Debug)]
+        enum CodecError {
+            Encode(err: EncodeError) {
+                from()
+            }
+            Decode(err: DecodeError) {
+                from()
+            }
+            Io(err: io::Error) {
+                from()
+            }
+        }
+    }
+
+    fn is_query_buffer_limit(result: Result<Option<Command>, CodecError>) -> bool {
+        match result {
+            Err(CodecError::Decode(DecodeError::QueryBufferLimit)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn query_buffer_limit_counts_partially_decoded_arrays() {
+        let limits = Limits {
+            max_query_buffer: 32,
+            ..Limits::default()
+        };
+        // The elements are taken from the buffer as they arrive, leaving it empty
+        let data = "*8\r\n$4\r\nPING\r\n$4\r\nPING\r\n$4\r\nPING\r\n";
+
+        let mut codec = StreamCodec::<resp2::Value, CodecError>::default().with_limits(limits);
+        assert!(is_query_buffer_limit(
+            codec.decode(&mut BytesMut::from(data))
+        ));
+
+        let mut codec = NegotiatedStreamCodec::<CodecError>::default().with_limits(limits);
+        let query_buffer = codec.query_buffer();
+        let mut buffer = BytesMut::from(&data[..24]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+        assert_eq!(query_buffer.get(), 24);
+
+        buffer.extend_from_slice(data[24..].as_bytes());
+        assert!(is_query_buffer_limit(codec.decode(&mut buffer)));
+    }
+}
@@ -1,8 +1,5 @@
 //! Command/response codec implementation for the [Redis Serialization Protocol v2 (RESP2)](https://redis.io/topics/protocol).
 
-use std::mem;
-use std::time::Duration;
-
 use slog::{slog_debug, slog_trace};
 use slog_scope::{debug, trace};
 
@@ -10,7 +7,14 @@ use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::protocol::*;
 
-use super::{DecodeError, EncodeError, ProtocolCodec};
+use super::{parse_command, DecodeError, EncodeError, Limits, ProtocolCodec};
+
+/// The maximum length of an inline command
+const INLINE_MAX_SIZE: usize = 64 * 1024;
+
+/// The most buffer space reserved ahead for a bulk string's payload (beyond that, the buffer
+/// grows as the payload arrives, rather than trusting the announced length)
+const BULK_RESERVE_MAX: usize = 64 * 1024;
 
 /// A primitive protocol value
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -22,126 +26,201 @@ pub enum Value {
     Array(Vec<Value>),
     Nil,
 }
-impl<'a> Value {
-    /// Try to read a `Value` from a byte buffer. Will return `Ok(None)` if an incomplete but so far correct
-    /// value is encountered, or `Err(DecodeError)` in case of invalid data.
-    fn read_from(buffer: &mut BytesMut) -> Result<Option<Self>, DecodeError> {
-        if buffer.is_empty() {
-            return Ok(None);
-        }
 
-        fn read_simple(buffer: &mut BytesMut) -> Result<Option<BytesMut>, DecodeError> {
-            let pos = match buffer.iter().position(|&x| x == b'\r' || x == b'\n') {
-                Some(pos) => pos,
-                None => return Ok(None),
-            };
-            if pos + 1 == buffer.len() {
+/// The progress of decoding a value that hasn't been received completely yet
+///
+/// Decoding is resumed where it left off when more data arrives, so bytes that have already been
+/// consumed are never parsed again, and bulk string payloads are split off the buffer without
+/// copying.
+#[derive(Debug, Default)]
+pub struct DecodeState {
+    /// The arrays being filled (innermost last), with their expected length
+    arrays: Vec<(usize, Vec<Value>)>,
+    /// The length of the bulk string whose payload is awaited
+    bulk: Option<usize>,
+    /// How many bytes of the current line have been searched for its end
+    scanned: usize,
+    /// How many bytes of the partially decoded value were already taken from the buffer
+    consumed: usize,
+}
+impl DecodeState {
+    /// Whether no value is partially decoded
+    fn is_idle(&self) -> bool {
+        self.arrays.is_empty() && self.bulk.is_none()
+    }
+
+    /// The number of bytes held for the partially decoded value (besides the unparsed buffer)
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Take the next line (including its CRLF) from the buffer, if it has been received completely
+    fn read_line(&mut self, buffer: &mut BytesMut) -> Result<Option<BytesMut>, DecodeError> {
+        let pos = match buffer[self.scanned..]
+            .iter()
+            .position(|&x| x == b'\r' || x == b'\n')
+        {
+            Some(pos) => self.scanned + pos,
+            None => {
+                self.scanned = buffer.len();
                 return Ok(None);
             }
-            if buffer[pos] != b'\r' {
-                return Err(DecodeError::UnexpectedByte(buffer[pos]));
+        };
+        if pos + 1 == buffer.len() {
+            self.scanned = pos;
+            return Ok(None);
+        }
+        if buffer[pos] != b'\r' {
+            return Err(DecodeError::UnexpectedByte(buffer[pos]));
+        }
+        if buffer[pos + 1] != b'\n' {
+            return Err(DecodeError::UnexpectedByte(buffer[pos + 1]));
+        }
+
+        self.scanned = 0;
+        Ok(Some(buffer.split_to(pos + 2)))
+    }
+
+    /// Take the next inline command line from the buffer, if it has been received completely
+    fn read_inline(&mut self, buffer: &mut BytesMut) -> Result<Option<BytesMut>, DecodeError> {
+        match buffer[self.scanned..].iter().position(|&x| x == b'\n') {
+            Some(pos) => {
+                let line = buffer.split_to(self.scanned + pos + 1);
+                self.scanned = 0;
+                Ok(Some(line))
             }
-            if buffer[pos + 1] != b'\n' {
-                return Err(DecodeError::UnexpectedByte(buffer[pos + 1]));
+            None if buffer.len() > INLINE_MAX_SIZE => Err(DecodeError::InlineTooLong),
+            None => {
+                self.scanned = buffer.len();
+                Ok(None)
             }
-            Ok(Some(buffer.split_to(pos + 2)))
         }
+    }
 
-        debug!("Attempting to parse RESPv2 value");
-
-        let mut original = buffer.clone();
-        trace!("Buffer: {:?}", original);
-
-        match buffer[0] {
-            b'+' => Ok(read_simple(buffer)?.map(|mut command| {
-                command.advance(1);
-                Value::SimpleString(command.split_to(command.len() - 2).freeze())
-            })),
-            b'-' => Ok(read_simple(buffer)?.map(|mut command| {
-                command.advance(1);
-                Value::Error(command.split_to(command.len() - 2).freeze())
-            })),
-            b':' => read_simple(buffer)?
-                .map(|command| -> Result<Value, DecodeError> {
-                    let repr = std::str::from_utf8(&command[1..command.len() - 2])?;
-                    trace!("Parsing RESPv2 integer from '{}'", repr);
-                    Ok(Value::Integer(repr.parse()?))
-                })
-                .transpose(),
-            b'*' => {
-                if let Some(len) = read_simple(buffer)?
-                    .map(|command| -> Result<isize, DecodeError> {
-                        let repr = std::str::from_utf8(&command[1..command.len() - 2])?;
-                        trace!("Parsing RESPv2 array size from '{}'", repr);
-                        Ok(repr.parse()?)
-                    })
-                    .transpose()
-                    .map_err(|err| {
-                        mem::swap(&mut original, buffer);
-                        err
-                    })?
-                {
-                    if len == -1 {
-                        return Ok(Some(Value::Nil));
-                    }
+    /// Try to decode a value, continuing where the previous call left off. Will return `Ok(None)`
+    /// if more data is needed, or `Err(DecodeError)` in case of invalid data.
+    pub fn decode(
+        &mut self,
+        buffer: &mut BytesMut,
+        limits: &Limits,
+    ) -> Result<Option<Value>, DecodeError> {
+        let result = self.resume(buffer, limits);
+        if result.is_err() {
+            *self = DecodeState::default();
+        }
+        result
+    }
 
-                    let mut values: Vec<Value> = Vec::with_capacity(len as usize);
-                    for _ in 0..len {
-                        if let Some(value) = Value::read_from(buffer).map_err(|err| {
-                            mem::swap(&mut original, buffer);
-                            err
-                        })? {
-                            values.push(value)
-                        } else {
-                            mem::swap(&mut original, buffer);
-                            return Ok(None);
-                        }
-                    }
+    fn resume(
+        &mut self,
+        buffer: &mut BytesMut,
+        limits: &Limits,
+    ) -> Result<Option<Value>, DecodeError> {
+        fn parse_integer(line: &[u8]) -> Result<i64, DecodeError> {
+            let repr = std::str::from_utf8(&line[1..line.len() - 2])?;
+            trace!("Parsing RESPv2 integer from '{}'", repr);
+            Ok(repr.parse()?)
+        }
 
-                    Ok(Some(Value::Array(values)))
-                } else {
-                    Ok(None)
+        loop {
+            let mut value = if let Some(len) = self.bulk {
+                if buffer.len() < len + 2 {
+                    buffer.reserve(std::cmp::min(len + 2 - buffer.len(), BULK_RESERVE_MAX));
+                    return Ok(None);
                 }
-            }
-            b'$' => {
-                if let Some(len) = read_simple(buffer)?
-                    .map(|command| -> Result<isize, DecodeError> {
-                        let repr = std::str::from_utf8(&command[1..command.len() - 2])?;
-                        trace!("Parsing RESPv2 bulk string size from '{}'", repr);
-                        Ok(repr.parse()?)
-                    })
-                    .transpose()
-                    .map_err(|err| {
-                        mem::swap(&mut original, buffer);
-                        err
-                    })?
-                {
-                    if len == -1 {
-                        Ok(Some(Value::Nil))
-                    } else if len < 0 {
-                        mem::swap(&mut original, buffer);
-                        Err(DecodeError::InvalidLength)
-                    } else if (buffer.len() as isize) < len + 2 {
-                        Ok(None)
-                    } else if buffer[len as usize] != b'\r' {
-                        Err(DecodeError::UnexpectedByte(buffer[len as usize]))
-                    } else if buffer[len as usize + 1] != b'\n' {
-                        Err(DecodeError::UnexpectedByte(buffer[(len as usize) + 1]))
-                    } else {
-                        Ok(Some(Value::BulkString(
-                            buffer
-                                .split_to(len as usize + 2)
-                                .split_to(len as usize)
-                                .freeze(),
-                        )))
+                if buffer[len] != b'\r' {
+                    return Err(DecodeError::UnexpectedByte(buffer[len]));
+                }
+                if buffer[len + 1] != b'\n' {
+                    return Err(DecodeError::UnexpectedByte(buffer[len + 1]));
+                }
+
+                self.bulk = None;
+                let data = buffer.split_to(len).freeze();
+                buffer.advance(2);
+                self.consumed += len + 2;
+                Value::BulkString(data)
+            } else {
+                if buffer.is_empty() {
+                    return Ok(None);
+                }
+                let mut line = match self.read_line(buffer)? {
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
+                self.consumed += line.len();
+                trace!("Parsing RESPv2 line {:?}", line);
+
+                match line[0] {
+                    b'+' => {
+                        line.advance(1);
+                        Value::SimpleString(line.split_to(line.len() - 2).freeze())
+                    }
+                    b'-' => {
+                        line.advance(1);
+                        Value::Error(line.split_to(line.len() - 2).freeze())
+                    }
+                    b':' => Value::Integer(parse_integer(&line)?),
+                    b'$' => match parse_integer(&line).map_err(|_| DecodeError::InvalidLength)? {
+                        -1 => Value::Nil,
+                        len if len < 0 || len as usize > limits.max_bulk_len => {
+                            return Err(DecodeError::InvalidLength)
+                        }
+                        len => {
+                            self.bulk = Some(len as usize);
+                            continue;
+                        }
+                    },
+                    b'*' => match parse_integer(&line)
+                        .map_err(|_| DecodeError::InvalidMultibulkLength)?
+                    {
+                        -1 => Value::Nil,
+                        len if len < 0 || len as usize > limits.max_multibulk_len => {
+                            return Err(DecodeError::InvalidMultibulkLength)
+                        }
+                        0 => Value::Array(Vec::new()),
+                        len => {
+                            // Don't trust the announced length for preallocation
+                            let capacity = (len as usize).min(1024);
+                            self.arrays
+                                .push((len as usize, Vec::with_capacity(capacity)));
+                            continue;
+                        }
+                    },
+                    b => return Err(DecodeError::UnexpectedByte(b)),
+                }
+            };
+
+            // Add the value to the array being filled, completing any arrays it fills up
+            loop {
+                match self.arrays.last_mut() {
+                    None => {
+                        self.consumed = 0;
+                        return Ok(Some(value));
+                    }
+                    Some(&mut (len, ref mut values)) => {
+                        values.push(value);
+                        if values.len() < len {
+                            break;
+                        }
                     }
-                } else {
-                    Ok(None)
                 }
+                let (_, values) = self.arrays.pop().expect("No array being filled");
+                value = Value::Array(values);
             }
-            b => Err(DecodeError::UnexpectedByte(b)),
         }
     }
+}
+
+impl Value {
+    /// Try to read a `Value` from a byte buffer. Will return `Ok(None)` if an incomplete but so far correct
+    /// value is encountered, or `Err(DecodeError)` in case of invalid data.
+    ///
+    /// Incomplete values are consumed, so decoding them can only be resumed with a `DecodeState`.
+    #[cfg(test)]
+    fn read_from(buffer: &mut BytesMut) -> Result<Option<Self>, DecodeError> {
+        DecodeState::default().decode(buffer, &Limits::default())
+    }
 
     /// Try to write a `Value` to a byte buffer.
     fn write_to(self, buffer: &mut BytesMut) -> Result<(), EncodeError> {
@@ -193,158 +272,175 @@ impl<'a> Value {
 
         Ok(())
     }
-    fn parse_millis(data: &[u8]) -> Result<Duration, DecodeError> {
-        let decoded = std::str::from_utf8(data)?;
-        let value: i64 = decoded.parse()?;
+}
+impl ProtocolCodec for Value {
+    type DecodeState = DecodeState;
 
-        Ok(Duration::from_millis(if value >= 0 {
-            value as u64
-        } else {
-            0
-        }))
+    fn consumed(state: &DecodeState) -> usize {
+        state.consumed()
     }
-    fn parse_seconds(data: &[u8]) -> Result<Duration, DecodeError> {
-        let decoded = std::str::from_utf8(data)?;
-        let value: i64 = decoded.parse()?;
 
-        Ok(Duration::from_secs(if value >= 0 {
-            value as u64
-        } else {
-            0
-        }))
-    }
-}
-impl ProtocolCodec for Value {
-    fn decode_from(buffer: &mut BytesMut) -> Result<Option<Command>, DecodeError> {
-        if let Some(value) = Self::read_from(buffer)? {
+    fn decode_from(
+        state: &mut DecodeState,
+        limits: &Limits,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<Command>, DecodeError> {
+        loop {
+            // Anything that isn't an array is an inline command (as typed in a telnet session)
+            while state.is_idle() && !buffer.is_empty() && buffer[0] != b'*' {
+                let line = match state.read_inline(buffer)? {
+                    Some(line) => line,
+                    None => return Ok(None),
+                };
+                let elems = split_inline(&line)?;
+                debug!("Parsed inline command {:?}", elems);
+
+                // Empty lines are ignored
+                if !elems.is_empty() {
+                    return Ok(Some(parse_command(&elems)?));
+                }
+            }
+
+            let value = match state.decode(buffer, limits)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
             debug!("Parsed raw value {:?}", value);
 
-            if let Value::Array(elems) = value {
-                let elems = elems
-                    .into_iter()
-                    .map(|x| match x {
-                        Value::BulkString(data) => Ok(data),
-                        _ => Err(DecodeError::InvalidDataType),
-                    })
-                    .collect::<Result<Vec<_>, DecodeError>>()?;
-
-                Ok(Some(match elems[0].as_ref() {
-                    b"ping" | b"PING" => match &elems[1..] {
-                        [] => Command::Ping(None),
-                        [ref msg] => Command::Ping(Some(msg.clone())),
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"get" | b"GET" => match &elems[1..] {
-                        [ref key] => Command::Get(key.clone()),
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"set" | b"SET" => match &elems[1..] {
-                        [] | [_] => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                        args if args.len() <= 5 => {
-                            let key = &args[0];
-                            let value = &args[1];
-
-                            let mut expiration: Option<Duration> = None;
-                            let mut conditional = Conditional::Always;
-
-                            let mut i = 2;
-                            while i < args.len() {
-                                match args[i].as_ref() {
-                                    b"XX" | b"xx" if conditional == Conditional::Always => {
-                                        conditional = Conditional::IfExists
-                                    }
-                                    b"NX" | b"nx" if conditional == Conditional::Always => {
-                                        conditional = Conditional::IfNotExists
-                                    }
-                                    b"PX" | b"px" if expiration.is_none() && i + 1 < args.len() => {
-                                        i += 1;
-                                        expiration = Some(Self::parse_millis(&args[i].as_ref())?);
-                                    }
-                                    b"EX" | b"ex" if expiration.is_none() && i + 1 < args.len() => {
-                                        i += 1;
-                                        expiration = Some(Self::parse_seconds(&args[i].as_ref())?);
-                                    }
-                                    _ => Err(DecodeError::InvalidArgument)?,
-                                }
-                                i += 1;
-                            }
+            let elems = match value {
+                // Empty (and null) arrays are ignored, like empty lines
+                Value::Array(ref elems) if elems.is_empty() => continue,
+                Value::Nil => continue,
+                Value::Array(elems) => elems,
+                _ => return Err(DecodeError::InvalidDataType),
+            };
+            let elems = elems
+                .into_iter()
+                .map(|x| match x {
+                    Value::BulkString(data) => Ok(data),
+                    _ => Err(DecodeError::InvalidDataType),
+                })
+                .collect::<Result<Vec<_>, DecodeError>>()?;
 
-                            Command::Set(key.clone(), value.clone(), expiration, conditional)
-                        }
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"del" | b"DEL" => match &elems[1..] {
-                        [] => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                        keys => Command::Del(keys.into()),
-                    },
-                    b"exists" | b"EXISTS" => match &elems[1..] {
-                        [] => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                        keys => Command::Exists(keys.into()),
-                    },
-                    b"expire" | b"EXPIRE" => match &elems[1..] {
-                        [ref key, ref seconds] => {
-                            Command::Expire(key.clone(), Self::parse_seconds(seconds)?)
-                        }
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"pexpire" | b"PEXPIRE" => match &elems[1..] {
-                        [ref key, ref seconds] => {
-                            Command::Expire(key.clone(), Self::parse_millis(seconds)?)
-                        }
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"persist" | b"PERSIST" => match &elems[1..] {
-                        [ref key] => Command::Persist(key.clone()),
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"flushdb" | b"FLUSHDB" => match &elems[1..] {
-                        [] => Command::FlushDB(Synchronicity::Sync),
-                        [ref opt] if opt.as_ref() == b"async" || opt.as_ref() == b"ASYNC" => {
-                            Command::FlushDB(Synchronicity::Async)
-                        }
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    b"flushall" | b"FLUSHALL" => match &elems[1..] {
-                        [] => Command::FlushAll(Synchronicity::Sync),
-                        [ref opt] if opt.as_ref() == b"async" || opt.as_ref() == b"ASYNC" => {
-                            Command::FlushDB(Synchronicity::Async)
-                        }
-                        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
-                    },
-                    _ => Err(DecodeError::UnrecognizedCommand(elems[0].clone()))?,
-                }))
-            } else {
-                Err(DecodeError::InvalidDataType)
-            }
-        } else {
-            Ok(None)
+            return Ok(Some(parse_command(&elems)?));
         }
     }
     fn encode_to(response: Response, buffer: &mut BytesMut) -> Result<(), EncodeError> {
-        let value = match response {
+        let value = Value::from(response);
+        debug!("Encoded raw value {:?}", value);
+
+        value.write_to(buffer)?;
+
+        Ok(())
+    }
+}
+impl From<Response> for Value {
+    fn from(response: Response) -> Self {
+        match response.downgrade() {
             Response::Nil => Value::Nil,
             Response::Pong => Value::SimpleString(Bytes::from(&b"PONG"[..])),
             Response::Ok => Value::SimpleString(Bytes::from(&b"OK"[..])),
             Response::Integer(value) => Value::Integer(value),
+            Response::Queued => Value::SimpleString(Bytes::from(&b"QUEUED"[..])),
             Response::Bulk(data) => Value::BulkString(data),
-            Response::Error(Error::WrongType) => Value::Error(Bytes::from(
-                &b"WRONGTYPE Operation against a key holding the wrong kind of value"[..],
-            )),
-            Response::Error(Error::Syntax) => Value::Error(Bytes::from(&b"ERR syntax error"[..])),
-            Response::Error(Error::UnknownCommand(cmd)) => {
-                let mut msg = BytesMut::from(&b"ERR unknown command '"[..]);
-                msg.reserve(cmd.len() + 1);
-                msg.put(cmd);
-                msg.put("'");
-
-                Value::Error(msg.freeze())
+            Response::Array(elements) => {
+                Value::Array(elements.into_iter().map(Value::from).collect())
             }
-        };
-        debug!("Encoded raw value {:?}", value);
+            Response::Status(data) => Value::SimpleString(data),
+            Response::Error(err) => Value::Error(err.message()),
+            _ => unreachable!(),
+        }
+    }
+}
 
-        value.write_to(buffer)?;
+/// Split an inline command into its arguments
+///
+/// Arguments are separated by whitespace, and may be quoted. Double-quoted arguments support
+/// escape sequences (such as `\n` and `\x41`), single-quoted arguments only support `\'`.
+fn split_inline(line: &[u8]) -> Result<Vec<Bytes>, DecodeError> {
+    fn hex(digit: u8) -> Option<u8> {
+        (digit as char).to_digit(16).map(|x| x as u8)
+    }
 
-        Ok(())
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        match line[i] {
+            b'"' => {
+                i += 1;
+                loop {
+                    match line.get(i) {
+                        None => return Err(DecodeError::UnbalancedQuotes),
+                        Some(b'"') => break,
+                        Some(b'\\') => {
+                            let hex_escape = match line.get(i + 1) {
+                                Some(b'x') if line.len() > i + 3 => {
+                                    match (hex(line[i + 2]), hex(line[i + 3])) {
+                                        (Some(high), Some(low)) => Some((high << 4) | low),
+                                        _ => None,
+                                    }
+                                }
+                                _ => None,
+                            };
+                            if let Some(byte) = hex_escape {
+                                arg.push(byte);
+                                i += 3;
+                            } else {
+                                i += 1;
+                                arg.push(match line.get(i) {
+                                    Some(b'n') => b'\n',
+                                    Some(b'r') => b'\r',
+                                    Some(b't') => b'\t',
+                                    Some(b'b') => 8,
+                                    Some(b'a') => 7,
+                                    Some(&byte) => byte,
+                                    None => return Err(DecodeError::UnbalancedQuotes),
+                                });
+                            }
+                        }
+                        Some(&byte) => arg.push(byte),
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'\'' => {
+                i += 1;
+                loop {
+                    match line.get(i) {
+                        None => return Err(DecodeError::UnbalancedQuotes),
+                        Some(b'\'') => break,
+                        Some(b'\\') if line.get(i + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            i += 1;
+                        }
+                        Some(&byte) => arg.push(byte),
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            _ => {
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        // A closing quote must be followed by whitespace
+        if i < line.len() && !line[i].is_ascii_whitespace() {
+            return Err(DecodeError::UnbalancedQuotes);
+        }
+        args.push(Bytes::from(arg));
     }
 }
 
@@ -481,6 +577,136 @@ mod tests {
         assert_eq!(&data[..], b"+EXTRA\r\n");
     }
 
+    #[test]
+    fn inline_commands_are_split_on_whitespace() {
+        assert_eq!(
+            split_inline(b"SET  key\tvalue\r\n").unwrap(),
+            vec![Bytes::from("SET"), Bytes::from("key"), Bytes::from("value")]
+        );
+        assert_eq!(split_inline(b"\r\n").unwrap(), Vec::<Bytes>::new());
+    }
+
+    #[test]
+    fn inline_commands_support_quotes() {
+        assert_eq!(
+            split_inline(br#"SET "a key" "line\n\x41\"" 'it\'s' """#).unwrap(),
+            vec![
+                Bytes::from("SET"),
+                Bytes::from("a key"),
+                Bytes::from("line\nA\""),
+                Bytes::from("it's"),
+                Bytes::from(""),
+            ]
+        );
+        assert!(split_inline(br#"GET "key"#).is_err());
+        assert!(split_inline(br#"GET "key"x"#).is_err());
+    }
+
+    #[test]
+    fn codec_decodes_inline_commands() {
+        let mut data = BytesMut::from("\r\nPING\r\n*1\r\n$4\r\nPING\r\n");
+        match Value::decode_from(&mut DecodeState::default(), &Limits::default(), &mut data) {
+            Ok(Some(Command::Ping(None))) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(&data[..], b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[test]
+    fn codec_ignores_empty_arrays() {
+        let mut data = BytesMut::from("*0\r\n*-1\r\n*1\r\n$4\r\nPING\r\n*0\r\n");
+        match Value::decode_from(&mut DecodeState::default(), &Limits::default(), &mut data) {
+            Ok(Some(Command::Ping(None))) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(&data[..], b"*0\r\n");
+        match Value::decode_from(&mut DecodeState::default(), &Limits::default(), &mut data) {
+            Ok(None) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn codec_resumes_partial_values() {
+        let limits = Limits::default();
+        let mut state = DecodeState::default();
+        let mut data = BytesMut::from("*2\r\n$3\r\nGET\r\n$5\r\nva");
+        assert_eq!(state.decode(&mut data, &limits).unwrap(), None);
+        assert_eq!(&data[..], b"va");
+
+        data.extend_from_slice(b"lue\r");
+        assert_eq!(state.decode(&mut data, &limits).unwrap(), None);
+
+        data.extend_from_slice(b"\n:1");
+        assert_eq!(
+            state.decode(&mut data, &limits).unwrap(),
+            Some(Value::Array(vec![
+                Value::BulkString(Bytes::from("GET")),
+                Value::BulkString(Bytes::from("value")),
+            ]))
+        );
+        assert_eq!(state.decode(&mut data, &limits).unwrap(), None);
+
+        data.extend_from_slice(b"2\r\n");
+        assert_eq!(
+            state.decode(&mut data, &limits).unwrap(),
+            Some(Value::Integer(12))
+        );
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn codec_enforces_limits() {
+        let limits = Limits {
+            max_bulk_len: 4,
+            max_multibulk_len: 2,
+            ..Limits::default()
+        };
+        let decode = |data: &str| DecodeState::default().decode(&mut BytesMut::from(data), &limits);
+
+        assert!(decode("*2\r\n$4\r\nPING\r\n").is_ok());
+        match decode("*3\r\n") {
+            Err(DecodeError::InvalidMultibulkLength) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match decode("*1\r\n$5\r\n") {
+            Err(DecodeError::InvalidLength) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let mut data = BytesMut::from(&[b'P'; INLINE_MAX_SIZE + 1][..]);
+        match Value::decode_from(&mut DecodeState::default(), &limits, &mut data) {
+            Err(DecodeError::InlineTooLong) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn codec_doesnt_reserve_announced_bulk_lengths() {
+        let mut state = DecodeState::default();
+        let mut data = BytesMut::from("*1\r\n$100000000\r\nPING");
+        assert_eq!(state.decode(&mut data, &Limits::default()).unwrap(), None);
+        assert!(
+            data.capacity() <= 2 * BULK_RESERVE_MAX,
+            "{}",
+            data.capacity()
+        );
+    }
+
+    #[test]
+    fn codec_decodes_nested_arrays() {
+        let mut data = BytesMut::from("*2\r\n*1\r\n:1\r\n*0\r\n");
+        let decoded = Value::read_from(&mut data).expect("Failed to decode nested arrays");
+        assert_eq!(
+            decoded,
+            Some(Value::Array(vec![
+                Value::Array(vec![Value::Integer(1)]),
+                Value::Array(Vec::new()),
+            ]))
+        );
+    }
+
     #[test]
     fn codec_ignores_bytes_outside_simple_string() {
         let mut data = BytesMut::from("+TEST\r\n+TEST2\r\n");
@@ -0,0 +1,223 @@
+//! Response encoding for the [Redis Serialization Protocol v3 (RESP3)](https://github.com/antirez/RESP3/blob/master/spec.md).
+//!
+//! Clients send their commands as RESP2 arrays regardless of the protocol version, so only
+//! responses are ever encoded as RESP3.
+
+use slog::slog_debug;
+use slog_scope::debug;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::protocol::*;
+
+use super::EncodeError;
+
+/// A primitive protocol value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    SimpleString(Bytes),
+    Error(Bytes),
+    Integer(i64),
+    BulkString(Bytes),
+    Array(Vec<Value>),
+    Null,
+    Double(f64),
+    Boolean(bool),
+    BlobError(Bytes),
+    /// A verbatim string, with its format and text
+    Verbatim(Bytes, Bytes),
+    BigNumber(Bytes),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    /// Attributes, followed by the value they describe
+    Attribute(Vec<(Value, Value)>, Box<Value>),
+    Push(Vec<Value>),
+}
+impl Value {
+    /// Encode a response, as sent to clients that switched to RESP3 with `HELLO 3`
+    pub fn encode_to(response: Response, buffer: &mut BytesMut) -> Result<(), EncodeError> {
+        let value = Value::from(response);
+        debug!("Encoded raw value {:?}", value);
+
+        value.write_to(buffer)?;
+
+        Ok(())
+    }
+
+    /// Try to write a `Value` to a byte buffer.
+    fn write_to(self, buffer: &mut BytesMut) -> Result<(), EncodeError> {
+        fn header(buffer: &mut BytesMut, kind: &str, len: usize) {
+            let len = len.to_string();
+            buffer.reserve(3 + len.len());
+            buffer.put(kind);
+            buffer.put(len);
+            buffer.put("\r\n");
+        }
+
+        fn simple(buffer: &mut BytesMut, kind: &str, data: &[u8]) {
+            buffer.reserve(3 + data.len());
+            buffer.put(kind);
+            buffer.put(data);
+            buffer.put("\r\n");
+        }
+
+        fn blob(buffer: &mut BytesMut, kind: &str, data: &[u8]) {
+            header(buffer, kind, data.len());
+            buffer.reserve(2 + data.len());
+            buffer.put(data);
+            buffer.put("\r\n");
+        }
+
+        match self {
+            Value::Null => simple(buffer, "_", b""),
+            Value::SimpleString(data) => simple(buffer, "+", &data),
+            Value::Error(data) => simple(buffer, "-", &data),
+            Value::Integer(value) => simple(buffer, ":", value.to_string().as_bytes()),
+            Value::Double(value) => simple(buffer, ",", format_double(value).as_bytes()),
+            Value::Boolean(value) => simple(buffer, "#", if value { b"t" } else { b"f" }),
+            Value::BigNumber(data) => simple(buffer, "(", &data),
+            Value::BulkString(data) => blob(buffer, "$", &data),
+            Value::BlobError(data) => blob(buffer, "!", &data),
+            Value::Verbatim(format, data) => {
+                header(buffer, "=", format.len() + 1 + data.len());
+                buffer.reserve(format.len() + data.len() + 3);
+                buffer.put(format);
+                buffer.put(":");
+                buffer.put(data);
+                buffer.put("\r\n");
+            }
+            Value::Array(elements) => {
+                header(buffer, "*", elements.len());
+                for element in elements.into_iter() {
+                    element.write_to(buffer)?;
+                }
+            }
+            Value::Set(elements) => {
+                header(buffer, "~", elements.len());
+                for element in elements.into_iter() {
+                    element.write_to(buffer)?;
+                }
+            }
+            Value::Push(elements) => {
+                header(buffer, ">", elements.len());
+                for element in elements.into_iter() {
+                    element.write_to(buffer)?;
+                }
+            }
+            Value::Map(entries) => {
+                header(buffer, "%", entries.len());
+                for (key, value) in entries.into_iter() {
+                    key.write_to(buffer)?;
+                    value.write_to(buffer)?;
+                }
+            }
+            Value::Attribute(entries, value) => {
+                header(buffer, "|", entries.len());
+                for (key, value) in entries.into_iter() {
+                    key.write_to(buffer)?;
+                    value.write_to(buffer)?;
+                }
+                value.write_to(buffer)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+impl From<Response> for Value {
+    fn from(response: Response) -> Self {
+        fn pairs(entries: Vec<(Response, Response)>) -> Vec<(Value, Value)> {
+            entries
+                .into_iter()
+                .map(|(key, value)| (Value::from(key), Value::from(value)))
+                .collect()
+        }
+
+        match response {
+            Response::Nil => Value::Null,
+            Response::Pong => Value::SimpleString(Bytes::from(&b"PONG"[..])),
+            Response::Ok => Value::SimpleString(Bytes::from(&b"OK"[..])),
+            Response::Integer(value) => Value::Integer(value),
+            Response::Queued => Value::SimpleString(Bytes::from(&b"QUEUED"[..])),
+            Response::Bulk(data) => Value::BulkString(data),
+            Response::Array(elements) => {
+                Value::Array(elements.into_iter().map(Value::from).collect())
+            }
+            Response::Status(data) => Value::SimpleString(data),
+            Response::Error(err) => Value::Error(err.message()),
+            Response::Map(entries) => Value::Map(pairs(entries)),
+            Response::Set(elements) => Value::Set(elements.into_iter().map(Value::from).collect()),
+            Response::Double(value) => Value::Double(value),
+            Response::Boolean(value) => Value::Boolean(value),
+            Response::Verbatim(format, data) => Value::Verbatim(format, data),
+            Response::BigNumber(data) => Value::BigNumber(data),
+            Response::Attribute(entries, response) => {
+                Value::Attribute(pairs(entries), Box::new(Value::from(*response)))
+            }
+            Response::Push(elements) => {
+                Value::Push(elements.into_iter().map(Value::from).collect())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::{Bytes, BytesMut};
+
+    fn encode(value: Value) -> BytesMut {
+        let mut data = BytesMut::new();
+        value.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn codec_can_encode_scalars() {
+        assert_eq!(&encode(Value::Null)[..], b"_\r\n");
+        assert_eq!(&encode(Value::Boolean(true))[..], b"#t\r\n");
+        assert_eq!(&encode(Value::Double(1.5))[..], b",1.5\r\n");
+        assert_eq!(
+            &encode(Value::Double(std::f64::NEG_INFINITY))[..],
+            b",-inf\r\n"
+        );
+        assert_eq!(
+            &encode(Value::BigNumber(Bytes::from(
+                "3492890328409238509324850943850943825024385"
+            )))[..],
+            &b"(3492890328409238509324850943850943825024385\r\n"[..]
+        );
+        assert_eq!(
+            &encode(Value::Verbatim(
+                Bytes::from("txt"),
+                Bytes::from("Some string")
+            ))[..],
+            b"=15\r\ntxt:Some string\r\n"
+        );
+    }
+
+    #[test]
+    fn codec_can_encode_aggregates() {
+        let value = Value::Map(vec![
+            (Value::SimpleString(Bytes::from("first")), Value::Integer(1)),
+            (
+                Value::SimpleString(Bytes::from("second")),
+                Value::Set(vec![Value::Boolean(true)]),
+            ),
+        ]);
+        assert_eq!(
+            &encode(value)[..],
+            &b"%2\r\n+first\r\n:1\r\n+second\r\n~1\r\n#t\r\n"[..]
+        );
+
+        let value = Value::Push(vec![
+            Value::BulkString(Bytes::from("message")),
+            Value::BulkString(Bytes::from("channel")),
+        ]);
+        assert_eq!(
+            &encode(value)[..],
+            &b">2\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n"[..]
+        );
+    }
+}
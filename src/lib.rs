@@ -5,10 +5,15 @@
 /// Various utilities
 pub mod utils {
     pub mod configuration;
+    pub mod constant_time;
+    pub mod glob;
+    pub mod hash_slot;
     pub mod logging;
 }
 
 pub mod codecs;
 pub mod protocol;
+pub mod pubsub;
 pub mod server;
+pub mod stats;
 pub mod storage;
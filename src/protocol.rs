@@ -2,10 +2,14 @@
 
 use std::time::Duration;
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 
 use actix_derive::Message;
 
+pub mod commands;
+
+use self::commands::{CommandFilter, CommandSpec, Flag};
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Synchronicity {
     Sync,
@@ -30,6 +34,82 @@ impl Conditional {
     }
 }
 
+/// How `FUNCTION RESTORE` deals with existing libraries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePolicy {
+    /// Fail if any library already exists
+    Append,
+    /// Replace existing libraries with the same name
+    Replace,
+    /// Delete all existing libraries first
+    Flush,
+}
+
+/// The options of `CLIENT TRACKING ON`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TrackingOptions {
+    /// The id of the client invalidations are sent to instead
+    pub redirect: Option<u64>,
+    /// Send invalidations for all keys (matching the prefixes) instead of only the keys read
+    pub broadcast: bool,
+    /// The key prefixes to send invalidations for in broadcasting mode
+    pub prefixes: Vec<Bytes>,
+    /// Only track keys read right after `CLIENT CACHING YES`
+    pub opt_in: bool,
+    /// Track keys unless read right after `CLIENT CACHING NO`
+    pub opt_out: bool,
+}
+
+/// The filters of `CLIENT KILL` (a client is killed if it matches all of them)
+#[derive(Debug, PartialEq, Eq)]
+pub struct KillFilter {
+    /// The id of the client
+    pub id: Option<u64>,
+    /// The address (`ip:port`) of the client
+    pub addr: Option<Bytes>,
+    /// The local address (`ip:port`) the client connected to
+    pub laddr: Option<Bytes>,
+    /// The user the client is authenticated as
+    pub user: Option<Bytes>,
+    /// Whether the calling client is spared
+    pub skip_me: bool,
+    /// Whether the filter was given in the old `CLIENT KILL ip:port` form (which replies `OK`
+    /// instead of the number of killed clients)
+    pub legacy: bool,
+}
+impl Default for KillFilter {
+    fn default() -> Self {
+        KillFilter {
+            id: None,
+            addr: None,
+            laddr: None,
+            user: None,
+            skip_me: true,
+            legacy: false,
+        }
+    }
+}
+
+/// Which commands `CLIENT PAUSE` holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PauseMode {
+    /// Only commands that may write
+    Write,
+    /// All commands
+    All,
+}
+
+/// Which replies a connection receives (set by `CLIENT REPLY`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyMode {
+    /// All replies
+    On,
+    /// No replies
+    Off,
+    /// No reply to the next command
+    Skip,
+}
+
 /// A Redis command
 #[derive(Debug, Message)]
 pub enum Command {
@@ -47,6 +127,140 @@ pub enum Command {
     Expire(Bytes, Duration),
     /// Clear the expiration for a key
     Persist(Bytes),
+    /// Rename a key, overwriting the destination
+    Rename(Bytes, Bytes),
+    /// Rename a key, only if the destination doesn't exist
+    RenameNX(Bytes, Bytes),
+    /// Copy a key's value to another key, optionally in another database
+    Copy(Bytes, Bytes, Option<usize>, Conditional),
+    /// Move a key to another database
+    Move(Bytes, usize),
+
+    /// Select the database to use for the current connection
+    Select(usize),
+    /// Swap the contents of two databases
+    SwapDB(usize, usize),
+
+    /// Start a transaction
+    Multi,
+    /// Execute all commands queued in the current transaction
+    Exec,
+    /// Discard all commands queued in the current transaction
+    Discard,
+    /// Abort the next transaction if any of the given keys are modified
+    Watch(Vec<Bytes>),
+    /// Stop watching all keys
+    Unwatch,
+
+    /// Evaluate a Lua script with the given keys and arguments
+    Eval(Bytes, Vec<Bytes>, Vec<Bytes>),
+    /// Evaluate a cached Lua script (by SHA1 digest) with the given keys and arguments
+    EvalSha(Bytes, Vec<Bytes>, Vec<Bytes>),
+    /// Add a Lua script to the script cache
+    ScriptLoad(Bytes),
+    /// Check whether scripts (by SHA1 digest) exist in the script cache
+    ScriptExists(Vec<Bytes>),
+    /// Remove all scripts from the script cache
+    ScriptFlush,
+
+    /// Call a library function with the given keys and arguments
+    FCall(Bytes, Vec<Bytes>, Vec<Bytes>),
+    /// Call a read-only library function with the given keys and arguments
+    FCallRO(Bytes, Vec<Bytes>, Vec<Bytes>),
+    /// Load a function library, optionally replacing an existing one
+    FunctionLoad(Bytes, bool),
+    /// Delete a function library
+    FunctionDelete(Bytes),
+    /// Delete all function libraries
+    FunctionFlush,
+    /// List the function libraries (matching a pattern), optionally including their code
+    FunctionList(Option<Bytes>, bool),
+    /// Serialize all function libraries
+    FunctionDump,
+    /// Restore function libraries from a serialized payload
+    FunctionRestore(Bytes, RestorePolicy),
+
+    /// Subscribe to channels
+    Subscribe(Vec<Bytes>),
+    /// Unsubscribe from channels (or all channels if none are given)
+    Unsubscribe(Vec<Bytes>),
+    /// Subscribe to channels matching patterns
+    PSubscribe(Vec<Bytes>),
+    /// Unsubscribe from patterns (or all patterns if none are given)
+    PUnsubscribe(Vec<Bytes>),
+    /// Publish a message on a channel
+    Publish(Bytes, Bytes),
+    /// List the channels with subscribers (matching a pattern)
+    PubSubChannels(Option<Bytes>),
+    /// Count the subscribers of channels
+    PubSubNumSub(Vec<Bytes>),
+    /// Count the subscribed patterns
+    PubSubNumPat,
+    /// Subscribe to sharded channels
+    SSubscribe(Vec<Bytes>),
+    /// Unsubscribe from sharded channels (or all sharded channels if none are given)
+    SUnsubscribe(Vec<Bytes>),
+    /// Publish a message on a sharded channel
+    SPublish(Bytes, Bytes),
+    /// List the sharded channels with subscribers (matching a pattern)
+    PubSubShardChannels(Option<Bytes>),
+    /// Count the subscribers of sharded channels
+    PubSubShardNumSub(Vec<Bytes>),
+
+    /// Authenticate the connection with a password (as the given user, or the default user)
+    Auth(Option<Bytes>, Bytes),
+    /// Switch the protocol version (2 or 3), optionally authenticating and naming the connection,
+    /// and get information about the server
+    Hello(Option<i64>, Option<(Bytes, Bytes)>, Option<Bytes>),
+
+    /// Get the id of the current connection
+    ClientId,
+    /// Enable (or disable) invalidation messages for the keys the connection reads
+    ClientTracking(bool, TrackingOptions),
+    /// Track (or don't track) the keys read by the next command, in `OPTIN` (or `OPTOUT`) mode
+    ClientCaching(bool),
+    /// List the connected clients (all of them, or those with the given ids)
+    ClientList(Vec<u64>),
+    /// Get information about the current connection
+    ClientInfo,
+    /// Name the current connection (or remove its name if empty)
+    ClientSetName(Bytes),
+    /// Get the name of the current connection
+    ClientGetName,
+    /// Close the connections of the clients matching a filter
+    ClientKill(KillFilter),
+    /// Hold the commands of all clients for a while
+    ClientPause(Duration, PauseMode),
+    /// Resume the commands of clients held by `CLIENT PAUSE`
+    ClientUnpause,
+    /// Change which replies the current connection receives
+    ClientReply(ReplyMode),
+
+    /// Get information about all commands (or the given ones)
+    CommandInfo(Vec<Bytes>),
+    /// Count the commands
+    CommandCount,
+    /// Get the documentation of all commands (or the given ones)
+    CommandDocs(Vec<Bytes>),
+    /// List the names of all commands (matching a filter)
+    CommandList(Option<CommandFilter>),
+    /// Extract the keys from a command and its arguments
+    CommandGetKeys(Vec<Bytes>),
+
+    /// Close the connection
+    Quit,
+
+    /// Get information and statistics about the server (all default sections, or the given ones)
+    Info(Vec<Bytes>),
+
+    /// Get the configuration parameters matching any of the patterns
+    ConfigGet(Vec<Bytes>),
+    /// Set configuration parameters (all of them, or none if any of the values is invalid)
+    ConfigSet(Vec<(Bytes, Bytes)>),
+    /// Reset the statistics reported by `INFO`
+    ConfigResetStat,
+    /// Write the effective configuration to the configuration file
+    ConfigRewrite,
 
     /// Flush all databases
     FlushAll(Synchronicity),
@@ -63,12 +277,99 @@ impl Command {
         }
     }
 
-    /// Whether this command is a write operation
+    /// This command's entry in the command table
+    pub fn spec(&self) -> &'static CommandSpec {
+        use self::commands as spec;
+        use Command::*;
+        match self {
+            Ping(_) => &spec::PING,
+            Get(_) => &spec::GET,
+            Set(..) => &spec::SET,
+            Del(_) => &spec::DEL,
+            Exists(_) => &spec::EXISTS,
+            // `PEXPIRE` is parsed into the same command, and has the same properties
+            Expire(..) => &spec::EXPIRE,
+            Persist(_) => &spec::PERSIST,
+            Rename(..) => &spec::RENAME,
+            RenameNX(..) => &spec::RENAMENX,
+            Copy(..) => &spec::COPY,
+            Move(..) => &spec::MOVE,
+            Select(_) => &spec::SELECT,
+            SwapDB(..) => &spec::SWAPDB,
+            Multi => &spec::MULTI,
+            Exec => &spec::EXEC,
+            Discard => &spec::DISCARD,
+            Watch(_) => &spec::WATCH,
+            Unwatch => &spec::UNWATCH,
+            Eval(..) => &spec::EVAL,
+            EvalSha(..) => &spec::EVALSHA,
+            ScriptLoad(_) => &spec::SCRIPT_LOAD,
+            ScriptExists(_) => &spec::SCRIPT_EXISTS,
+            ScriptFlush => &spec::SCRIPT_FLUSH,
+            FCall(..) => &spec::FCALL,
+            FCallRO(..) => &spec::FCALL_RO,
+            FunctionLoad(..) => &spec::FUNCTION_LOAD,
+            FunctionDelete(_) => &spec::FUNCTION_DELETE,
+            FunctionFlush => &spec::FUNCTION_FLUSH,
+            FunctionList(..) => &spec::FUNCTION_LIST,
+            FunctionDump => &spec::FUNCTION_DUMP,
+            FunctionRestore(..) => &spec::FUNCTION_RESTORE,
+            Subscribe(_) => &spec::SUBSCRIBE,
+            Unsubscribe(_) => &spec::UNSUBSCRIBE,
+            PSubscribe(_) => &spec::PSUBSCRIBE,
+            PUnsubscribe(_) => &spec::PUNSUBSCRIBE,
+            Publish(..) => &spec::PUBLISH,
+            PubSubChannels(_) => &spec::PUBSUB_CHANNELS,
+            PubSubNumSub(_) => &spec::PUBSUB_NUMSUB,
+            PubSubNumPat => &spec::PUBSUB_NUMPAT,
+            SSubscribe(_) => &spec::SSUBSCRIBE,
+            SUnsubscribe(_) => &spec::SUNSUBSCRIBE,
+            SPublish(..) => &spec::SPUBLISH,
+            PubSubShardChannels(_) => &spec::PUBSUB_SHARDCHANNELS,
+            PubSubShardNumSub(_) => &spec::PUBSUB_SHARDNUMSUB,
+            Auth(..) => &spec::AUTH,
+            Hello(..) => &spec::HELLO,
+            ClientId => &spec::CLIENT_ID,
+            ClientTracking(..) => &spec::CLIENT_TRACKING,
+            ClientCaching(_) => &spec::CLIENT_CACHING,
+            ClientList(_) => &spec::CLIENT_LIST,
+            ClientInfo => &spec::CLIENT_INFO,
+            ClientSetName(_) => &spec::CLIENT_SETNAME,
+            ClientGetName => &spec::CLIENT_GETNAME,
+            ClientKill(_) => &spec::CLIENT_KILL,
+            ClientPause(..) => &spec::CLIENT_PAUSE,
+            ClientUnpause => &spec::CLIENT_UNPAUSE,
+            ClientReply(_) => &spec::CLIENT_REPLY,
+            CommandInfo(_) => &spec::COMMAND_INFO,
+            CommandCount => &spec::COMMAND_COUNT,
+            CommandDocs(_) => &spec::COMMAND_DOCS,
+            CommandList(_) => &spec::COMMAND_LIST,
+            CommandGetKeys(_) => &spec::COMMAND_GETKEYS,
+            Quit => &spec::QUIT,
+            Info(_) => &spec::INFO,
+            ConfigGet(_) => &spec::CONFIG_GET,
+            ConfigSet(_) => &spec::CONFIG_SET,
+            ConfigResetStat => &spec::CONFIG_RESETSTAT,
+            ConfigRewrite => &spec::CONFIG_REWRITE,
+            FlushAll(_) => &spec::FLUSHALL,
+            FlushDB(_) => &spec::FLUSHDB,
+        }
+    }
+
+    /// Whether this command is executed by the writer, because it may modify the data (or is
+    /// propagated like a write, such as scripts and `PUBLISH`)
     pub fn writes(&self) -> bool {
+        let spec = self.spec();
+        spec.has(Flag::Write) || spec.has(Flag::MayReplicate)
+    }
+
+    /// The keys this command reads, which a tracking client may cache
+    pub fn read_keys(&self) -> Vec<Bytes> {
         use Command::*;
         match self {
-            Ping(_) | Get(_) | Exists(_) => false,
-            _ => true,
+            Get(key) => vec![key.clone()],
+            Exists(keys) | FCallRO(_, keys, _) => keys.clone(),
+            _ => Vec::new(),
         }
     }
 
@@ -76,23 +377,311 @@ impl Command {
     pub fn reads(&self) -> bool {
         !self.writes()
     }
+
+    /// Whether this command changes the channels or patterns the connection is subscribed to
+    pub fn changes_subscriptions(&self) -> bool {
+        use Command::*;
+        match self {
+            Subscribe(_) | Unsubscribe(_) | PSubscribe(_) | PUnsubscribe(_) => true,
+            SSubscribe(_) | SUnsubscribe(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this command is executed by the connection itself, rather than by the storage
+    pub fn runs_on_connection(&self) -> bool {
+        use Command::*;
+        match self {
+            ClientId | ClientList(_) | ClientInfo | ClientSetName(_) | ClientGetName => true,
+            ClientKill(_) | ClientPause(..) | ClientUnpause => true,
+            CommandInfo(_) | CommandCount | CommandDocs(_) | CommandList(_) | CommandGetKeys(_) => {
+                true
+            }
+            Info(_) | ConfigGet(_) | ConfigSet(_) | ConfigResetStat | ConfigRewrite => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this command may be called while the connection is subscribed to channels
+    pub fn allowed_in_subscriber_mode(&self) -> bool {
+        match self {
+            Command::Ping(_) | Command::Quit => true,
+            command => command.changes_subscriptions(),
+        }
+    }
+
+    /// Whether this command may be queued in a transaction
+    ///
+    /// Besides the commands flagged `no_multi`, this excludes the commands that change how the
+    /// connection itself behaves (its subscriptions, authentication, protocol, tracking or
+    /// replies), which take effect right away.
+    pub fn allowed_in_transactions(&self) -> bool {
+        use Command::*;
+        match self {
+            Auth(..) | Hello(..) | ClientTracking(..) | ClientCaching(_) | ClientReply(_) => false,
+            command if command.changes_subscriptions() => false,
+            command => !command.spec().has(Flag::NoMulti),
+        }
+    }
+
+    /// Whether this command may be called from a script
+    pub fn allowed_in_scripts(&self) -> bool {
+        !self.spec().has(Flag::NoScript)
+    }
+
+    /// Whether this command may be called before the connection has authenticated
+    pub fn allowed_without_auth(&self) -> bool {
+        self.spec().has(Flag::NoAuth)
+    }
 }
 
 /// An error response
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Error {
     WrongType,
     Syntax,
     UnknownCommand(Bytes),
+    UnknownSubcommand(&'static str, Bytes),
+    WrongArity(&'static str),
+    InvalidCommand,
+    InvalidCommandArguments,
+    NoKeyArguments,
+    NoSuchKey,
+    SameObject,
+    NotAnInteger,
+    DbIndexOutOfRange,
+    NestedMulti,
+    ExecWithoutMulti,
+    DiscardWithoutMulti,
+    ExecAbort,
+    WatchInsideMulti,
+    NoScript,
+    NotAllowedFromScript,
+    UnknownScriptCommand,
+    NoSuchFunction,
+    NoSuchLibrary,
+    WriteFlagInReadOnlyCall,
+    WriteFromReadOnlyScript,
+    NotAllowedInSubscriberMode,
+    NotAllowedInTransaction,
+    PrefixWithoutBroadcast,
+    OptInAndOptOut,
+    OptInOptOutWithBroadcast,
+    NoSuchRedirectClient,
+    CachingWithoutOptInOut,
+    UnsupportedProtocol,
+    WrongPass,
+    NoAuth,
+    HelloNoAuth,
+    AuthNotConfigured,
+    InvalidClientName,
+    NoSuchClient,
+    MaxClients,
+    /// `CONFIG SET` of a parameter that doesn't exist
+    UnknownConfig(Bytes),
+    /// `CONFIG SET` of an invalid value (or an immutable parameter), with the reason
+    ConfigSetFailed(&'static str, String),
+    /// `CONFIG REWRITE` without a configuration file
+    NoConfigFile,
+    /// `CONFIG REWRITE` failed to write the configuration file
+    ConfigRewriteFailed(String),
+    /// A protocol violation (after which the connection is closed)
+    Protocol(String),
+    /// An arbitrary error reply (e.g. raised by a script)
+    Reply(Bytes),
+}
+impl Error {
+    /// The error message, prefixed by its error code
+    pub fn message(&self) -> Bytes {
+        use Error::*;
+        let msg: &'static [u8] = match self {
+            WrongType => b"WRONGTYPE Operation against a key holding the wrong kind of value",
+            Syntax => b"ERR syntax error",
+            NoSuchKey => b"ERR no such key",
+            SameObject => b"ERR source and destination objects are the same",
+            NotAnInteger => b"ERR value is not an integer or out of range",
+            DbIndexOutOfRange => b"ERR DB index is out of range",
+            NestedMulti => b"ERR MULTI calls can not be nested",
+            ExecWithoutMulti => b"ERR EXEC without MULTI",
+            DiscardWithoutMulti => b"ERR DISCARD without MULTI",
+            ExecAbort => b"EXECABORT Transaction discarded because of previous errors.",
+            WatchInsideMulti => b"ERR WATCH inside MULTI is not allowed",
+            NoScript => b"NOSCRIPT No matching script. Please use EVAL.",
+            NotAllowedFromScript => b"ERR This Redis command is not allowed from scripts",
+            UnknownScriptCommand => b"ERR Unknown Redis command called from script",
+            NoSuchFunction => b"ERR Function not found",
+            NoSuchLibrary => b"ERR Library not found",
+            WriteFlagInReadOnlyCall => {
+                b"ERR Can not execute a script with write flag using *_ro command."
+            }
+            WriteFromReadOnlyScript => {
+                b"ERR Write commands are not allowed from read-only scripts."
+            }
+            NotAllowedInSubscriberMode => {
+                b"ERR only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT allowed in this context"
+            }
+            NotAllowedInTransaction => b"ERR Command not allowed inside a transaction",
+            PrefixWithoutBroadcast => b"ERR PREFIX option requires BCAST mode to be enabled",
+            OptInAndOptOut => b"ERR You can't use both OPTIN and OPTOUT",
+            OptInOptOutWithBroadcast => b"ERR OPTIN and OPTOUT are not compatible with BCAST",
+            NoSuchRedirectClient => b"ERR The client ID you want redirect to does not exist",
+            CachingWithoutOptInOut => {
+                b"ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled"
+            }
+            UnsupportedProtocol => b"NOPROTO unsupported protocol version",
+            WrongPass => b"WRONGPASS invalid username-password pair or user is disabled.",
+            NoAuth => b"NOAUTH Authentication required.",
+            HelloNoAuth => {
+                b"NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"
+            }
+            AuthNotConfigured => {
+                b"ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"
+            }
+            InvalidClientName => {
+                b"ERR Client names cannot contain spaces, newlines or special characters."
+            }
+            NoSuchClient => b"ERR No such client",
+            MaxClients => b"ERR max number of clients reached",
+            InvalidCommand => b"ERR Invalid command specified",
+            InvalidCommandArguments => b"ERR Invalid number of arguments specified for command",
+            NoKeyArguments => b"ERR The command has no key arguments",
+            NoConfigFile => b"ERR The server is running without a config file",
+            UnknownConfig(name) => {
+                return Bytes::from(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    String::from_utf8_lossy(name)
+                ))
+            }
+            ConfigSetFailed(name, reason) => {
+                return Bytes::from(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    name, reason
+                ))
+            }
+            ConfigRewriteFailed(err) => {
+                return Bytes::from(format!("ERR Rewriting config file: {}", err))
+            }
+            Protocol(msg) => return Bytes::from(format!("ERR Protocol error: {}", msg)),
+            Reply(msg) => return msg.clone(),
+            WrongArity(cmd) => {
+                return Bytes::from(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    cmd
+                ))
+            }
+            UnknownSubcommand(cmd, subcommand) => {
+                return Bytes::from(format!(
+                    "ERR unknown subcommand '{}'. Try {} HELP.",
+                    String::from_utf8_lossy(subcommand),
+                    cmd.to_ascii_uppercase()
+                ))
+            }
+            UnknownCommand(cmd) => {
+                let mut msg = BytesMut::from(&b"ERR unknown command '"[..]);
+                msg.reserve(cmd.len() + 1);
+                msg.put(&cmd[..]);
+                msg.put("'");
+                return msg.freeze();
+            }
+        };
+        Bytes::from_static(msg)
+    }
 }
 
 /// A response
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Response {
     Ok,
     Error(Error),
     Nil,
     Pong,
+    Queued,
+    Status(Bytes),
     Integer(i64),
     Bulk(Bytes),
+    Array(Vec<Response>),
+    /// An ordered map (RESP3 only, sent as a flat array to RESP2 clients)
+    Map(Vec<(Response, Response)>),
+    /// An unordered collection of distinct elements (RESP3 only)
+    Set(Vec<Response>),
+    /// A floating point number (RESP3 only, sent as a bulk string to RESP2 clients)
+    Double(f64),
+    /// A boolean (RESP3 only, sent as 1 or 0 to RESP2 clients)
+    Boolean(bool),
+    /// A string with a three-letter format (such as `txt` or `mkd`) (RESP3 only)
+    Verbatim(Bytes, Bytes),
+    /// An arbitrarily large integer, in decimal (RESP3 only)
+    BigNumber(Bytes),
+    /// A response with auxiliary attributes (RESP3 only, RESP2 clients only get the response)
+    Attribute(Vec<(Response, Response)>, Box<Response>),
+    /// An out-of-band message, such as a pub/sub message (sent as an array to RESP2 clients)
+    Push(Vec<Response>),
+}
+impl Response {
+    /// Convert RESP3-only types to their closest RESP2 equivalent
+    pub fn downgrade(self) -> Response {
+        match self {
+            Response::Array(elements) | Response::Set(elements) | Response::Push(elements) => {
+                Response::Array(elements.into_iter().map(Response::downgrade).collect())
+            }
+            Response::Map(entries) => Response::Array(
+                entries
+                    .into_iter()
+                    .flat_map(|(key, value)| vec![key.downgrade(), value.downgrade()])
+                    .collect(),
+            ),
+            Response::Double(value) => Response::Bulk(Bytes::from(format_double(value))),
+            Response::Boolean(value) => Response::Integer(value as i64),
+            Response::Verbatim(_, data) | Response::BigNumber(data) => Response::Bulk(data),
+            Response::Attribute(_, response) => response.downgrade(),
+            response => response,
+        }
+    }
+
+    /// Approximately how many bytes the response takes on the wire (used to account for queued
+    /// responses)
+    pub fn size(&self) -> usize {
+        // The type byte and the terminating CRLF
+        const OVERHEAD: usize = 3;
+        let header = |len: usize| OVERHEAD + len.to_string().len();
+        let pairs = |entries: &[(Response, Response)]| -> usize {
+            entries
+                .iter()
+                .map(|(key, value)| key.size() + value.size())
+                .sum()
+        };
+        match self {
+            Response::Error(err) => OVERHEAD + err.message().len(),
+            Response::Status(data) | Response::BigNumber(data) => OVERHEAD + data.len(),
+            Response::Integer(value) => OVERHEAD + value.to_string().len(),
+            Response::Double(value) => OVERHEAD + format_double(*value).len(),
+            Response::Bulk(data) => header(data.len()) + data.len() + 2,
+            Response::Verbatim(format, data) => {
+                let len = format.len() + 1 + data.len();
+                header(len) + len + 2
+            }
+            Response::Array(elements) | Response::Set(elements) | Response::Push(elements) => {
+                header(elements.len()) + elements.iter().map(Response::size).sum::<usize>()
+            }
+            Response::Map(entries) => header(entries.len()) + pairs(entries),
+            Response::Attribute(entries, response) => {
+                header(entries.len()) + pairs(entries) + response.size()
+            }
+            Response::Ok | Response::Nil | Response::Pong | Response::Queued => OVERHEAD + 3,
+            Response::Boolean(_) => OVERHEAD + 1,
+        }
+    }
+}
+
+/// Format a floating point number the way Redis does (using `inf`, `-inf` and `nan`)
+pub fn format_double(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_owned()
+    } else if value.is_infinite() && value > 0.0 {
+        "inf".to_owned()
+    } else if value.is_infinite() {
+        "-inf".to_owned()
+    } else {
+        value.to_string()
+    }
 }
@@ -0,0 +1,1612 @@
+//! The command table: the name, arity, flags, key positions and argument parser of every command
+
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::codecs::DecodeError;
+use crate::utils::glob;
+
+use super::{
+    Command, Conditional, Error, KillFilter, PauseMode, ReplyMode, Response, RestorePolicy,
+    Synchronicity, TrackingOptions,
+};
+
+/// A property of a command, as reported by `COMMAND`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    /// The command may modify the data
+    Write,
+    /// The command only reads the data
+    ReadOnly,
+    /// The command may increase memory usage, so it is refused when out of memory
+    DenyOom,
+    /// The command is an administrative command
+    Admin,
+    /// The command is related to pub/sub
+    PubSub,
+    /// The command can't be called from scripts
+    NoScript,
+    /// The command can't be queued in a transaction
+    NoMulti,
+    /// The command may block the connection
+    Blocking,
+    /// The command is allowed while the database is loading
+    Loading,
+    /// The command is allowed on a replica with stale data
+    Stale,
+    /// The command runs in constant or logarithmic time
+    Fast,
+    /// The command may be propagated to replicas, even though it isn't a write
+    MayReplicate,
+    /// The command is allowed before the connection has authenticated
+    NoAuth,
+}
+impl Flag {
+    /// The flag's name, as reported by `COMMAND`
+    pub fn name(self) -> &'static str {
+        use Flag::*;
+        match self {
+            Write => "write",
+            ReadOnly => "readonly",
+            DenyOom => "denyoom",
+            Admin => "admin",
+            PubSub => "pubsub",
+            NoScript => "noscript",
+            NoMulti => "no_multi",
+            Blocking => "blocking",
+            Loading => "loading",
+            Stale => "stale",
+            Fast => "fast",
+            MayReplicate => "may_replicate",
+            NoAuth => "no_auth",
+        }
+    }
+}
+
+/// The group a command is documented in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    Generic,
+    String,
+    Connection,
+    Server,
+    Transactions,
+    Scripting,
+    PubSub,
+}
+impl Group {
+    /// The group's name, as reported by `COMMAND DOCS`
+    pub fn name(self) -> &'static str {
+        use Group::*;
+        match self {
+            Generic => "generic",
+            String => "string",
+            Connection => "connection",
+            Server => "server",
+            Transactions => "transactions",
+            Scripting => "scripting",
+            PubSub => "pubsub",
+        }
+    }
+
+    /// The ACL category of the group's commands, if any
+    fn category(self) -> Option<&'static str> {
+        use Group::*;
+        match self {
+            Generic => Some("keyspace"),
+            String => Some("string"),
+            Connection => Some("connection"),
+            Server => None,
+            Transactions => Some("transaction"),
+            Scripting => Some("scripting"),
+            PubSub => Some("pubsub"),
+        }
+    }
+}
+
+/// Where the keys are in a command's arguments (counting the command name as argument 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keys {
+    /// The command has no keys
+    None,
+    /// Every `step`th argument from `first` to `last` is a key (a negative `last` counts from the
+    /// end)
+    Range {
+        first: usize,
+        last: isize,
+        step: usize,
+    },
+    /// The argument at this position is the number of keys, which follow right after it
+    Counted(usize),
+}
+impl Keys {
+    /// The positions of the keys in a command's arguments, or `None` if the number of keys is
+    /// invalid
+    pub fn positions(self, args: &[Bytes]) -> Option<Vec<usize>> {
+        match self {
+            Keys::None => Some(Vec::new()),
+            Keys::Range { first, last, step } => {
+                let last = if last < 0 {
+                    args.len() as isize + last
+                } else {
+                    last
+                };
+                if last < first as isize {
+                    return Some(Vec::new());
+                }
+                if last as usize >= args.len() {
+                    return None;
+                }
+                Some((first..=last as usize).step_by(step).collect())
+            }
+            Keys::Counted(index) => {
+                let count: usize = std::str::from_utf8(args.get(index)?).ok()?.parse().ok()?;
+                if index + 1 + count > args.len() {
+                    return None;
+                }
+                Some((index + 1..index + 1 + count).collect())
+            }
+        }
+    }
+
+    /// The legacy `(first, last, step)` key positions reported by `COMMAND INFO` (all zero if the
+    /// positions depend on the arguments)
+    fn legacy(self) -> (i64, i64, i64) {
+        match self {
+            Keys::Range { first, last, step } => (first as i64, last as i64, step as i64),
+            Keys::None | Keys::Counted(_) => (0, 0, 0),
+        }
+    }
+}
+
+/// The definition of a command
+pub struct CommandSpec {
+    /// The lowercase name (`container|subcommand` for subcommands)
+    pub name: &'static str,
+    /// The number of arguments including the name, or minus the minimum number if it's negative
+    pub arity: isize,
+    pub flags: &'static [Flag],
+    pub keys: Keys,
+    pub group: Group,
+    /// A short description for `COMMAND DOCS`
+    pub summary: &'static str,
+    /// The subcommands, for container commands like `SCRIPT`
+    pub subcommands: &'static [&'static CommandSpec],
+    /// Parse the command from its name and arguments (for container commands, this is only called
+    /// if there is no such subcommand)
+    pub parse: fn(&[Bytes]) -> Result<Command, DecodeError>,
+}
+impl CommandSpec {
+    /// Whether the command has the given flag
+    pub fn has(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Whether the command accepts this number of arguments (including its name)
+    pub fn accepts(&self, count: usize) -> bool {
+        if self.arity < 0 {
+            count >= -self.arity as usize
+        } else {
+            count == self.arity as usize
+        }
+    }
+
+    /// Find a subcommand by name, ignoring case
+    pub fn subcommand(&self, name: &[u8]) -> Option<&'static CommandSpec> {
+        self.subcommands.iter().cloned().find(|spec| {
+            let suffix = spec.name.rsplit('|').next().unwrap_or(spec.name);
+            suffix.as_bytes().eq_ignore_ascii_case(name)
+        })
+    }
+
+    /// The ACL categories the command belongs to (without the `@` prefix)
+    pub fn categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        if self.has(Flag::Write) {
+            categories.push("write");
+        }
+        if self.has(Flag::ReadOnly) {
+            categories.push("read");
+        }
+        if self.has(Flag::Admin) {
+            categories.push("admin");
+            categories.push("dangerous");
+        }
+        if self.has(Flag::Blocking) {
+            categories.push("blocking");
+        }
+        categories.push(if self.has(Flag::Fast) { "fast" } else { "slow" });
+        if let Some(category) = self.group.category() {
+            categories.push(category);
+        }
+        categories
+    }
+
+    /// The reply to `COMMAND INFO` for this command
+    pub fn info(&self) -> Response {
+        let mut flags: Vec<Response> = self
+            .flags
+            .iter()
+            .map(|flag| Response::Status(Bytes::from_static(flag.name().as_bytes())))
+            .collect();
+        if let Keys::Counted(_) = self.keys {
+            flags.push(Response::Status(Bytes::from_static(b"movablekeys")));
+        }
+        let (first, last, step) = self.keys.legacy();
+
+        Response::Array(vec![
+            Response::Bulk(Bytes::from_static(self.name.as_bytes())),
+            Response::Integer(self.arity as i64),
+            Response::Set(flags),
+            Response::Integer(first),
+            Response::Integer(last),
+            Response::Integer(step),
+            Response::Set(
+                self.categories()
+                    .into_iter()
+                    .map(|category| Response::Status(Bytes::from(format!("@{}", category))))
+                    .collect(),
+            ),
+            Response::Array(Vec::new()),
+            Response::Array(self.key_specs()),
+            Response::Array(self.subcommands.iter().map(|spec| spec.info()).collect()),
+        ])
+    }
+
+    /// The key specifications reported by `COMMAND INFO`
+    fn key_specs(&self) -> Vec<Response> {
+        fn bulk(data: &'static str) -> Response {
+            Response::Bulk(Bytes::from_static(data.as_bytes()))
+        }
+        fn status(data: &'static str) -> Response {
+            Response::Status(Bytes::from_static(data.as_bytes()))
+        }
+
+        let (begin, find) = match self.keys {
+            Keys::None => return Vec::new(),
+            Keys::Range { first, last, step } => (
+                first,
+                Response::Map(vec![
+                    (bulk("type"), bulk("range")),
+                    (
+                        bulk("spec"),
+                        Response::Map(vec![
+                            (
+                                bulk("lastkey"),
+                                Response::Integer(if last < 0 {
+                                    last as i64
+                                } else {
+                                    last as i64 - first as i64
+                                }),
+                            ),
+                            (bulk("keystep"), Response::Integer(step as i64)),
+                            (bulk("limit"), Response::Integer(0)),
+                        ]),
+                    ),
+                ]),
+            ),
+            Keys::Counted(index) => (
+                index,
+                Response::Map(vec![
+                    (bulk("type"), bulk("keynum")),
+                    (
+                        bulk("spec"),
+                        Response::Map(vec![
+                            (bulk("keynumidx"), Response::Integer(0)),
+                            (bulk("firstkey"), Response::Integer(1)),
+                            (bulk("keystep"), Response::Integer(1)),
+                        ]),
+                    ),
+                ]),
+            ),
+        };
+        let flags = if self.has(Flag::PubSub) {
+            vec![status("NOT_KEY")]
+        } else if self.has(Flag::ReadOnly) {
+            vec![status("RO"), status("ACCESS")]
+        } else if self.has(Flag::Write) {
+            vec![status("RW"), status("UPDATE")]
+        } else {
+            vec![status("RW"), status("ACCESS"), status("UPDATE")]
+        };
+
+        vec![Response::Map(vec![
+            (bulk("flags"), Response::Set(flags)),
+            (
+                bulk("begin_search"),
+                Response::Map(vec![
+                    (bulk("type"), bulk("index")),
+                    (
+                        bulk("spec"),
+                        Response::Map(vec![(bulk("index"), Response::Integer(begin as i64))]),
+                    ),
+                ]),
+            ),
+            (bulk("find_keys"), find),
+        ])]
+    }
+
+    /// The reply to `COMMAND DOCS` for this command
+    pub fn docs(&self) -> Response {
+        let mut docs = vec![
+            (
+                Response::Bulk(Bytes::from_static(b"summary")),
+                Response::Bulk(Bytes::from_static(self.summary.as_bytes())),
+            ),
+            (
+                Response::Bulk(Bytes::from_static(b"group")),
+                Response::Bulk(Bytes::from_static(self.group.name().as_bytes())),
+            ),
+        ];
+        if !self.subcommands.is_empty() {
+            docs.push((
+                Response::Bulk(Bytes::from_static(b"subcommands")),
+                Response::Map(
+                    self.subcommands
+                        .iter()
+                        .map(|spec| {
+                            (
+                                Response::Bulk(Bytes::from_static(spec.name.as_bytes())),
+                                spec.docs(),
+                            )
+                        })
+                        .collect(),
+                ),
+            ));
+        }
+        Response::Map(docs)
+    }
+
+    /// This command and all its subcommands
+    fn with_subcommands(&'static self) -> impl Iterator<Item = &'static CommandSpec> {
+        std::iter::once(self).chain(self.subcommands.iter().cloned())
+    }
+}
+
+/// A filter for `COMMAND LIST`
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandFilter {
+    /// Commands of a module (there are none)
+    Module(Bytes),
+    /// Commands in an ACL category
+    AclCategory(Bytes),
+    /// Commands with names matching a pattern
+    Pattern(Bytes),
+}
+
+/// Find a command by name, ignoring case
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .cloned()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+/// Find a command by its name or its `container|subcommand` name, ignoring case
+pub fn find(name: &[u8]) -> Option<&'static CommandSpec> {
+    let mut parts = name.splitn(2, |&x| x == b'|');
+    let spec = lookup(parts.next()?)?;
+    match parts.next() {
+        Some(subcommand) => spec.subcommand(subcommand),
+        None => Some(spec),
+    }
+}
+
+/// The reply to `COMMAND INFO` (or `COMMAND`) for the given commands, or all commands
+pub fn info(names: &[Bytes]) -> Response {
+    if names.is_empty() {
+        return Response::Array(COMMANDS.iter().map(|spec| spec.info()).collect());
+    }
+    Response::Array(
+        names
+            .iter()
+            .map(|name| find(name).map_or(Response::Nil, |spec| spec.info()))
+            .collect(),
+    )
+}
+
+/// The reply to `COMMAND DOCS` for the given commands (skipping unknown ones), or all commands
+pub fn docs(names: &[Bytes]) -> Response {
+    let specs: Vec<&CommandSpec> = if names.is_empty() {
+        COMMANDS.to_vec()
+    } else {
+        names.iter().filter_map(|name| find(name)).collect()
+    };
+    Response::Map(
+        specs
+            .into_iter()
+            .map(|spec| {
+                (
+                    Response::Bulk(Bytes::from_static(spec.name.as_bytes())),
+                    spec.docs(),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// The reply to `COMMAND LIST`: the names of all commands and subcommands matching the filter
+pub fn list(filter: Option<&CommandFilter>) -> Response {
+    Response::Array(
+        COMMANDS
+            .iter()
+            .flat_map(|spec| spec.with_subcommands())
+            .filter(|spec| match filter {
+                None => true,
+                Some(CommandFilter::Module(_)) => false,
+                Some(CommandFilter::AclCategory(category)) => spec
+                    .categories()
+                    .iter()
+                    .any(|x| x.as_bytes().eq_ignore_ascii_case(category)),
+                Some(CommandFilter::Pattern(pattern)) => {
+                    glob::matches(pattern, spec.name.as_bytes())
+                }
+            })
+            .map(|spec| Response::Bulk(Bytes::from_static(spec.name.as_bytes())))
+            .collect(),
+    )
+}
+
+/// The keys of a command (given with its arguments), for `COMMAND GETKEYS`
+pub fn get_keys(elems: &[Bytes]) -> Result<Vec<Bytes>, Error> {
+    let mut spec = lookup(&elems[0]).ok_or(Error::InvalidCommand)?;
+    if let Some(subcommand) = elems.get(1).and_then(|name| spec.subcommand(name)) {
+        spec = subcommand;
+    }
+    if !spec.accepts(elems.len()) {
+        return Err(Error::InvalidCommandArguments);
+    }
+
+    let positions = spec
+        .keys
+        .positions(elems)
+        .ok_or(Error::InvalidCommandArguments)?;
+    if positions.is_empty() {
+        return Err(Error::NoKeyArguments);
+    }
+    Ok(positions.into_iter().map(|i| elems[i].clone()).collect())
+}
+
+/// Parse a command from its name and arguments
+pub fn parse(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let name = elems
+        .first()
+        .ok_or(DecodeError::UnexpectedNumberOfArguments)?;
+    let mut spec = lookup(name).ok_or_else(|| DecodeError::UnrecognizedCommand(name.clone()))?;
+    if !spec.accepts(elems.len()) {
+        return Err(DecodeError::WrongArity(spec.name));
+    }
+
+    if let Some(subcommand) = elems.get(1).and_then(|name| spec.subcommand(name)) {
+        spec = subcommand;
+        if !spec.accepts(elems.len()) {
+            return Err(DecodeError::WrongArity(spec.name));
+        }
+    }
+    (spec.parse)(elems)
+}
+
+/// Define commands as `ID: name, arity, [flags], keys, group, parser, summary;`
+macro_rules! commands {
+    ($(
+        $id:ident: $name:expr, $arity:expr, [$($flag:ident),*], $keys:expr, $group:ident,
+        $parse:expr, $summary:expr;
+    )*) => {
+        $(
+            pub static $id: CommandSpec = CommandSpec {
+                name: $name,
+                arity: $arity,
+                flags: &[$(Flag::$flag),*],
+                keys: $keys,
+                group: Group::$group,
+                summary: $summary,
+                subcommands: &[],
+                parse: $parse,
+            };
+        )*
+    };
+}
+
+/// Define container commands as `ID: name, [flags], group, [subcommands], summary;`
+///
+/// Container commands need a subcommand, unless they have their own parser (as the last argument).
+macro_rules! containers {
+    ($(
+        $id:ident: $name:expr, [$($flag:ident),*], $group:ident,
+        [$($subcommand:ident),* $(,)*], $summary:expr $(, $parse:expr)*;
+    )*) => {
+        $(
+            pub static $id: CommandSpec = CommandSpec {
+                name: $name,
+                arity: containers!(@arity $($parse)*),
+                flags: &[$(Flag::$flag),*],
+                keys: Keys::None,
+                group: Group::$group,
+                summary: $summary,
+                subcommands: &[$(&$subcommand),*],
+                parse: containers!(@parse $($parse)*),
+            };
+        )*
+    };
+    (@arity) => { -2 };
+    (@arity $parse:expr) => { -1 };
+    (@parse) => { unknown_subcommand };
+    (@parse $parse:expr) => { $parse };
+}
+
+/// A single key right after the command name
+const KEY: Keys = Keys::Range {
+    first: 1,
+    last: 1,
+    step: 1,
+};
+/// All arguments are keys
+const ALL_KEYS: Keys = Keys::Range {
+    first: 1,
+    last: -1,
+    step: 1,
+};
+/// A source and destination key right after the command name
+const TWO_KEYS: Keys = Keys::Range {
+    first: 1,
+    last: 2,
+    step: 1,
+};
+/// The number of keys right after the script or function, followed by the keys
+const SCRIPT_KEYS: Keys = Keys::Counted(2);
+
+commands! {
+    PING: "ping", -1, [Fast], Keys::None, Connection, parse_ping,
+        "Ping the server";
+
+    GET: "get", 2, [ReadOnly, Fast], KEY, String, parse_get,
+        "Get the value of a key";
+    SET: "set", -3, [Write, DenyOom], KEY, String, parse_set,
+        "Set the value of a key, optionally with an expiration or only if it (doesn't) exist";
+
+    DEL: "del", -2, [Write], ALL_KEYS, Generic, parse_del,
+        "Delete keys";
+    EXISTS: "exists", -2, [ReadOnly, Fast], ALL_KEYS, Generic, parse_exists,
+        "Count how many of the given keys exist";
+    EXPIRE: "expire", 3, [Write, Fast], KEY, Generic, parse_expire,
+        "Set a key's time to live in seconds";
+    PEXPIRE: "pexpire", 3, [Write, Fast], KEY, Generic, parse_pexpire,
+        "Set a key's time to live in milliseconds";
+    PERSIST: "persist", 2, [Write, Fast], KEY, Generic, parse_persist,
+        "Remove the expiration from a key";
+    RENAME: "rename", 3, [Write], TWO_KEYS, Generic, parse_rename,
+        "Rename a key, overwriting the destination";
+    RENAMENX: "renamenx", 3, [Write, Fast], TWO_KEYS, Generic, parse_renamenx,
+        "Rename a key, only if the destination doesn't exist";
+    COPY: "copy", -3, [Write, DenyOom], TWO_KEYS, Generic, parse_copy,
+        "Copy the value of a key to another key, optionally in another database";
+    MOVE: "move", 3, [Write, Fast], KEY, Generic, parse_move,
+        "Move a key to another database";
+
+    SELECT: "select", 2, [Loading, Stale, Fast], Keys::None, Connection, parse_select,
+        "Change the selected database";
+
+    SWAPDB: "swapdb", 3, [Write, Fast], Keys::None, Server, parse_swapdb,
+        "Swap the contents of two databases";
+
+    MULTI: "multi", 1, [NoScript, Loading, Stale, Fast, NoMulti],
+        Keys::None, Transactions, parse_multi,
+        "Start a transaction";
+    EXEC: "exec", 1, [NoScript, Loading, Stale], Keys::None, Transactions, parse_exec,
+        "Execute all commands queued in a transaction";
+    DISCARD: "discard", 1, [NoScript, Loading, Stale, Fast],
+        Keys::None, Transactions, parse_discard,
+        "Discard all commands queued in a transaction";
+    WATCH: "watch", -2, [NoScript, Loading, Stale, Fast, NoMulti],
+        ALL_KEYS, Transactions, parse_watch,
+        "Abort the next transaction if any of the given keys are modified";
+    UNWATCH: "unwatch", 1, [NoScript, Loading, Stale, Fast],
+        Keys::None, Transactions, parse_unwatch,
+        "Forget about all watched keys";
+
+    EVAL: "eval", -3, [NoScript, Stale, MayReplicate], SCRIPT_KEYS, Scripting, parse_eval,
+        "Execute a Lua script";
+    EVALSHA: "evalsha", -3, [NoScript, Stale, MayReplicate], SCRIPT_KEYS, Scripting, parse_evalsha,
+        "Execute a cached Lua script by its SHA1 digest";
+    SCRIPT_LOAD: "script|load", 3, [NoScript, Stale, MayReplicate],
+        Keys::None, Scripting, parse_script_load,
+        "Load a Lua script into the script cache";
+    SCRIPT_EXISTS: "script|exists", -3, [NoScript, MayReplicate],
+        Keys::None, Scripting, parse_script_exists,
+        "Check whether scripts exist in the script cache";
+    SCRIPT_FLUSH: "script|flush", -2, [NoScript, MayReplicate],
+        Keys::None, Scripting, parse_script_flush,
+        "Remove all scripts from the script cache";
+    FCALL: "fcall", -3, [NoScript, Stale, MayReplicate], SCRIPT_KEYS, Scripting, parse_fcall,
+        "Call a library function";
+    FCALL_RO: "fcall_ro", -3, [NoScript, Stale, ReadOnly], SCRIPT_KEYS, Scripting, parse_fcall_ro,
+        "Call a read-only library function";
+    FUNCTION_LOAD: "function|load", -3, [Write, DenyOom, NoScript],
+        Keys::None, Scripting, parse_function_load,
+        "Create (or replace) a function library";
+    FUNCTION_DELETE: "function|delete", 3, [Write, NoScript],
+        Keys::None, Scripting, parse_function_delete,
+        "Delete a function library";
+    FUNCTION_FLUSH: "function|flush", -2, [Write, NoScript],
+        Keys::None, Scripting, parse_function_flush,
+        "Delete all function libraries";
+    FUNCTION_LIST: "function|list", -2, [NoScript], Keys::None, Scripting, parse_function_list,
+        "List the function libraries";
+    FUNCTION_DUMP: "function|dump", 2, [NoScript], Keys::None, Scripting, parse_function_dump,
+        "Serialize all function libraries";
+    FUNCTION_RESTORE: "function|restore", -3, [Write, DenyOom, NoScript],
+        Keys::None, Scripting, parse_function_restore,
+        "Restore function libraries from a serialized payload";
+
+    SUBSCRIBE: "subscribe", -2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_subscribe,
+        "Listen for messages published to channels";
+    UNSUBSCRIBE: "unsubscribe", -1, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_unsubscribe,
+        "Stop listening for messages published to channels";
+    PSUBSCRIBE: "psubscribe", -2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_psubscribe,
+        "Listen for messages published to channels matching patterns";
+    PUNSUBSCRIBE: "punsubscribe", -1, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_punsubscribe,
+        "Stop listening for messages published to channels matching patterns";
+    PUBLISH: "publish", 3, [PubSub, Loading, Stale, Fast, MayReplicate],
+        Keys::None, PubSub, parse_publish,
+        "Post a message to a channel";
+    // Sharded channels are hashed like keys, so they are reported as keys
+    SSUBSCRIBE: "ssubscribe", -2, [PubSub, NoScript, Loading, Stale],
+        ALL_KEYS, PubSub, parse_ssubscribe,
+        "Listen for messages published to shard channels";
+    SUNSUBSCRIBE: "sunsubscribe", -1, [PubSub, NoScript, Loading, Stale],
+        ALL_KEYS, PubSub, parse_sunsubscribe,
+        "Stop listening for messages published to shard channels";
+    SPUBLISH: "spublish", 3, [PubSub, Loading, Stale, Fast, MayReplicate],
+        KEY, PubSub, parse_spublish,
+        "Post a message to a shard channel";
+    PUBSUB_CHANNELS: "pubsub|channels", -2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_pubsub_channels,
+        "List the channels with subscribers";
+    PUBSUB_NUMSUB: "pubsub|numsub", -2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_pubsub_numsub,
+        "Count the subscribers of channels";
+    PUBSUB_NUMPAT: "pubsub|numpat", 2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_pubsub_numpat,
+        "Count the subscribed patterns";
+    PUBSUB_SHARDCHANNELS: "pubsub|shardchannels", -2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_pubsub_shardchannels,
+        "List the shard channels with subscribers";
+    PUBSUB_SHARDNUMSUB: "pubsub|shardnumsub", -2, [PubSub, NoScript, Loading, Stale],
+        Keys::None, PubSub, parse_pubsub_shardnumsub,
+        "Count the subscribers of shard channels";
+
+    AUTH: "auth", -2, [NoScript, Loading, Stale, Fast, NoAuth], Keys::None, Connection,
+        parse_auth,
+        "Authenticate the connection";
+    HELLO: "hello", -1, [NoScript, Loading, Stale, Fast, NoAuth], Keys::None, Connection,
+        parse_hello,
+        "Handshake with the server, optionally switching the protocol version";
+    CLIENT_ID: "client|id", 2, [NoScript, Loading, Stale, Fast],
+        Keys::None, Connection, parse_client_id,
+        "Get the id of the connection";
+    CLIENT_TRACKING: "client|tracking", -3, [NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_tracking,
+        "Enable or disable server-assisted client side caching";
+    CLIENT_CACHING: "client|caching", 3, [NoScript, Loading, Stale, Fast],
+        Keys::None, Connection, parse_client_caching,
+        "Track (or don't track) the keys read by the next command";
+    CLIENT_LIST: "client|list", -2, [Admin, NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_list,
+        "List the connected clients";
+    CLIENT_INFO: "client|info", 2, [NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_info,
+        "Get information about the connection";
+    CLIENT_SETNAME: "client|setname", 3, [NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_setname,
+        "Set the name of the connection";
+    CLIENT_GETNAME: "client|getname", 2, [NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_getname,
+        "Get the name of the connection";
+    CLIENT_KILL: "client|kill", -3, [Admin, NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_kill,
+        "Close the connections of clients";
+    CLIENT_PAUSE: "client|pause", -3, [Admin, NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_pause,
+        "Hold the commands of all clients for a while";
+    CLIENT_UNPAUSE: "client|unpause", 2, [Admin, NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_unpause,
+        "Resume the commands of paused clients";
+    CLIENT_REPLY: "client|reply", 3, [NoScript, Loading, Stale],
+        Keys::None, Connection, parse_client_reply,
+        "Change which replies the connection receives";
+    COMMAND_COUNT: "command|count", 2, [NoScript, Loading, Stale], Keys::None, Server,
+        parse_command_count,
+        "Count the commands";
+    COMMAND_INFO: "command|info", -2, [NoScript, Loading, Stale], Keys::None, Server,
+        parse_command_info,
+        "Get information about commands";
+    COMMAND_DOCS: "command|docs", -2, [NoScript, Loading, Stale], Keys::None, Server,
+        parse_command_docs,
+        "Get the documentation of commands";
+    COMMAND_LIST: "command|list", -2, [NoScript, Loading, Stale], Keys::None, Server,
+        parse_command_list,
+        "List the names of commands, optionally filtered";
+    COMMAND_GETKEYS: "command|getkeys", -3, [NoScript, Loading, Stale], Keys::None, Server,
+        parse_command_getkeys,
+        "Extract the keys from a command";
+
+    QUIT: "quit", -1, [NoScript, Loading, Stale, Fast, NoAuth], Keys::None, Connection,
+        parse_quit,
+        "Close the connection";
+
+    INFO: "info", -1, [NoScript, Loading, Stale], Keys::None, Server, parse_info,
+        "Get information and statistics about the server";
+    CONFIG_GET: "config|get", -3, [Admin, NoScript, Loading, Stale],
+        Keys::None, Server, parse_config_get,
+        "Get the values of configuration parameters";
+    CONFIG_SET: "config|set", -4, [Admin, NoScript, Loading, Stale],
+        Keys::None, Server, parse_config_set,
+        "Set configuration parameters";
+    CONFIG_RESETSTAT: "config|resetstat", 2, [Admin, NoScript, Loading, Stale],
+        Keys::None, Server, parse_config_resetstat,
+        "Reset the statistics reported by INFO";
+    CONFIG_REWRITE: "config|rewrite", 2, [Admin, NoScript, Loading, Stale],
+        Keys::None, Server, parse_config_rewrite,
+        "Write the effective configuration to the configuration file";
+
+    FLUSHDB: "flushdb", -1, [Write], Keys::None, Server, parse_flushdb,
+        "Remove all keys from the selected database";
+    FLUSHALL: "flushall", -1, [Write], Keys::None, Server, parse_flushall,
+        "Remove all keys from all databases";
+}
+
+containers! {
+    SCRIPT: "script", [NoScript], Scripting, [SCRIPT_LOAD, SCRIPT_EXISTS, SCRIPT_FLUSH],
+        "Manage the Lua script cache";
+    FUNCTION: "function", [NoScript], Scripting, [
+        FUNCTION_LOAD,
+        FUNCTION_DELETE,
+        FUNCTION_FLUSH,
+        FUNCTION_LIST,
+        FUNCTION_DUMP,
+        FUNCTION_RESTORE,
+    ], "Manage the function libraries";
+    PUBSUB: "pubsub", [PubSub, NoScript, Loading, Stale], PubSub, [
+        PUBSUB_CHANNELS,
+        PUBSUB_NUMSUB,
+        PUBSUB_NUMPAT,
+        PUBSUB_SHARDCHANNELS,
+        PUBSUB_SHARDNUMSUB,
+    ], "Inspect the state of the pub/sub subsystem";
+    CLIENT: "client", [NoScript, Loading, Stale], Connection,
+        [CLIENT_ID, CLIENT_TRACKING, CLIENT_CACHING, CLIENT_LIST, CLIENT_INFO, CLIENT_SETNAME,
+         CLIENT_GETNAME, CLIENT_KILL, CLIENT_PAUSE, CLIENT_UNPAUSE, CLIENT_REPLY],
+        "Manage client connections";
+    COMMAND: "command", [NoScript, Loading, Stale], Server, [
+        COMMAND_COUNT,
+        COMMAND_INFO,
+        COMMAND_DOCS,
+        COMMAND_LIST,
+        COMMAND_GETKEYS,
+    ], "Get information about all commands", parse_command_default;
+    CONFIG: "config", [], Server, [
+        CONFIG_GET,
+        CONFIG_SET,
+        CONFIG_RESETSTAT,
+        CONFIG_REWRITE,
+    ], "Manage the runtime configuration";
+}
+
+/// All commands (subcommands are only listed by their container)
+pub static COMMANDS: &[&CommandSpec] = &[
+    &PING,
+    &GET,
+    &SET,
+    &DEL,
+    &EXISTS,
+    &EXPIRE,
+    &PEXPIRE,
+    &PERSIST,
+    &RENAME,
+    &RENAMENX,
+    &COPY,
+    &MOVE,
+    &SELECT,
+    &SWAPDB,
+    &MULTI,
+    &EXEC,
+    &DISCARD,
+    &WATCH,
+    &UNWATCH,
+    &EVAL,
+    &EVALSHA,
+    &SCRIPT,
+    &FCALL,
+    &FCALL_RO,
+    &FUNCTION,
+    &SUBSCRIBE,
+    &UNSUBSCRIBE,
+    &PSUBSCRIBE,
+    &PUNSUBSCRIBE,
+    &PUBLISH,
+    &SSUBSCRIBE,
+    &SUNSUBSCRIBE,
+    &SPUBLISH,
+    &PUBSUB,
+    &AUTH,
+    &HELLO,
+    &CLIENT,
+    &COMMAND,
+    &QUIT,
+    &INFO,
+    &CONFIG,
+    &FLUSHDB,
+    &FLUSHALL,
+];
+
+fn unknown_subcommand(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let container = lookup(&elems[0]).expect("Container command not in table");
+    Err(DecodeError::UnknownSubcommand(
+        container.name,
+        elems[1].clone(),
+    ))
+}
+
+fn parse_ping(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(match elems {
+        [_] => Command::Ping(None),
+        [_, ref msg] => Command::Ping(Some(msg.clone())),
+        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
+    })
+}
+
+fn parse_get(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Get(elems[1].clone()))
+}
+
+fn parse_set(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let args = &elems[1..];
+    if args.len() > 5 {
+        return Err(DecodeError::UnexpectedNumberOfArguments);
+    }
+
+    let mut expiration: Option<Duration> = None;
+    let mut conditional = Conditional::Always;
+
+    let mut i = 2;
+    while i < args.len() {
+        match &args[i].to_ascii_uppercase()[..] {
+            b"XX" if conditional == Conditional::Always => conditional = Conditional::IfExists,
+            b"NX" if conditional == Conditional::Always => conditional = Conditional::IfNotExists,
+            b"PX" if expiration.is_none() && i + 1 < args.len() => {
+                i += 1;
+                expiration = Some(parse_millis(&args[i])?);
+            }
+            b"EX" if expiration.is_none() && i + 1 < args.len() => {
+                i += 1;
+                expiration = Some(parse_seconds(&args[i])?);
+            }
+            _ => Err(DecodeError::InvalidArgument)?,
+        }
+        i += 1;
+    }
+
+    Ok(Command::Set(
+        args[0].clone(),
+        args[1].clone(),
+        expiration,
+        conditional,
+    ))
+}
+
+fn parse_del(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Del(elems[1..].into()))
+}
+
+fn parse_exists(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Exists(elems[1..].into()))
+}
+
+fn parse_expire(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Expire(elems[1].clone(), parse_seconds(&elems[2])?))
+}
+
+fn parse_pexpire(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Expire(elems[1].clone(), parse_millis(&elems[2])?))
+}
+
+fn parse_persist(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Persist(elems[1].clone()))
+}
+
+fn parse_rename(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Rename(elems[1].clone(), elems[2].clone()))
+}
+
+fn parse_renamenx(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::RenameNX(elems[1].clone(), elems[2].clone()))
+}
+
+fn parse_copy(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let args = &elems[1..];
+    if args.len() > 5 {
+        return Err(DecodeError::UnexpectedNumberOfArguments);
+    }
+
+    let mut target: Option<usize> = None;
+    let mut conditional = Conditional::IfNotExists;
+
+    let mut i = 2;
+    while i < args.len() {
+        match &args[i].to_ascii_uppercase()[..] {
+            b"REPLACE" if conditional == Conditional::IfNotExists => {
+                conditional = Conditional::Always
+            }
+            b"DB" if target.is_none() && i + 1 < args.len() => {
+                i += 1;
+                target = Some(parse_index(&args[i])?);
+            }
+            _ => Err(DecodeError::InvalidArgument)?,
+        }
+        i += 1;
+    }
+
+    Ok(Command::Copy(
+        args[0].clone(),
+        args[1].clone(),
+        target,
+        conditional,
+    ))
+}
+
+fn parse_move(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Move(elems[1].clone(), parse_index(&elems[2])?))
+}
+
+fn parse_select(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Select(parse_index(&elems[1])?))
+}
+
+fn parse_swapdb(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::SwapDB(
+        parse_index(&elems[1])?,
+        parse_index(&elems[2])?,
+    ))
+}
+
+fn parse_multi(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Multi)
+}
+
+fn parse_exec(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Exec)
+}
+
+fn parse_discard(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Discard)
+}
+
+fn parse_watch(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Watch(elems[1..].into()))
+}
+
+fn parse_unwatch(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Unwatch)
+}
+
+fn parse_eval(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let (script, keys, args) = parse_script_call(&elems[1..])?;
+    Ok(Command::Eval(script, keys, args))
+}
+
+fn parse_evalsha(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let (sha, keys, args) = parse_script_call(&elems[1..])?;
+    Ok(Command::EvalSha(sha, keys, args))
+}
+
+fn parse_script_load(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ScriptLoad(elems[2].clone()))
+}
+
+fn parse_script_exists(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ScriptExists(elems[2..].into()))
+}
+
+fn parse_script_flush(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    parse_flush_mode(&elems[2..])?;
+    Ok(Command::ScriptFlush)
+}
+
+fn parse_fcall(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let (function, keys, args) = parse_script_call(&elems[1..])?;
+    Ok(Command::FCall(function, keys, args))
+}
+
+fn parse_fcall_ro(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let (function, keys, args) = parse_script_call(&elems[1..])?;
+    Ok(Command::FCallRO(function, keys, args))
+}
+
+fn parse_function_load(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(match &elems[2..] {
+        [ref code] => Command::FunctionLoad(code.clone(), false),
+        [ref opt, ref code] if opt.eq_ignore_ascii_case(b"REPLACE") => {
+            Command::FunctionLoad(code.clone(), true)
+        }
+        _ => Err(DecodeError::InvalidArgument)?,
+    })
+}
+
+fn parse_function_delete(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::FunctionDelete(elems[2].clone()))
+}
+
+fn parse_function_flush(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    parse_flush_mode(&elems[2..])?;
+    Ok(Command::FunctionFlush)
+}
+
+fn parse_function_list(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let mut pattern: Option<Bytes> = None;
+    let mut with_code = false;
+
+    let args = &elems[2..];
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i].to_ascii_uppercase()[..] {
+            b"WITHCODE" if !with_code => with_code = true,
+            b"LIBRARYNAME" if pattern.is_none() && i + 1 < args.len() => {
+                i += 1;
+                pattern = Some(args[i].clone());
+            }
+            _ => Err(DecodeError::InvalidArgument)?,
+        }
+        i += 1;
+    }
+
+    Ok(Command::FunctionList(pattern, with_code))
+}
+
+fn parse_function_dump(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::FunctionDump)
+}
+
+fn parse_function_restore(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(match &elems[2..] {
+        [ref payload] => Command::FunctionRestore(payload.clone(), RestorePolicy::Append),
+        [ref payload, ref policy] => Command::FunctionRestore(
+            payload.clone(),
+            match &policy.to_ascii_uppercase()[..] {
+                b"APPEND" => RestorePolicy::Append,
+                b"REPLACE" => RestorePolicy::Replace,
+                b"FLUSH" => RestorePolicy::Flush,
+                _ => Err(DecodeError::InvalidArgument)?,
+            },
+        ),
+        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
+    })
+}
+
+fn parse_subscribe(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Subscribe(elems[1..].into()))
+}
+
+fn parse_unsubscribe(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Unsubscribe(elems[1..].into()))
+}
+
+fn parse_psubscribe(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::PSubscribe(elems[1..].into()))
+}
+
+fn parse_punsubscribe(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::PUnsubscribe(elems[1..].into()))
+}
+
+fn parse_publish(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Publish(elems[1].clone(), elems[2].clone()))
+}
+
+fn parse_ssubscribe(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::SSubscribe(elems[1..].into()))
+}
+
+fn parse_sunsubscribe(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::SUnsubscribe(elems[1..].into()))
+}
+
+fn parse_spublish(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::SPublish(elems[1].clone(), elems[2].clone()))
+}
+
+fn parse_pubsub_channels(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(match &elems[2..] {
+        [] => Command::PubSubChannels(None),
+        [ref pattern] => Command::PubSubChannels(Some(pattern.clone())),
+        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
+    })
+}
+
+fn parse_pubsub_numsub(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::PubSubNumSub(elems[2..].into()))
+}
+
+fn parse_pubsub_numpat(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::PubSubNumPat)
+}
+
+fn parse_pubsub_shardchannels(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(match &elems[2..] {
+        [] => Command::PubSubShardChannels(None),
+        [ref pattern] => Command::PubSubShardChannels(Some(pattern.clone())),
+        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
+    })
+}
+
+fn parse_pubsub_shardnumsub(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::PubSubShardNumSub(elems[2..].into()))
+}
+
+fn parse_auth(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(match elems {
+        [_, ref password] => Command::Auth(None, password.clone()),
+        [_, ref username, ref password] => Command::Auth(Some(username.clone()), password.clone()),
+        _ => Err(DecodeError::UnexpectedNumberOfArguments)?,
+    })
+}
+
+fn parse_hello(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let mut version = None;
+    let mut auth = None;
+    let mut name = None;
+
+    if elems.len() > 1 {
+        version = Some(std::str::from_utf8(&elems[1])?.parse()?);
+    }
+
+    let args = &elems[elems.len().min(2)..];
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i].to_ascii_uppercase()[..] {
+            b"AUTH" if auth.is_none() && i + 2 < args.len() => {
+                auth = Some((args[i + 1].clone(), args[i + 2].clone()));
+                i += 2;
+            }
+            b"SETNAME" if name.is_none() && i + 1 < args.len() => {
+                name = Some(args[i + 1].clone());
+                i += 1;
+            }
+            _ => Err(DecodeError::InvalidArgument)?,
+        }
+        i += 1;
+    }
+
+    Ok(Command::Hello(version, auth, name))
+}
+
+fn parse_client_id(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientId)
+}
+
+fn parse_client_tracking(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let enabled = match &elems[2].to_ascii_uppercase()[..] {
+        b"ON" => true,
+        b"OFF" => false,
+        _ => Err(DecodeError::InvalidArgument)?,
+    };
+    let mut options = TrackingOptions::default();
+
+    let args = &elems[3..];
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i].to_ascii_uppercase()[..] {
+            b"REDIRECT" if i + 1 < args.len() => {
+                i += 1;
+                options.redirect = Some(std::str::from_utf8(&args[i])?.parse()?);
+            }
+            b"PREFIX" if i + 1 < args.len() => {
+                i += 1;
+                options.prefixes.push(args[i].clone());
+            }
+            b"BCAST" => options.broadcast = true,
+            b"OPTIN" => options.opt_in = true,
+            b"OPTOUT" => options.opt_out = true,
+            _ => Err(DecodeError::InvalidArgument)?,
+        }
+        i += 1;
+    }
+
+    Ok(Command::ClientTracking(enabled, options))
+}
+
+fn parse_client_caching(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientCaching(
+        match &elems[2].to_ascii_uppercase()[..] {
+            b"YES" => true,
+            b"NO" => false,
+            _ => Err(DecodeError::InvalidArgument)?,
+        },
+    ))
+}
+
+fn parse_client_list(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let args = &elems[2..];
+    if args.is_empty() {
+        return Ok(Command::ClientList(Vec::new()));
+    }
+    if args.len() < 2 || !args[0].eq_ignore_ascii_case(b"ID") {
+        Err(DecodeError::InvalidArgument)?;
+    }
+    let ids = args[1..]
+        .iter()
+        .map(|id| Ok(std::str::from_utf8(id)?.parse()?))
+        .collect::<Result<_, DecodeError>>()?;
+    Ok(Command::ClientList(ids))
+}
+
+fn parse_client_info(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientInfo)
+}
+
+fn parse_client_setname(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientSetName(elems[2].clone()))
+}
+
+fn parse_client_getname(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientGetName)
+}
+
+fn parse_client_kill(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let args = &elems[2..];
+    // The old form only takes an address, and may kill the calling client
+    if args.len() == 1 {
+        return Ok(Command::ClientKill(KillFilter {
+            addr: Some(args[0].clone()),
+            skip_me: false,
+            legacy: true,
+            ..Default::default()
+        }));
+    }
+    if args.len() % 2 != 0 {
+        Err(DecodeError::InvalidArgument)?;
+    }
+
+    let mut filter = KillFilter::default();
+    for pair in args.chunks(2) {
+        let value = &pair[1];
+        match &pair[0].to_ascii_uppercase()[..] {
+            b"ID" => filter.id = Some(std::str::from_utf8(value)?.parse()?),
+            b"ADDR" => filter.addr = Some(value.clone()),
+            b"LADDR" => filter.laddr = Some(value.clone()),
+            b"USER" => filter.user = Some(value.clone()),
+            b"SKIPME" => {
+                filter.skip_me = match &value.to_ascii_uppercase()[..] {
+                    b"YES" => true,
+                    b"NO" => false,
+                    _ => Err(DecodeError::InvalidArgument)?,
+                }
+            }
+            _ => Err(DecodeError::InvalidArgument)?,
+        }
+    }
+    Ok(Command::ClientKill(filter))
+}
+
+fn parse_client_pause(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    let timeout = Duration::from_millis(std::str::from_utf8(&elems[2])?.parse()?);
+    let mode = match &elems[3..] {
+        [] => PauseMode::All,
+        [mode] if mode.eq_ignore_ascii_case(b"ALL") => PauseMode::All,
+        [mode] if mode.eq_ignore_ascii_case(b"WRITE") => PauseMode::Write,
+        _ => Err(DecodeError::InvalidArgument)?,
+    };
+    Ok(Command::ClientPause(timeout, mode))
+}
+
+fn parse_client_unpause(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientUnpause)
+}
+
+fn parse_client_reply(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ClientReply(
+        match &elems[2].to_ascii_uppercase()[..] {
+            b"ON" => ReplyMode::On,
+            b"OFF" => ReplyMode::Off,
+            b"SKIP" => ReplyMode::Skip,
+            _ => Err(DecodeError::InvalidArgument)?,
+        },
+    ))
+}
+
+fn parse_command_default(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    match elems {
+        [_] => Ok(Command::CommandInfo(Vec::new())),
+        _ => unknown_subcommand(elems),
+    }
+}
+
+fn parse_command_count(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::CommandCount)
+}
+
+fn parse_command_info(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::CommandInfo(elems[2..].into()))
+}
+
+fn parse_command_docs(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::CommandDocs(elems[2..].into()))
+}
+
+fn parse_command_list(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::CommandList(match &elems[2..] {
+        [] => None,
+        [ref filterby, ref kind, ref value] if filterby.eq_ignore_ascii_case(b"FILTERBY") => {
+            Some(match &kind.to_ascii_uppercase()[..] {
+                b"MODULE" => CommandFilter::Module(value.clone()),
+                b"ACLCAT" => CommandFilter::AclCategory(value.clone()),
+                b"PATTERN" => CommandFilter::Pattern(value.clone()),
+                _ => Err(DecodeError::InvalidArgument)?,
+            })
+        }
+        _ => Err(DecodeError::InvalidArgument)?,
+    }))
+}
+
+fn parse_command_getkeys(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::CommandGetKeys(elems[2..].into()))
+}
+
+fn parse_quit(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Quit)
+}
+
+fn parse_info(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::Info(elems[1..].into()))
+}
+
+fn parse_config_get(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ConfigGet(elems[2..].into()))
+}
+
+fn parse_config_set(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    if elems.len() % 2 != 0 {
+        return Err(DecodeError::WrongArity(CONFIG_SET.name));
+    }
+    Ok(Command::ConfigSet(
+        elems[2..]
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect(),
+    ))
+}
+
+fn parse_config_resetstat(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ConfigResetStat)
+}
+
+fn parse_config_rewrite(_elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::ConfigRewrite)
+}
+
+fn parse_flushdb(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::FlushDB(parse_synchronicity(&elems[1..])?))
+}
+
+fn parse_flushall(elems: &[Bytes]) -> Result<Command, DecodeError> {
+    Ok(Command::FlushAll(parse_synchronicity(&elems[1..])?))
+}
+
+fn parse_script_call(args: &[Bytes]) -> Result<(Bytes, Vec<Bytes>, Vec<Bytes>), DecodeError> {
+    let numkeys: usize = std::str::from_utf8(&args[1])?.parse()?;
+    if numkeys > args.len() - 2 {
+        return Err(DecodeError::InvalidArgument);
+    }
+
+    let (keys, rest) = args[2..].split_at(numkeys);
+    Ok((args[0].clone(), keys.into(), rest.into()))
+}
+
+/// Parse the optional `ASYNC` argument of `FLUSHDB` and `FLUSHALL`
+fn parse_synchronicity(args: &[Bytes]) -> Result<Synchronicity, DecodeError> {
+    match args {
+        [] => Ok(Synchronicity::Sync),
+        [ref opt] if opt.eq_ignore_ascii_case(b"ASYNC") => Ok(Synchronicity::Async),
+        _ => Err(DecodeError::UnexpectedNumberOfArguments),
+    }
+}
+
+/// Parse the optional (and ignored) `ASYNC` or `SYNC` argument of `SCRIPT FLUSH` and
+/// `FUNCTION FLUSH`
+fn parse_flush_mode(args: &[Bytes]) -> Result<(), DecodeError> {
+    match args {
+        [] => Ok(()),
+        [ref opt] if opt.eq_ignore_ascii_case(b"ASYNC") || opt.eq_ignore_ascii_case(b"SYNC") => {
+            Ok(())
+        }
+        _ => Err(DecodeError::InvalidArgument),
+    }
+}
+
+fn parse_millis(data: &[u8]) -> Result<Duration, DecodeError> {
+    let decoded = std::str::from_utf8(data)?;
+    let value: i64 = decoded.parse()?;
+
+    Ok(Duration::from_millis(if value >= 0 {
+        value as u64
+    } else {
+        0
+    }))
+}
+fn parse_seconds(data: &[u8]) -> Result<Duration, DecodeError> {
+    let decoded = std::str::from_utf8(data)?;
+    let value: i64 = decoded.parse()?;
+
+    Ok(Duration::from_secs(if value >= 0 {
+        value as u64
+    } else {
+        0
+    }))
+}
+fn parse_index(data: &[u8]) -> Result<usize, DecodeError> {
+    let decoded = std::str::from_utf8(data)?;
+    let value: i64 = decoded.parse()?;
+
+    // Negative indices are never valid, so map them to one that fails the range check
+    Ok(if value >= 0 {
+        value as usize
+    } else {
+        usize::max_value()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&'static str]) -> Vec<Bytes> {
+        args.iter().map(|&arg| Bytes::from(arg)).collect()
+    }
+
+    #[test]
+    fn commands_are_looked_up_ignoring_case() {
+        assert!(match parse(&args(&["Get", "key"])) {
+            Ok(Command::Get(ref key)) => key == "key",
+            _ => false,
+        });
+        assert!(match parse(&args(&["sCrIpT", "FlUsH"])) {
+            Ok(Command::ScriptFlush) => true,
+            _ => false,
+        });
+        assert!(match parse(&args(&["set", "key", "value", "nx"])) {
+            Ok(Command::Set(_, _, None, Conditional::IfNotExists)) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn arity_is_checked() {
+        assert!(match parse(&args(&["get"])) {
+            Err(DecodeError::WrongArity("get")) => true,
+            _ => false,
+        });
+        assert!(match parse(&args(&["get", "a", "b"])) {
+            Err(DecodeError::WrongArity("get")) => true,
+            _ => false,
+        });
+        assert!(match parse(&args(&["script"])) {
+            Err(DecodeError::WrongArity("script")) => true,
+            _ => false,
+        });
+        assert!(match parse(&args(&["script", "load"])) {
+            Err(DecodeError::WrongArity("script|load")) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn unknown_subcommands_are_rejected() {
+        assert!(match parse(&args(&["script", "kill"])) {
+            Err(DecodeError::UnknownSubcommand("script", ref subcommand)) => subcommand == "kill",
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn keys_are_extracted() {
+        assert_eq!(
+            get_keys(&args(&["del", "a", "b", "c"])).unwrap(),
+            args(&["a", "b", "c"])
+        );
+        assert_eq!(
+            get_keys(&args(&["rename", "a", "b"])).unwrap(),
+            args(&["a", "b"])
+        );
+        assert_eq!(
+            get_keys(&args(&["EVAL", "script", "2", "a", "b", "arg"])).unwrap(),
+            args(&["a", "b"])
+        );
+        assert!(get_keys(&args(&["eval", "script", "3", "a", "b"])).is_err());
+        assert!(get_keys(&args(&["ping"])).is_err());
+        assert!(get_keys(&args(&["get"])).is_err());
+    }
+
+    #[test]
+    fn subcommands_are_found_by_full_name() {
+        assert_eq!(find(b"SCRIPT|load").unwrap().name, "script|load");
+        assert_eq!(find(b"script").unwrap().subcommands.len(), 3);
+        assert!(find(b"script|kill").is_none());
+    }
+
+    #[test]
+    fn parsed_commands_have_their_flags() {
+        assert!(parse(&args(&["set", "key", "value"])).unwrap().writes());
+        assert!(parse(&args(&["publish", "channel", "message"]))
+            .unwrap()
+            .writes());
+        assert!(parse(&args(&["exists", "key"])).unwrap().reads());
+        assert!(!parse(&args(&["multi"])).unwrap().allowed_in_scripts());
+    }
+
+    #[test]
+    fn client_kill_filters_are_parsed() {
+        assert!(match parse(&args(&["client", "kill", "127.0.0.1:1234"])) {
+            Ok(Command::ClientKill(KillFilter {
+                addr: Some(ref addr),
+                skip_me: false,
+                legacy: true,
+                ..
+            })) => addr == "127.0.0.1:1234",
+            _ => false,
+        });
+        assert!(
+            match parse(&args(&["client", "kill", "ID", "4", "skipme", "no"])) {
+                Ok(Command::ClientKill(KillFilter {
+                    id: Some(4),
+                    skip_me: false,
+                    legacy: false,
+                    ..
+                })) => true,
+                _ => false,
+            }
+        );
+        assert!(parse(&args(&["client", "kill", "id", "4", "skipme"])).is_err());
+        assert!(parse(&args(&["client", "kill", "name", "x"])).is_err());
+    }
+
+    #[test]
+    fn auth_takes_an_optional_username() {
+        assert!(match parse(&args(&["auth", "secret"])) {
+            Ok(Command::Auth(None, ref password)) => password == "secret",
+            _ => false,
+        });
+        assert!(match parse(&args(&["auth", "default", "secret"])) {
+            Ok(Command::Auth(Some(ref username), _)) => username == "default",
+            _ => false,
+        });
+        assert!(parse(&args(&["auth", "a", "b", "c"])).is_err());
+        assert!(parse(&args(&["quit"])).unwrap().allowed_without_auth());
+        assert!(!parse(&args(&["get", "key"]))
+            .unwrap()
+            .allowed_without_auth());
+    }
+
+    #[test]
+    fn transaction_and_subscriber_restrictions() {
+        let allowed = |command: &[&'static str]| {
+            let command = parse(&args(command)).unwrap();
+            (
+                command.allowed_in_transactions(),
+                command.allowed_in_subscriber_mode(),
+            )
+        };
+        assert_eq!(allowed(&["get", "key"]), (true, false));
+        assert_eq!(allowed(&["publish", "channel", "message"]), (true, false));
+        assert_eq!(allowed(&["subscribe", "channel"]), (false, true));
+        assert_eq!(allowed(&["sunsubscribe"]), (false, true));
+        assert_eq!(allowed(&["ping"]), (true, true));
+        assert_eq!(allowed(&["config", "get", "timeout"]), (true, false));
+        assert_eq!(allowed(&["client", "setname", "name"]), (true, false));
+        assert_eq!(allowed(&["client", "tracking", "on"]), (false, false));
+        assert_eq!(allowed(&["watch", "key"]), (false, false));
+    }
+}
@@ -0,0 +1,338 @@
+//! Publish/subscribe messaging between connections
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use slog::{slog_debug, slog_info};
+use slog_scope::{debug, info};
+
+use bytes::Bytes;
+use uuid::Uuid;
+
+use actix::prelude::*;
+use actix_derive::Message;
+
+use crate::protocol::{Command, Response};
+use crate::utils::glob;
+use crate::utils::hash_slot::key_slot;
+
+/// What a subscription is matched against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubscriptionKind {
+    /// An exact channel name
+    Channel,
+    /// A glob-style channel pattern
+    Pattern,
+    /// An exact sharded channel name (assigned to a hash slot like a key)
+    Shard,
+}
+impl SubscriptionKind {
+    /// The kind of the reply confirming a subscription
+    pub fn subscribe_reply(self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "subscribe",
+            SubscriptionKind::Pattern => "psubscribe",
+            SubscriptionKind::Shard => "ssubscribe",
+        }
+    }
+
+    /// The kind of the reply confirming an unsubscription
+    pub fn unsubscribe_reply(self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "unsubscribe",
+            SubscriptionKind::Pattern => "punsubscribe",
+            SubscriptionKind::Shard => "sunsubscribe",
+        }
+    }
+}
+
+/// A message pushed to a subscribed connection (sent as a push frame to RESP3 clients)
+#[derive(Debug, Message)]
+pub struct Push(pub Response);
+
+/// The subscribers of a single channel or pattern
+type Subscribers = HashMap<Uuid, Recipient<Push>>;
+
+/// All channel, pattern and sharded channel subscriptions
+///
+/// The subscriptions are managed by the `Broker`, and shared with the `Writer` so that it can
+/// publish messages from transactions and scripts. Connections only confirm a subscription once
+/// the `Broker` has added it, so both see it from then on. Sharded channels live in a separate namespace,
+/// grouped by hash slot.
+#[derive(Default)]
+pub struct Subscriptions {
+    channels: HashMap<Bytes, Subscribers>,
+    patterns: HashMap<Bytes, Subscribers>,
+    shard_channels: HashMap<u16, HashMap<Bytes, Subscribers>>,
+}
+impl Subscriptions {
+    fn targets(
+        &mut self,
+        kind: SubscriptionKind,
+        target: &Bytes,
+    ) -> &mut HashMap<Bytes, Subscribers> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::Shard => self
+                .shard_channels
+                .entry(key_slot(target))
+                .or_insert_with(HashMap::new),
+        }
+    }
+
+    fn shard_subscribers(&self, channel: &Bytes) -> Option<&Subscribers> {
+        self.shard_channels
+            .get(&key_slot(channel))
+            .and_then(|channels| channels.get(channel))
+    }
+
+    fn subscribe(
+        &mut self,
+        kind: SubscriptionKind,
+        client: Uuid,
+        recipient: &Recipient<Push>,
+        target: Bytes,
+    ) {
+        self.targets(kind, &target)
+            .entry(target)
+            .or_insert_with(HashMap::new)
+            .insert(client, recipient.clone());
+    }
+
+    fn unsubscribe(&mut self, kind: SubscriptionKind, client: Uuid, target: &Bytes) {
+        let targets = self.targets(kind, target);
+        let empty = match targets.get_mut(target) {
+            Some(subscribers) => {
+                subscribers.remove(&client);
+                subscribers.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            targets.remove(target);
+        }
+
+        if kind == SubscriptionKind::Shard {
+            let slot = key_slot(target);
+            if self.shard_channels.get(&slot).map(|x| x.is_empty()) == Some(true) {
+                self.shard_channels.remove(&slot);
+            }
+        }
+    }
+
+    /// The number of channels with subscribers, subscribed patterns and shard channels with
+    /// subscribers
+    pub fn counts(&self) -> (usize, usize, usize) {
+        (
+            self.channels.len(),
+            self.patterns.len(),
+            self.shard_channels.values().map(HashMap::len).sum(),
+        )
+    }
+
+    /// Push a message to all subscribers of a channel, returning the number of receivers
+    pub fn publish(&self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut receivers = 0;
+
+        if let Some(subscribers) = self.channels.get(channel) {
+            for recipient in subscribers.values() {
+                let push = Push(Response::Push(vec![
+                    Response::Bulk(Bytes::from_static(b"message")),
+                    Response::Bulk(channel.clone()),
+                    Response::Bulk(message.clone()),
+                ]));
+                if recipient.do_send(push).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        for (pattern, subscribers) in self.patterns.iter() {
+            if !glob::matches(pattern, channel) {
+                continue;
+            }
+            for recipient in subscribers.values() {
+                let push = Push(Response::Push(vec![
+                    Response::Bulk(Bytes::from_static(b"pmessage")),
+                    Response::Bulk(pattern.clone()),
+                    Response::Bulk(channel.clone()),
+                    Response::Bulk(message.clone()),
+                ]));
+                if recipient.do_send(push).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        debug!(
+            "Published message on {:?} to {} receivers",
+            channel, receivers
+        );
+        receivers
+    }
+
+    /// Push a message to all subscribers of a sharded channel, returning the number of receivers
+    pub fn publish_sharded(&self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut receivers = 0;
+
+        if let Some(subscribers) = self.shard_subscribers(channel) {
+            for recipient in subscribers.values() {
+                let push = Push(Response::Push(vec![
+                    Response::Bulk(Bytes::from_static(b"smessage")),
+                    Response::Bulk(channel.clone()),
+                    Response::Bulk(message.clone()),
+                ]));
+                if recipient.do_send(push).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        debug!(
+            "Published sharded message on {:?} to {} receivers",
+            channel, receivers
+        );
+        receivers
+    }
+
+    /// Execute a `(S)PUBLISH` or `PUBSUB` command
+    pub fn execute(&self, command: Command) -> Response {
+        match command {
+            Command::Publish(channel, message) => {
+                Response::Integer(self.publish(&channel, &message) as i64)
+            }
+            Command::PubSubChannels(pattern) => Response::Array(
+                self.channels
+                    .keys()
+                    .filter(|channel| {
+                        pattern
+                            .as_ref()
+                            .map(|pattern| glob::matches(pattern, channel))
+                            .unwrap_or(true)
+                    })
+                    .cloned()
+                    .map(Response::Bulk)
+                    .collect(),
+            ),
+            Command::PubSubNumSub(channels) => Response::Array(
+                channels
+                    .into_iter()
+                    .flat_map(|channel| {
+                        let count = self.channels.get(&channel).map(|x| x.len()).unwrap_or(0);
+                        vec![Response::Bulk(channel), Response::Integer(count as i64)]
+                    })
+                    .collect(),
+            ),
+            Command::PubSubNumPat => Response::Integer(self.patterns.len() as i64),
+            Command::SPublish(channel, message) => {
+                Response::Integer(self.publish_sharded(&channel, &message) as i64)
+            }
+            Command::PubSubShardChannels(pattern) => Response::Array(
+                self.shard_channels
+                    .values()
+                    .flat_map(|channels| channels.keys())
+                    .filter(|channel| {
+                        pattern
+                            .as_ref()
+                            .map(|pattern| glob::matches(pattern, channel))
+                            .unwrap_or(true)
+                    })
+                    .cloned()
+                    .map(Response::Bulk)
+                    .collect(),
+            ),
+            Command::PubSubShardNumSub(channels) => Response::Array(
+                channels
+                    .into_iter()
+                    .flat_map(|channel| {
+                        let count = self
+                            .shard_subscribers(&channel)
+                            .map(|x| x.len())
+                            .unwrap_or(0);
+                        vec![Response::Bulk(channel), Response::Integer(count as i64)]
+                    })
+                    .collect(),
+            ),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+/// An actor that tracks the subscriptions of all connections
+#[derive(Default)]
+pub struct Broker {
+    subscriptions: Arc<RwLock<Subscriptions>>,
+}
+impl Broker {
+    /// A handle to the subscriptions, for publishing messages from outside the broker
+    pub fn subscriptions(&self) -> Arc<RwLock<Subscriptions>> {
+        self.subscriptions.clone()
+    }
+}
+impl Actor for Broker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Spawned pub/sub broker");
+    }
+}
+impl Supervised for Broker {}
+impl SystemService for Broker {}
+
+/// A request to push messages on the given channels (or matching the given patterns) to a client
+#[derive(Message)]
+pub struct Subscribe {
+    pub client: Uuid,
+    pub recipient: Recipient<Push>,
+    pub kind: SubscriptionKind,
+    pub targets: Vec<Bytes>,
+}
+
+/// A request to stop pushing messages on the given channels (or patterns) to a client
+#[derive(Debug, Message)]
+pub struct Unsubscribe {
+    pub client: Uuid,
+    pub kind: SubscriptionKind,
+    pub targets: Vec<Bytes>,
+}
+
+/// A `(S)PUBLISH` or `PUBSUB` command
+#[derive(Debug, Message)]
+#[rtype(result = "Response")]
+pub struct Query(pub Command);
+
+impl Handler<Subscribe> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, subscribe: Subscribe, _ctx: &mut Context<Self>) {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        for target in subscribe.targets {
+            subscriptions.subscribe(
+                subscribe.kind,
+                subscribe.client,
+                &subscribe.recipient,
+                target,
+            );
+        }
+    }
+}
+
+impl Handler<Unsubscribe> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, unsubscribe: Unsubscribe, _ctx: &mut Context<Self>) {
+        let mut subscriptions = self.subscriptions.write().unwrap();
+        for target in unsubscribe.targets.iter() {
+            subscriptions.unsubscribe(unsubscribe.kind, unsubscribe.client, target);
+        }
+    }
+}
+
+impl Handler<Query> for Broker {
+    type Result = MessageResult<Query>;
+
+    fn handle(&mut self, Query(command): Query, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.subscriptions.read().unwrap().execute(command))
+    }
+}
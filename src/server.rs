@@ -1,29 +1,74 @@
 //! The evredis server and its configuration
 
+use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use slog::{slog_error, slog_info};
 use slog_scope::{error, info};
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+
+use app_dirs::{get_data_root, AppDataType};
 
 use actix::prelude::*;
-use actix_net::server::Server;
+use actix_net::server::{Server, StopServer};
 use actix_net::service::IntoNewService;
-use futures::{Future, IntoFuture};
+use futures::{future, Future};
 
-use crate::codecs::resp2;
+use crate::codecs::{Limits, NegotiatedStreamCodec};
+use crate::pubsub::Broker;
+use crate::server::config::{LiveConfiguration, Settings};
+use crate::server::connection::{Accepted, ConnectionError};
+use crate::stats::Stats;
 use crate::storage::reader::Reader;
 use crate::storage::writer::Writer;
+use crate::storage::{scripting, DEFAULT_DATABASES};
+use crate::utils::logging::LoggingConfiguration;
 
+pub mod clients;
+pub mod config;
 pub mod connection;
+pub mod info;
+pub mod reload;
 
 /// Configuration for an evredis server
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
 pub struct ServerConfiguration {
     /// The interfaces to listen on
     pub listen_on: Vec<SocketAddr>,
+    /// The number of logical databases
+    pub databases: usize,
+    /// The maximum execution time of Lua scripts (in milliseconds)
+    pub lua_time_limit: u64,
+    /// The file function libraries are persisted to (if any)
+    pub functions_file: Option<PathBuf>,
+    /// The classes of keyspace events to publish (see `notify-keyspace-events` in Redis)
+    pub notify_keyspace_events: String,
+    /// The maximum length of a bulk string in a command
+    pub proto_max_bulk_len: usize,
+    /// The maximum number of arguments of a command
+    pub proto_max_multibulk_len: usize,
+    /// The maximum number of bytes buffered for a client's incomplete commands
+    pub client_query_buffer_limit: usize,
+    /// The memory limit reported by `INFO` (in bytes, or 0 for no limit)
+    pub maxmemory: u64,
+    /// The number of seconds after which idle connections are closed (or 0 to keep them open)
+    ///
+    /// Subscribers and clients held by `CLIENT PAUSE` are never closed for being idle.
+    pub timeout: u64,
+    /// The interval of TCP keepalive probes (in seconds, or 0 to disable them)
+    pub tcp_keepalive: u64,
+    /// The maximum number of connected clients
+    pub maxclients: usize,
+    /// The password clients need to authenticate with (if any)
+    pub requirepass: Option<String>,
+    /// The limits on the responses queued for each class of clients
+    pub client_output_buffer_limit: OutputBufferLimits,
 }
 impl Default for ServerConfiguration {
     fn default() -> Self {
@@ -32,6 +77,64 @@ impl Default for ServerConfiguration {
                 .to_socket_addrs()
                 .expect("Invalid default address")
                 .collect(),
+            databases: DEFAULT_DATABASES,
+            lua_time_limit: scripting::DEFAULT_TIME_LIMIT.as_millis() as u64,
+            functions_file: get_data_root(AppDataType::UserData).ok().map(|mut root| {
+                root.push("evredis");
+                root.push("functions.dump");
+                root
+            }),
+            notify_keyspace_events: String::new(),
+            proto_max_bulk_len: Limits::default().max_bulk_len,
+            proto_max_multibulk_len: Limits::default().max_multibulk_len,
+            client_query_buffer_limit: Limits::default().max_query_buffer,
+            maxmemory: 0,
+            timeout: 0,
+            tcp_keepalive: 300,
+            maxclients: 10000,
+            requirepass: None,
+            client_output_buffer_limit: OutputBufferLimits::default(),
+        }
+    }
+}
+
+/// Limits on the bytes of responses queued for a client (0 disables a limit)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct OutputBufferLimit {
+    /// The client is disconnected as soon as this many bytes are queued
+    pub hard_limit: u64,
+    /// The client is disconnected if this many bytes stay queued for `soft_seconds`
+    pub soft_limit: u64,
+    /// The number of seconds the soft limit may be exceeded
+    pub soft_seconds: u64,
+}
+
+/// Output buffer limits for each class of clients (see `client-output-buffer-limit` in Redis)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct OutputBufferLimits {
+    /// Clients that aren't subscribed to any channels
+    pub normal: OutputBufferLimit,
+    /// Replicas (accepted for compatibility, evredis doesn't support replication)
+    pub replica: OutputBufferLimit,
+    /// Clients subscribed to channels or patterns
+    pub pubsub: OutputBufferLimit,
+}
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        OutputBufferLimits {
+            normal: OutputBufferLimit::default(),
+            replica: OutputBufferLimit {
+                hard_limit: 256 * 1024 * 1024,
+                soft_limit: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard_limit: 32 * 1024 * 1024,
+                soft_limit: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
         }
     }
 }
@@ -39,30 +142,161 @@ impl ServerConfiguration {
     /// Spawn a server actor
     ///
     /// This may fail if the server cannot bind on the configured interfaces
-    pub fn start_server(&self) -> io::Result<Addr<Server>> {
-        start(&self.listen_on[..])
+    pub fn start_server(&self) -> io::Result<RunningServer> {
+        start(LiveConfiguration::new(self.clone()))
+    }
+
+    /// The protocol limits of new connections
+    fn limits(&self) -> Limits {
+        Limits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_multibulk_len: self.proto_max_multibulk_len,
+            max_query_buffer: self.client_query_buffer_limit,
+        }
+    }
+}
+
+/// Spawn the server actors (and their storage) for the given configuration
+pub fn start(live: LiveConfiguration) -> io::Result<RunningServer> {
+    let config = live.server().clone();
+    let keyspace_events = config
+        .notify_keyspace_events
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let stats = Arc::new(Stats::default());
+    let broker = Broker::default();
+    let mut writer = Writer::new(config.databases)
+        .with_script_time_limit(Duration::from_millis(config.lua_time_limit))
+        .with_subscriptions(broker.subscriptions())
+        .with_keyspace_events(keyspace_events)
+        .with_stats(stats.clone());
+    if let Some(ref path) = config.functions_file {
+        writer = writer.with_functions_file(path.clone());
     }
+    System::current().registry().set(writer.start());
+    System::current().registry().set(broker.start());
+
+    let mut server = RunningServer {
+        config: Arc::new(live),
+        stats,
+        listeners: HashMap::new(),
+    };
+    for address in config.listen_on {
+        let listener = server.listen(address)?;
+        server.listeners.insert(address, listener);
+    }
+    Ok(server)
 }
 
-/// Spawn a server actor on the given interfaces
-pub fn start(addr: impl ToSocketAddrs) -> io::Result<Addr<Server>> {
-    Ok(Server::default()
-        .bind("evredis", addr, move || {
-            info!("Spawning new worker");
-            let codec = resp2::StreamCodec::default();
-
-            (move |stream: tokio_tcp::TcpStream| {
-                info!("Accepting new connection");
-                stream.set_nodelay(true).unwrap();
-
-                let reader = Reader::from_registry();
-                let writer = Writer::from_registry();
-
-                connection::accept(stream, codec.clone(), reader, writer)
-                    .into_future()
-                    .map_err(|err| error!("Connection error: {}", err))
-            })
-            .into_new_service()
-        })?
-        .start())
+/// The actors of a running server
+pub struct RunningServer {
+    config: Arc<LiveConfiguration>,
+    stats: Arc<Stats>,
+    /// The actors accepting connections (by listening address)
+    listeners: HashMap<SocketAddr, Addr<Server>>,
+}
+impl RunningServer {
+    /// The configuration shared by all connections
+    pub fn config(&self) -> &LiveConfiguration {
+        &self.config
+    }
+
+    /// Apply a reloaded configuration
+    ///
+    /// Listeners are started and stopped to match `listen_on` (closing the connections accepted
+    /// by stopped listeners). Returns the names of the changed settings that need a restart.
+    pub fn reload(
+        &mut self,
+        server: ServerConfiguration,
+        logging: LoggingConfiguration,
+    ) -> Result<Vec<&'static str>, String> {
+        let mut settings = Settings { server, logging };
+        settings.validate()?;
+        let requested = settings.server.listen_on.clone();
+
+        let stopped: Vec<_> = self
+            .listeners
+            .keys()
+            .filter(|address| !requested.contains(address))
+            .cloned()
+            .collect();
+        for address in stopped {
+            info!("Stopped listening on {}", address);
+            self.listeners[&address].do_send(StopServer { graceful: true });
+            self.listeners.remove(&address);
+        }
+        for address in requested {
+            if self.listeners.contains_key(&address) {
+                continue;
+            }
+            match self.listen(address) {
+                Ok(listener) => {
+                    info!("Listening on {}", address);
+                    self.listeners.insert(address, listener);
+                }
+                Err(err) => error!("Failed to listen on {}: {}", address, err),
+            }
+        }
+
+        // Only report the addresses that are actually listened on
+        settings
+            .server
+            .listen_on
+            .retain(|address| self.listeners.contains_key(address));
+        self.config.reload(settings)
+    }
+
+    /// Spawn a server actor accepting connections on the given address
+    fn listen(&self, address: SocketAddr) -> io::Result<Addr<Server>> {
+        let config = self.config.clone();
+        let stats = self.stats.clone();
+
+        Ok(Server::default()
+            .bind("evredis", address, move || {
+                info!("Spawning new worker");
+                let config = config.clone();
+                let stats = stats.clone();
+
+                (move |stream: tokio_tcp::TcpStream| {
+                    info!("Accepting new connection");
+                    stream.set_nodelay(true).unwrap();
+                    let keepalive = config.server().tcp_keepalive;
+                    let keepalive = if keepalive > 0 {
+                        Some(Duration::from_secs(keepalive))
+                    } else {
+                        None
+                    };
+                    if let Err(err) = stream.set_keepalive(keepalive) {
+                        error!("Failed to set TCP keepalive: {}", err);
+                    }
+
+                    let reader = Reader::from_registry();
+                    let writer = Writer::from_registry();
+                    let limits = config.server().limits();
+                    let codec = NegotiatedStreamCodec::default().with_limits(limits);
+                    let accepted = stream.peer_addr().and_then(|addr| {
+                        Ok(Accepted {
+                            addr,
+                            laddr: stream.local_addr()?,
+                            protocol: codec.version(),
+                            query_buffer: codec.query_buffer(),
+                        })
+                    });
+
+                    let config = config.clone();
+                    let stats = stats.clone();
+                    future::result(accepted)
+                        .map_err(ConnectionError::from)
+                        .and_then(move |accepted| {
+                            connection::accept(
+                                stream, codec, reader, writer, config, stats, accepted,
+                            )
+                        })
+                        .map_err(|err| error!("Connection error: {}", err))
+                })
+                .into_new_service()
+            })?
+            .start())
+    }
 }
@@ -0,0 +1,365 @@
+//! The registry of connected clients
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use slog::slog_info;
+use slog_scope::info;
+
+use bytes::Bytes;
+
+use actix::prelude::*;
+use actix_derive::Message;
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+use crate::codecs::{ProtocolVersion, QueryBuffer};
+use crate::protocol::{KillFilter, PauseMode};
+use crate::storage::tracking::Invalidate;
+
+/// The id the next connection is assigned (ids start at 1, like in Redis)
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Assign a unique id to a new connection
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed) as u64
+}
+
+/// What other connections can see of a client (for `CLIENT LIST`)
+///
+/// Connections keep this up to date as they process commands.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    /// The address of the client
+    pub addr: SocketAddr,
+    /// The local address the client connected to
+    pub laddr: SocketAddr,
+    pub name: Option<Bytes>,
+    /// When the connection was opened
+    pub created: Instant,
+    /// When the client last sent a command
+    pub last_interaction: Instant,
+    /// The full name of the last command (or `NULL` if there was none)
+    pub last_command: &'static str,
+    /// The selected database
+    pub db: usize,
+    /// The flags of the client (like `P` for subscribers, or `N` if none apply)
+    pub flags: String,
+    /// The number of channels, patterns and sharded channels the client is subscribed to
+    pub subscriptions: (usize, usize, usize),
+    /// The number of commands queued in a transaction, if any
+    pub multi: Option<usize>,
+    pub protocol: ProtocolVersion,
+    pub query_buffer: QueryBuffer,
+    /// The number of responses and bytes queued to be sent to the client
+    pub output_buffer: (usize, usize),
+}
+impl ClientInfo {
+    /// Describe a new connection
+    pub fn new(
+        id: u64,
+        addr: SocketAddr,
+        laddr: SocketAddr,
+        protocol: ProtocolVersion,
+        query_buffer: QueryBuffer,
+    ) -> Self {
+        let now = Instant::now();
+        ClientInfo {
+            id,
+            addr,
+            laddr,
+            name: None,
+            created: now,
+            last_interaction: now,
+            last_command: "NULL",
+            db: 0,
+            flags: "N".to_owned(),
+            subscriptions: (0, 0, 0),
+            multi: None,
+            output_buffer: (0, 0),
+            protocol,
+            query_buffer,
+        }
+    }
+
+    /// Format the information as a line of `CLIENT LIST`
+    pub fn render(&self) -> String {
+        let name = self.name.as_ref().map(|x| &x[..]).unwrap_or(&[]);
+        let (sub, psub, ssub) = self.subscriptions;
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub={} psub={} \
+             ssub={} multi={} qbuf={} oll={} omem={} cmd={} user=default resp={}\n",
+            self.id,
+            self.addr,
+            self.laddr,
+            String::from_utf8_lossy(name),
+            self.created.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.flags,
+            self.db,
+            sub,
+            psub,
+            ssub,
+            self.multi.map(|x| x as i64).unwrap_or(-1),
+            self.query_buffer.get(),
+            self.output_buffer.0,
+            self.output_buffer.1,
+            self.last_command,
+            self.protocol.get(),
+        )
+    }
+
+    /// Whether the client matches all filters of `CLIENT KILL` (issued by client `me`)
+    fn matches(&self, filter: &KillFilter, me: u64) -> bool {
+        let address = |addr: &SocketAddr, filter: &Option<Bytes>| match filter {
+            Some(filter) => &filter[..] == addr.to_string().as_bytes(),
+            None => true,
+        };
+        !(filter.skip_me && self.id == me)
+            && filter.id.map(|id| id == self.id).unwrap_or(true)
+            && address(&self.addr, &filter.addr)
+            && address(&self.laddr, &filter.laddr)
+            && filter
+                .user
+                .as_ref()
+                .map(|user| &user[..] == b"default")
+                .unwrap_or(true)
+    }
+}
+
+/// The pause set by `CLIENT PAUSE` (if any), shared by the registry with all connections
+#[derive(Debug, Default)]
+pub struct Pause(RwLock<Option<(PauseMode, Instant)>>);
+impl Pause {
+    /// Whether a command (that may write or not) must wait until clients are unpaused
+    pub fn holds(&self, writes: bool) -> bool {
+        match *self.0.read().unwrap() {
+            Some((_, until)) if until <= Instant::now() => false,
+            Some((PauseMode::All, _)) => true,
+            Some((PauseMode::Write, _)) => writes,
+            None => false,
+        }
+    }
+}
+
+/// A connected client, as seen by other connections
+struct Client {
+    /// What the connection reports about itself
+    info: Arc<Mutex<ClientInfo>>,
+    /// The connection's handler for invalidation messages
+    invalidate: Recipient<Invalidate>,
+    /// The connection's handler for `CLIENT KILL`
+    kill: Recipient<Kill>,
+}
+
+/// An actor that keeps track of all connected clients by id
+#[derive(Default)]
+pub struct Clients {
+    clients: HashMap<u64, Client>,
+    pause: Arc<Pause>,
+    /// The clients waiting for the pause to end
+    paused: Vec<oneshot::Sender<()>>,
+}
+impl Clients {
+    /// End the pause, resuming all waiting clients
+    fn unpause(&mut self) {
+        if self.pause.0.write().unwrap().take().is_some() {
+            info!("Unpausing clients");
+        }
+        for waiting in self.paused.drain(..) {
+            let _ = waiting.send(());
+        }
+    }
+}
+impl Actor for Clients {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Spawned client registry");
+    }
+}
+impl Supervised for Clients {}
+impl SystemService for Clients {}
+
+/// A notification that a client has connected, replying with the pause shared by all clients
+#[derive(Message)]
+#[rtype(result = "Arc<Pause>")]
+pub struct Register {
+    pub id: u64,
+    pub info: Arc<Mutex<ClientInfo>>,
+    pub invalidate: Recipient<Invalidate>,
+    pub kill: Recipient<Kill>,
+}
+
+/// A notification that a client has disconnected
+#[derive(Debug, Message)]
+pub struct Deregister {
+    pub id: u64,
+}
+
+/// A request for the invalidation handler of a client (used for `CLIENT TRACKING REDIRECT`)
+#[derive(Debug, Message)]
+#[rtype(result = "Option<Recipient<Invalidate>>")]
+pub struct Lookup {
+    pub id: u64,
+}
+
+/// A request for the information of all clients (or those with the given ids), sorted by id
+#[derive(Debug, Message)]
+#[rtype(result = "Vec<ClientInfo>")]
+pub struct List {
+    pub ids: Vec<u64>,
+}
+
+/// A request to close the connections of the clients matching a filter, replying with the
+/// number of clients killed
+#[derive(Debug, Message)]
+#[rtype(result = "usize")]
+pub struct KillClients {
+    pub filter: KillFilter,
+    /// The id of the client issuing the request (for `SKIPME`)
+    pub me: u64,
+}
+
+/// A request to hold the commands of all clients (or only those that may write) for a while
+///
+/// A pause that is already in effect is only extended, and made stricter.
+#[derive(Debug, Message)]
+pub struct PauseClients {
+    pub timeout: Duration,
+    pub mode: PauseMode,
+}
+
+/// A request to end the pause
+#[derive(Debug, Message)]
+pub struct UnpauseClients;
+
+/// A request to be notified when the pause ends (or right away if clients aren't paused)
+#[derive(Debug, Message)]
+#[rtype(result = "Result<(), ()>")]
+pub struct WaitUnpaused;
+
+/// A request for a connection to close (sent to the connection itself)
+#[derive(Debug, Message)]
+pub struct Kill;
+
+impl Handler<Register> for Clients {
+    type Result = MessageResult<Register>;
+
+    fn handle(&mut self, register: Register, _ctx: &mut Context<Self>) -> Self::Result {
+        self.clients.insert(
+            register.id,
+            Client {
+                info: register.info,
+                invalidate: register.invalidate,
+                kill: register.kill,
+            },
+        );
+        MessageResult(self.pause.clone())
+    }
+}
+
+impl Handler<Deregister> for Clients {
+    type Result = ();
+
+    fn handle(&mut self, deregister: Deregister, _ctx: &mut Context<Self>) {
+        self.clients.remove(&deregister.id);
+    }
+}
+
+impl Handler<Lookup> for Clients {
+    type Result = MessageResult<Lookup>;
+
+    fn handle(&mut self, lookup: Lookup, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(
+            self.clients
+                .get(&lookup.id)
+                .map(|client| client.invalidate.clone()),
+        )
+    }
+}
+
+impl Handler<List> for Clients {
+    type Result = MessageResult<List>;
+
+    fn handle(&mut self, list: List, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut clients: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|(id, _)| list.ids.is_empty() || list.ids.contains(id))
+            .map(|(_, client)| client.info.lock().unwrap().clone())
+            .collect();
+        clients.sort_by_key(|info| info.id);
+        MessageResult(clients)
+    }
+}
+
+impl Handler<KillClients> for Clients {
+    type Result = usize;
+
+    fn handle(&mut self, kill: KillClients, _ctx: &mut Context<Self>) -> usize {
+        let mut killed = 0;
+        for client in self.clients.values() {
+            let info = client.info.lock().unwrap();
+            if info.matches(&kill.filter, kill.me) {
+                info!("Killing client {} ({})", info.id, info.addr);
+                let _ = client.kill.do_send(Kill);
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+impl Handler<PauseClients> for Clients {
+    type Result = ();
+
+    fn handle(&mut self, pause: PauseClients, ctx: &mut Context<Self>) {
+        info!("Pausing clients ({:?}) for {:?}", pause.mode, pause.timeout);
+        let until = Instant::now() + pause.timeout;
+        {
+            let mut current = self.pause.0.write().unwrap();
+            *current = match *current {
+                Some((mode, end)) if end > Instant::now() => {
+                    Some((mode.max(pause.mode), end.max(until)))
+                }
+                _ => Some((pause.mode, until)),
+            };
+        }
+
+        ctx.run_later(pause.timeout, |actor, _ctx| {
+            let expired = match *actor.pause.0.read().unwrap() {
+                Some((_, until)) => until <= Instant::now(),
+                None => false,
+            };
+            if expired {
+                actor.unpause();
+            }
+        });
+    }
+}
+
+impl Handler<UnpauseClients> for Clients {
+    type Result = ();
+
+    fn handle(&mut self, _: UnpauseClients, _ctx: &mut Context<Self>) {
+        self.unpause();
+    }
+}
+
+impl Handler<WaitUnpaused> for Clients {
+    type Result = Box<Future<Item = (), Error = ()>>;
+
+    fn handle(&mut self, _: WaitUnpaused, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.pause.0.read().unwrap().is_none() {
+            return Box::new(future::ok(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.paused.push(tx);
+        Box::new(rx.map_err(|_| ()))
+    }
+}
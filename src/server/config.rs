@@ -0,0 +1,601 @@
+//! The live configuration managed by `CONFIG`
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+use slog::slog_info;
+use slog_scope::info;
+
+use bytes::Bytes;
+
+use actix::prelude::*;
+
+use crate::protocol::Error;
+use crate::server::ServerConfiguration;
+use crate::storage::notifications::KeyspaceEvents;
+use crate::storage::writer::{SetKeyspaceEvents, SetScriptTimeLimit, Writer};
+use crate::utils::glob;
+use crate::utils::logging::{self, LoggingConfiguration, LoggingHandle};
+
+/// The settings of a running server
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub server: ServerConfiguration,
+    pub logging: LoggingConfiguration,
+}
+
+impl Settings {
+    /// Check that the settings are valid, applying the same rules as `CONFIG SET`
+    pub fn validate(&self) -> Result<(), String> {
+        let mut scratch = self.clone();
+        for parameter in PARAMETERS {
+            if let (Some(set), ("server", _)) = (parameter.set, parameter.location) {
+                set(&mut scratch, &(parameter.get)(self))
+                    .map_err(|reason| format!("{}: {}", parameter.name, reason))?;
+            }
+        }
+        let logging = &self.logging;
+        for level in logging.level.iter().chain(&logging.stdlog_level) {
+            if logging::parse_level(level).is_none() {
+                return Err(format!("invalid logging level '{}'", level));
+            }
+        }
+        if self.server.listen_on.is_empty() {
+            return Err("no addresses to listen on".to_owned());
+        }
+        if self.server.databases == 0 {
+            return Err("databases: there must be at least one database".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// A configuration parameter, as named by `CONFIG`
+struct Parameter {
+    name: &'static str,
+    /// The section and key of the parameter in the configuration file
+    location: (&'static str, &'static str),
+    get: fn(&Settings) -> String,
+    /// Change the parameter (`None` if it can't be changed at runtime)
+    set: Option<fn(&mut Settings, &str) -> Result<(), String>>,
+}
+
+/// All configuration parameters
+static PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "bind",
+        location: ("server", "listen_on"),
+        get: |settings| {
+            let addresses: Vec<_> = settings
+                .server
+                .listen_on
+                .iter()
+                .map(|x| x.ip().to_string())
+                .collect();
+            addresses.join(" ")
+        },
+        set: None,
+    },
+    Parameter {
+        name: "port",
+        location: ("server", "listen_on"),
+        get: |settings| {
+            let port = settings.server.listen_on.first().map(|x| x.port());
+            port.unwrap_or(0).to_string()
+        },
+        set: None,
+    },
+    Parameter {
+        name: "databases",
+        location: ("server", "databases"),
+        get: |settings| settings.server.databases.to_string(),
+        set: None,
+    },
+    Parameter {
+        name: "loglevel",
+        location: ("logging", "level"),
+        get: |settings| settings.logging.level.clone().unwrap_or_default(),
+        set: Some(|settings, value| match logging::parse_level(value) {
+            Some(_) => {
+                settings.logging.level = Some(value.to_owned());
+                Ok(())
+            }
+            None => Err("argument must be a logging level".to_owned()),
+        }),
+    },
+    Parameter {
+        name: "lua-time-limit",
+        location: ("server", "lua_time_limit"),
+        get: |settings| settings.server.lua_time_limit.to_string(),
+        set: Some(|settings, value| {
+            settings.server.lua_time_limit = parse_integer(value)?;
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "notify-keyspace-events",
+        location: ("server", "notify_keyspace_events"),
+        get: |settings| settings.server.notify_keyspace_events.clone(),
+        set: Some(|settings, value| {
+            value
+                .parse::<KeyspaceEvents>()
+                .map_err(|err| err.to_string())?;
+            settings.server.notify_keyspace_events = value.to_owned();
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "maxmemory",
+        location: ("server", "maxmemory"),
+        get: |settings| settings.server.maxmemory.to_string(),
+        // Memory usage isn't limited, so changing the limit at runtime would suggest otherwise
+        set: None,
+    },
+    Parameter {
+        name: "timeout",
+        location: ("server", "timeout"),
+        get: |settings| settings.server.timeout.to_string(),
+        set: Some(|settings, value| {
+            settings.server.timeout = parse_integer(value)?;
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "tcp-keepalive",
+        location: ("server", "tcp_keepalive"),
+        get: |settings| settings.server.tcp_keepalive.to_string(),
+        set: Some(|settings, value| {
+            settings.server.tcp_keepalive = parse_integer(value)?;
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "maxclients",
+        location: ("server", "maxclients"),
+        get: |settings| settings.server.maxclients.to_string(),
+        set: Some(|settings, value| match parse_integer(value)? {
+            0 => Err("argument must be between 1 and 4294967295 inclusive".to_owned()),
+            maxclients => {
+                settings.server.maxclients = maxclients;
+                Ok(())
+            }
+        }),
+    },
+    Parameter {
+        name: "requirepass",
+        location: ("server", "requirepass"),
+        get: |settings| settings.server.requirepass.clone().unwrap_or_default(),
+        set: Some(|settings, value| {
+            settings.server.requirepass = Some(value.to_owned()).filter(|x| !x.is_empty());
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "client-output-buffer-limit",
+        location: ("server", "client_output_buffer_limit"),
+        get: |settings| {
+            let limits = &settings.server.client_output_buffer_limit;
+            let classes = [
+                ("normal", limits.normal),
+                ("replica", limits.replica),
+                ("pubsub", limits.pubsub),
+            ];
+            let classes: Vec<_> = classes
+                .iter()
+                .map(|(class, limit)| {
+                    format!(
+                        "{} {} {} {}",
+                        class, limit.hard_limit, limit.soft_limit, limit.soft_seconds
+                    )
+                })
+                .collect();
+            classes.join(" ")
+        },
+        set: Some(|settings, value| {
+            let tokens: Vec<_> = value.split_whitespace().collect();
+            if tokens.is_empty() || tokens.len() % 4 != 0 {
+                return Err("Wrong number of arguments in buffer limit configuration.".to_owned());
+            }
+            // Validate every class before changing any of them
+            let mut limits = settings.server.client_output_buffer_limit;
+            for class in tokens.chunks(4) {
+                let limit = match &class[0].to_ascii_lowercase()[..] {
+                    "normal" => &mut limits.normal,
+                    "replica" | "slave" => &mut limits.replica,
+                    "pubsub" => &mut limits.pubsub,
+                    _ => {
+                        return Err(
+                            "Invalid client class specified in buffer limit configuration."
+                                .to_owned(),
+                        )
+                    }
+                };
+                let invalid = |_| {
+                    "Error in hard, soft or soft_seconds setting in buffer limit configuration."
+                        .to_owned()
+                };
+                limit.hard_limit = parse_memory(class[1]).map_err(invalid)?;
+                limit.soft_limit = parse_memory(class[2]).map_err(invalid)?;
+                limit.soft_seconds = parse_integer(class[3]).map_err(invalid)?;
+            }
+            settings.server.client_output_buffer_limit = limits;
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "proto-max-bulk-len",
+        location: ("server", "proto_max_bulk_len"),
+        get: |settings| settings.server.proto_max_bulk_len.to_string(),
+        set: Some(|settings, value| {
+            settings.server.proto_max_bulk_len = parse_memory(value)? as usize;
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "proto-max-multibulk-len",
+        location: ("server", "proto_max_multibulk_len"),
+        get: |settings| settings.server.proto_max_multibulk_len.to_string(),
+        set: Some(|settings, value| {
+            settings.server.proto_max_multibulk_len = parse_integer(value)?;
+            Ok(())
+        }),
+    },
+    Parameter {
+        name: "client-query-buffer-limit",
+        location: ("server", "client_query_buffer_limit"),
+        get: |settings| settings.server.client_query_buffer_limit.to_string(),
+        set: Some(|settings, value| {
+            settings.server.client_query_buffer_limit = parse_memory(value)? as usize;
+            Ok(())
+        }),
+    },
+];
+
+/// Find a parameter by name (ignoring case)
+fn parameter(name: &[u8]) -> Option<&'static Parameter> {
+    PARAMETERS
+        .iter()
+        .find(|parameter| parameter.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+fn parse_integer<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| "argument couldn't be parsed into an integer".to_owned())
+}
+
+/// Parse a number of bytes, optionally with a unit (like `100mb` or `1g`)
+fn parse_memory(value: &str) -> Result<u64, String> {
+    let value = value.to_ascii_lowercase();
+    let digits = value
+        .find(|x: char| !x.is_ascii_digit())
+        .unwrap_or_else(|| value.len());
+    let factor = match &value[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err("argument must be a memory value".to_owned()),
+    };
+    value[..digits]
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(factor))
+        .ok_or_else(|| "argument must be a memory value".to_owned())
+}
+
+/// The configuration of a running server, shared by its connections
+///
+/// Settings that are fixed per connection (like the protocol limits) only apply to new
+/// connections when they are changed.
+#[derive(Default)]
+pub struct LiveConfiguration {
+    settings: RwLock<Settings>,
+    /// The handle to reconfigure the global logger (if the server set it up)
+    logging: Option<LoggingHandle>,
+    /// The file the configuration was loaded from (and is rewritten to)
+    file: Option<PathBuf>,
+}
+impl LiveConfiguration {
+    /// Create a live configuration, starting with the given server settings
+    pub fn new(server: ServerConfiguration) -> Self {
+        LiveConfiguration {
+            settings: RwLock::new(Settings {
+                server,
+                logging: LoggingConfiguration::default(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Manage the logging level of the global logger
+    pub fn with_logging(mut self, handle: LoggingHandle) -> Self {
+        self.settings.get_mut().unwrap().logging = handle.config();
+        self.logging = Some(handle);
+        self
+    }
+
+    /// Write the configuration to the given file on `CONFIG REWRITE`
+    pub fn with_file(mut self, path: PathBuf) -> Self {
+        self.file = Some(path);
+        self
+    }
+
+    /// The current server settings
+    pub fn server(&self) -> ServerConfigurationGuard<'_> {
+        ServerConfigurationGuard(self.settings.read().unwrap())
+    }
+
+    /// The names and values of the parameters matching any of the patterns
+    pub fn get(&self, patterns: &[Bytes]) -> Vec<(&'static str, String)> {
+        let settings = self.settings.read().unwrap();
+        PARAMETERS
+            .iter()
+            .filter(|parameter| {
+                patterns.iter().any(|pattern| {
+                    glob::matches(&pattern.to_ascii_lowercase(), parameter.name.as_bytes())
+                })
+            })
+            .map(|parameter| (parameter.name, (parameter.get)(&settings)))
+            .collect()
+    }
+
+    /// Change parameters, applying them to the running actors
+    ///
+    /// Either all parameters are changed, or none of them if any value is invalid.
+    pub fn set(&self, values: Vec<(Bytes, Bytes)>) -> Result<(), Error> {
+        let mut settings = self.settings.write().unwrap();
+        let mut changed = settings.clone();
+        let mut seen = HashSet::new();
+        for (name, value) in values {
+            let parameter = parameter(&name).ok_or_else(|| Error::UnknownConfig(name.clone()))?;
+            let failed = |reason: &str| Error::ConfigSetFailed(parameter.name, reason.to_owned());
+            if !seen.insert(parameter.name) {
+                return Err(failed("duplicate parameter"));
+            }
+            let set = parameter
+                .set
+                .ok_or_else(|| failed("can't set immutable config"))?;
+            let value = std::str::from_utf8(&value).map_err(|_| failed("invalid argument"))?;
+            set(&mut changed, value).map_err(|reason| failed(&reason))?;
+        }
+
+        self.apply(&settings, &changed);
+        *settings = changed;
+        Ok(())
+    }
+
+    /// Replace all settings with a reloaded configuration, applying them to the running actors
+    ///
+    /// Nothing is changed if any setting is invalid. Settings that can't change at runtime keep
+    /// their current values; the names of those that differ are returned.
+    pub fn reload(&self, mut reloaded: Settings) -> Result<Vec<&'static str>, String> {
+        reloaded.validate()?;
+
+        let mut settings = self.settings.write().unwrap();
+        let mut restart = Vec::new();
+        {
+            let mut keep = |name, changed: bool| {
+                if changed {
+                    restart.push(name);
+                }
+            };
+            let (old, new) = (&settings.server, &mut reloaded.server);
+            keep("server.databases", old.databases != new.databases);
+            new.databases = old.databases;
+            keep(
+                "server.functions_file",
+                old.functions_file != new.functions_file,
+            );
+            new.functions_file = old.functions_file.clone();
+
+            let (old, new) = (&settings.logging, &mut reloaded.logging);
+            keep(
+                "logging.forward_stdlog",
+                old.forward_stdlog != new.forward_stdlog,
+            );
+            new.forward_stdlog = old.forward_stdlog;
+            keep("logging.stdlog_level", old.stdlog_level != new.stdlog_level);
+            new.stdlog_level = old.stdlog_level.clone();
+            keep("logging.with_module", old.with_module != new.with_module);
+            new.with_module = old.with_module;
+            keep(
+                "logging.with_filename",
+                old.with_filename != new.with_filename,
+            );
+            new.with_filename = old.with_filename;
+        }
+
+        self.apply(&settings, &reloaded);
+        *settings = reloaded;
+        Ok(restart)
+    }
+
+    /// Notify the actors whose settings changed
+    fn apply(&self, old: &Settings, new: &Settings) {
+        if old.server.lua_time_limit != new.server.lua_time_limit {
+            Writer::from_registry().do_send(SetScriptTimeLimit(Duration::from_millis(
+                new.server.lua_time_limit,
+            )));
+        }
+        if old.server.notify_keyspace_events != new.server.notify_keyspace_events {
+            if let Ok(events) = new.server.notify_keyspace_events.parse() {
+                Writer::from_registry().do_send(SetKeyspaceEvents(events));
+            }
+        }
+        if old.logging != new.logging {
+            if let Some(ref handle) = self.logging {
+                handle.reconfigure(new.logging.clone());
+            }
+        }
+    }
+
+    /// Write the effective parameters to the configuration file (keeping its other settings)
+    pub fn rewrite(&self) -> Result<(), Error> {
+        let path = self.file.as_ref().ok_or(Error::NoConfigFile)?;
+        rewrite(path, &self.settings.read().unwrap())
+            .map_err(|err| Error::ConfigRewriteFailed(err.to_string()))?;
+        info!("Rewrote configuration file {}", path.display());
+        Ok(())
+    }
+}
+
+/// Read access to the current server settings
+pub struct ServerConfigurationGuard<'a>(RwLockReadGuard<'a, Settings>);
+impl<'a> std::ops::Deref for ServerConfigurationGuard<'a> {
+    type Target = ServerConfiguration;
+
+    fn deref(&self) -> &ServerConfiguration {
+        &self.0.server
+    }
+}
+
+fn rewrite(path: &Path, settings: &Settings) -> io::Result<()> {
+    let invalid =
+        |err: &dyn std::error::Error| io::Error::new(io::ErrorKind::InvalidData, err.to_string());
+
+    let mut document = match fs::read_to_string(path) {
+        Ok(contents) => contents.parse().map_err(|err| invalid(&err))?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            toml::Value::Table(toml::value::Table::new())
+        }
+        Err(err) => return Err(err),
+    };
+    let server = toml::Value::try_from(&settings.server).map_err(|err| invalid(&err))?;
+    let logging = toml::Value::try_from(&settings.logging).map_err(|err| invalid(&err))?;
+
+    for parameter in PARAMETERS {
+        let (section, key) = parameter.location;
+        let effective = match section {
+            "server" => server.get(key),
+            _ => logging.get(key),
+        };
+        let table = document
+            .as_table_mut()
+            .and_then(|document| {
+                document
+                    .entry(section.to_owned())
+                    .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+                    .as_table_mut()
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("[{}] is not a table", section),
+                )
+            })?;
+        match effective {
+            Some(value) => table.insert(key.to_owned(), value.clone()),
+            None => table.remove(key),
+        };
+    }
+
+    // Replace the file at once, so it is never left half-written
+    let contents = toml::to_string_pretty(&document).map_err(|err| invalid(&err))?;
+    let temporary = path.with_extension("toml.tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_values_are_parsed() {
+        assert_eq!(parse_memory("100"), Ok(100));
+        assert_eq!(parse_memory("1kb"), Ok(1024));
+        assert_eq!(parse_memory("2M"), Ok(2_000_000));
+        assert_eq!(parse_memory("1gb"), Ok(1 << 30));
+        assert!(parse_memory("1tb").is_err());
+        assert!(parse_memory("mb").is_err());
+    }
+
+    #[test]
+    fn parameters_are_matched_by_pattern() {
+        let config = LiveConfiguration::new(ServerConfiguration::default());
+        let names: Vec<_> = config
+            .get(&[Bytes::from("proto-*"), Bytes::from("DATABASES")])
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["databases", "proto-max-bulk-len", "proto-max-multibulk-len"]
+        );
+    }
+
+    #[test]
+    fn invalid_values_change_nothing() {
+        let config = LiveConfiguration::new(ServerConfiguration::default());
+        let result = config.set(vec![
+            (Bytes::from("timeout"), Bytes::from("10")),
+            (Bytes::from("databases"), Bytes::from("4")),
+        ]);
+        match result {
+            Err(Error::ConfigSetFailed("databases", _)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(config.server().timeout, 0);
+    }
+
+    #[test]
+    fn output_buffer_limits_are_set_per_class() {
+        let config = LiveConfiguration::new(ServerConfiguration::default());
+        let name = Bytes::from("client-output-buffer-limit");
+        config
+            .set(vec![(
+                name.clone(),
+                Bytes::from("normal 1mb 512kb 10 slave 0 0 0"),
+            )])
+            .unwrap();
+        assert_eq!(
+            config.get(&[name.clone()]),
+            vec![(
+                "client-output-buffer-limit",
+                "normal 1048576 524288 10 replica 0 0 0 pubsub 33554432 8388608 60".to_owned()
+            )]
+        );
+        assert!(config
+            .set(vec![(name.clone(), Bytes::from("normal 0 0"))])
+            .is_err());
+        assert!(config
+            .set(vec![(name, Bytes::from("master 0 0 0"))])
+            .is_err());
+    }
+
+    #[test]
+    fn at_least_one_database_is_required() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.validate(), Ok(()));
+        settings.server.databases = 0;
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn reloading_keeps_fixed_settings() {
+        let config = LiveConfiguration::new(ServerConfiguration::default());
+        let mut reloaded = Settings::default();
+        reloaded.server.databases = 4;
+        reloaded.server.maxmemory = 1024;
+        assert_eq!(
+            config.reload(reloaded.clone()),
+            Ok(vec!["server.databases"])
+        );
+        assert_eq!(
+            config.server().databases,
+            ServerConfiguration::default().databases
+        );
+        assert_eq!(config.server().maxmemory, 1024);
+
+        reloaded.logging.level = Some("loud".to_owned());
+        assert!(config.reload(reloaded).is_err());
+    }
+}
@@ -1,23 +1,42 @@
 //! The connection handler
 
+use std::collections::HashSet;
 use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use slog::{slog_debug, slog_error, slog_info, slog_o, Logger};
+use slog::{slog_debug, slog_error, slog_info, slog_o, slog_warn, Logger};
 use slog_scope::error;
 
 use quick_error::quick_error;
+
+use bytes::Bytes;
 use uuid::Uuid;
 
 use actix::prelude::*;
-use futures::{Future, IntoFuture, Sink, Stream};
+use futures::{future, stream, Future, IntoFuture, Sink, Stream};
 use tokio_codec::{Decoder, Encoder};
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use crate::codecs::{DecodeError, EncodeError};
-use crate::protocol::{Command, Error, Response};
+use crate::codecs::{DecodeError, EncodeError, ProtocolVersion, QueryBuffer};
+use crate::protocol::{commands, Command, Error, ReplyMode, Response, TrackingOptions};
+use crate::pubsub::{self, Broker, Push, SubscriptionKind};
+use crate::server::clients::{
+    self, ClientInfo, Clients, Deregister, Kill, KillClients, List, Lookup, Pause, PauseClients,
+    Register, UnpauseClients, WaitUnpaused,
+};
+use crate::server::config::LiveConfiguration;
+use crate::server::info;
+use crate::stats::Stats;
 use crate::storage::reader::Reader;
-use crate::storage::writer::Writer;
-use crate::storage::{Operation, StorageError};
+use crate::storage::tracking::Invalidate;
+use crate::storage::writer::{
+    DisableTracking, EnableTracking, Info, Track, Unwatch, Watch, Writer,
+};
+use crate::storage::{Operation, StorageError, Transaction};
+use crate::utils::constant_time;
 
 quick_error! {
     /// An error encountered during connection handling
@@ -56,6 +75,52 @@ quick_error! {
     }
 }
 
+/// The commands queued in a transaction (started by `MULTI`)
+#[derive(Debug, Default)]
+struct QueuedTransaction {
+    /// The operations to execute on `EXEC`
+    operations: Vec<Operation>,
+    /// The database that queued commands are executed against
+    db: usize,
+    /// Whether any command was rejected while queueing
+    failed: bool,
+}
+
+/// How often connections check whether they have been idle for too long, or have stayed over the
+/// soft output buffer limit for too long (while the client isn't reading anything)
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The channel redirected invalidation messages are published on
+const INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+/// Which keys a connection with `CLIENT TRACKING` enabled receives invalidations for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TrackingMode {
+    /// All keys it reads
+    Default,
+    /// Keys read right after `CLIENT CACHING YES`
+    OptIn,
+    /// Keys read, except right after `CLIENT CACHING NO`
+    OptOut,
+    /// All keys matching its prefixes, whether it read them or not
+    Broadcast,
+}
+
+/// A newly accepted connection: its addresses, and the handles shared with its codec
+pub struct Accepted {
+    /// The address of the client
+    pub addr: SocketAddr,
+    /// The local address the client connected to
+    pub laddr: SocketAddr,
+    pub protocol: ProtocolVersion,
+    pub query_buffer: QueryBuffer,
+}
+
+/// Whether a client name is valid (names can't contain spaces or special characters)
+fn is_valid_name(name: &[u8]) -> bool {
+    name.iter().all(|&x| x > b' ' && x <= b'~')
+}
+
 /// A connection handler
 pub struct Connection<R, T>
 where
@@ -63,17 +128,69 @@ where
     T: Sink<SinkItem = Response, SinkError = ConnectionError>,
 {
     /// The connection identifier (useful for log correlation)
-    _client_id: Uuid,
+    client_id: Uuid,
+    /// The numeric connection id (as reported by `CLIENT ID`)
+    id: u64,
+    /// The connection name (set by `CLIENT SETNAME` or `HELLO ... SETNAME`)
+    name: Option<Bytes>,
+    /// What other connections can see of this one (shared with the client registry)
+    info: Arc<Mutex<ClientInfo>>,
+    /// The protocol version responses are encoded with
+    protocol: ProtocolVersion,
     /// The command stream to listen on
     rx: Option<R>,
-    /// The response sink to respond on
+    /// The response sink to respond on (`None` while it is sending responses)
     tx: Option<T>,
+    /// Responses waiting for the sink to finish sending earlier ones
+    pending: Vec<Response>,
+    /// The number of responses queued to be sent (waiting or being sent)
+    queued_responses: usize,
+    /// The (approximate) number of bytes of the responses queued to be sent
+    queued_bytes: usize,
+    /// Since when the queued responses exceed the soft output buffer limit
+    soft_limit_since: Option<Instant>,
+    /// Whether the connection closes once the queued responses have been sent
+    closing: bool,
+    /// Whether the connection was rejected (so it was never counted or registered)
+    rejected: bool,
     /// A scoped logger
     logger: Logger,
     /// Address of the `Reader` actor to use
     reader: Addr<Reader>,
     /// Address of the `writer` actor to use
     writer: Addr<Writer>,
+    /// Address of the pub/sub `Broker` actor
+    broker: Addr<Broker>,
+    /// Address of the client registry
+    clients: Addr<Clients>,
+    /// The pause set by `CLIENT PAUSE` (shared by all connections)
+    pause: Arc<Pause>,
+    /// Which replies the client receives
+    reply_mode: ReplyMode,
+    /// Whether the replies to the current command are suppressed
+    silent: bool,
+    /// Whether the client authenticated (or connected while no password was required)
+    authenticated: bool,
+    /// The live server configuration
+    config: Arc<LiveConfiguration>,
+    /// The server counters
+    stats: Arc<Stats>,
+    /// The currently selected database
+    db: usize,
+    /// The transaction that is currently being queued, if any
+    transaction: Option<QueuedTransaction>,
+    /// Whether the `Writer` is watching keys on behalf of this connection
+    watching: bool,
+    /// The channels this connection is subscribed to
+    channels: HashSet<Bytes>,
+    /// The patterns this connection is subscribed to
+    patterns: HashSet<Bytes>,
+    /// The sharded channels this connection is subscribed to
+    shard_channels: HashSet<Bytes>,
+    /// The client-side caching mode, if tracking is enabled
+    tracking: Option<TrackingMode>,
+    /// Whether to track the keys read by the next command (set by `CLIENT CACHING`)
+    caching: Option<bool>,
 }
 
 impl<R, T> Connection<R, T>
@@ -82,88 +199,1094 @@ where
     T: Sink<SinkItem = Response, SinkError = ConnectionError> + 'static,
 {
     /// Create a new connection handler for the given input/output and reader/writer
-    pub fn new(rx: R, tx: T, reader: Addr<Reader>, writer: Addr<Writer>) -> Self {
+    pub fn new(
+        rx: R,
+        tx: T,
+        reader: Addr<Reader>,
+        writer: Addr<Writer>,
+        config: Arc<LiveConfiguration>,
+        stats: Arc<Stats>,
+        accepted: Accepted,
+    ) -> Self {
         let client_id = Uuid::new_v4();
-        let logger = slog_scope::logger().new(slog_o!("client_id" => format!("{}", client_id)));
+        let id = clients::next_id();
+        let info = ClientInfo::new(
+            id,
+            accepted.addr,
+            accepted.laddr,
+            accepted.protocol.clone(),
+            accepted.query_buffer,
+        );
         Connection {
-            _client_id: client_id,
+            client_id,
+            id,
+            name: None,
+            info: Arc::new(Mutex::new(info)),
+            protocol: accepted.protocol,
             rx: Some(rx),
             tx: Some(tx),
-            logger,
+            pending: Vec::new(),
+            queued_responses: 0,
+            queued_bytes: 0,
+            soft_limit_since: None,
+            closing: false,
+            rejected: false,
+            logger: Self::logger(client_id, None),
             reader,
             writer,
+            broker: Broker::from_registry(),
+            clients: Clients::from_registry(),
+            pause: Arc::new(Pause::default()),
+            reply_mode: ReplyMode::On,
+            silent: false,
+            authenticated: config.server().requirepass.is_none(),
+            config,
+            stats,
+            db: 0,
+            transaction: None,
+            watching: false,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            tracking: None,
+            caching: None,
         }
     }
 
-    fn send_error(&mut self, err: Error, ctx: &mut Context<Self>) {
-        let tx = self.tx.take().expect("Sink not available");
+    /// A logger with the connection identifier and name in its context
+    fn logger(client_id: Uuid, name: Option<&Bytes>) -> Logger {
+        let logger = slog_scope::logger().new(slog_o!("client_id" => format!("{}", client_id)));
+        match name {
+            Some(name) => {
+                logger.new(slog_o!("client_name" => String::from_utf8_lossy(name).into_owned()))
+            }
+            None => logger,
+        }
+    }
+
+    /// Name the connection (or remove its name)
+    fn set_name(&mut self, name: Option<Bytes>) {
+        self.logger = Self::logger(self.client_id, name.as_ref());
+        self.name = name;
+    }
+
+    /// Update what other connections can see of this one
+    fn update_info(&self) {
+        let mut flags = String::new();
+        if self.is_subscriber() {
+            flags.push('P');
+        }
+        if self.transaction.is_some() {
+            flags.push('x');
+        }
+        if self.tracking.is_some() {
+            flags.push('t');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+
+        let mut info = self.info.lock().unwrap();
+        info.name = self.name.clone();
+        info.db = self.db;
+        info.flags = flags;
+        info.subscriptions = (
+            self.channels.len(),
+            self.patterns.len(),
+            self.shard_channels.len(),
+        );
+        info.multi = self
+            .transaction
+            .as_ref()
+            .map(|transaction| transaction.operations.len());
+        info.output_buffer = (self.queued_responses, self.queued_bytes);
+    }
+
+    /// Whether this connection speaks RESP3 (and thus supports push messages)
+    fn is_resp3(&self) -> bool {
+        self.protocol.get() == 3
+    }
+
+    /// Whether this connection is subscribed to any channels or patterns (and thus only accepts
+    /// pub/sub commands, unless it speaks RESP3)
+    fn is_subscriber(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty() || !self.shard_channels.is_empty()
+    }
+
+    fn subscriptions(&mut self, kind: SubscriptionKind) -> &mut HashSet<Bytes> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// The number of subscriptions reported in (un)subscribe confirmations
+    ///
+    /// Sharded channels are counted separately from classic channels and patterns.
+    fn subscription_count(&self, kind: SubscriptionKind) -> i64 {
+        match kind {
+            SubscriptionKind::Shard => self.shard_channels.len() as i64,
+            _ => (self.channels.len() + self.patterns.len()) as i64,
+        }
+    }
+
+    /// Subscribe to channels or patterns, replying with a confirmation for each of them
+    ///
+    /// The confirmations are sent once the `Broker` has added the subscriptions, so every message
+    /// published after that (by any connection, script or transaction) is pushed to this client.
+    fn subscribe(&mut self, kind: SubscriptionKind, targets: Vec<Bytes>, ctx: &mut Context<Self>) {
+        let mut added = Vec::new();
+        let mut responses = Vec::with_capacity(targets.len());
+        for target in targets {
+            if self.subscriptions(kind).insert(target.clone()) {
+                added.push(target.clone());
+            }
+            responses.push(Response::Push(vec![
+                Response::Bulk(Bytes::from(kind.subscribe_reply())),
+                Response::Bulk(target),
+                Response::Integer(self.subscription_count(kind)),
+            ]));
+        }
+
+        if added.is_empty() {
+            return self.reply(responses, ctx);
+        }
+        let subscribe = pubsub::Subscribe {
+            client: self.client_id,
+            recipient: ctx.address().recipient(),
+            kind,
+            targets: added,
+        };
+        ctx.wait(
+            self.broker
+                .send(subscribe)
+                .into_actor(self)
+                .then(move |result, actor, ctx| {
+                    if let Err(err) = result {
+                        error!("Error while subscribing: {}", err);
+                        return actix::fut::err(());
+                    }
+                    actor.reply(responses, ctx);
+                    actix::fut::ok(())
+                }),
+        );
+    }
+
+    /// Unsubscribe from channels or patterns (or all of them if none are given), returning a
+    /// confirmation for each of them
+    fn unsubscribe(&mut self, kind: SubscriptionKind, targets: Vec<Bytes>) -> Vec<Response> {
+        let targets = if targets.is_empty() {
+            self.subscriptions(kind).iter().cloned().collect()
+        } else {
+            targets
+        };
+
+        let mut removed = Vec::new();
+        let mut responses = Vec::with_capacity(targets.len());
+        for target in targets {
+            if self.subscriptions(kind).remove(&target) {
+                removed.push(target.clone());
+            }
+            responses.push(Response::Push(vec![
+                Response::Bulk(Bytes::from(kind.unsubscribe_reply())),
+                Response::Bulk(target),
+                Response::Integer(self.subscription_count(kind)),
+            ]));
+        }
+        if responses.is_empty() {
+            responses.push(Response::Push(vec![
+                Response::Bulk(Bytes::from(kind.unsubscribe_reply())),
+                Response::Nil,
+                Response::Integer(self.subscription_count(kind)),
+            ]));
+        }
+
+        if !removed.is_empty() {
+            self.broker.do_send(pubsub::Unsubscribe {
+                client: self.client_id,
+                kind,
+                targets: removed,
+            });
+        }
+        responses
+    }
+
+    /// Whether the client may run commands (it authenticated, or no password is required)
+    fn is_authenticated(&self) -> bool {
+        self.authenticated || self.config.server().requirepass.is_none()
+    }
+
+    /// Authenticate the client with a password (as the given user, or the default user)
+    fn authenticate(&mut self, username: Option<&Bytes>, password: &[u8]) -> Result<(), Error> {
+        if let Some(username) = username {
+            if username.as_ref() != b"default" {
+                return Err(Error::WrongPass);
+            }
+        }
+        let valid = match self.config.server().requirepass {
+            Some(ref requirepass) => constant_time::eq(requirepass.as_bytes(), password),
+            // Without `requirepass`, the default user accepts any password
+            None if username.is_some() => true,
+            None => return Err(Error::AuthNotConfigured),
+        };
+        if !valid {
+            slog_info!(self.logger, "Authentication failed");
+            return Err(Error::WrongPass);
+        }
+        self.authenticated = true;
+        Ok(())
+    }
+
+    /// Switch the protocol version (authenticating and naming the connection first), and reply
+    /// with information about the server
+    fn hello(
+        &mut self,
+        version: Option<i64>,
+        auth: Option<(Bytes, Bytes)>,
+        name: Option<Bytes>,
+        ctx: &mut Context<Self>,
+    ) {
+        let version = match version {
+            Some(2) => 2,
+            Some(3) => 3,
+            Some(_) => return self.send_error(Error::UnsupportedProtocol, ctx),
+            None => self.protocol.get(),
+        };
+        if let Some((ref username, ref password)) = auth {
+            if let Err(err) = self.authenticate(Some(username), password) {
+                return self.send_error(err, ctx);
+            }
+        } else if !self.is_authenticated() {
+            return self.send_error(Error::HelloNoAuth, ctx);
+        }
+        if let Some(ref name) = name {
+            if !is_valid_name(name) {
+                return self.send_error(Error::InvalidClientName, ctx);
+            }
+        }
+
+        if name.is_some() {
+            self.set_name(name);
+        }
+        self.protocol.set(version);
+
+        let bulk = |value: &str| Response::Bulk(Bytes::from(value));
+        let response = Response::Map(vec![
+            (bulk("server"), bulk("evredis")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), Response::Integer(version as i64)),
+            (bulk("id"), Response::Integer(self.id as i64)),
+            (bulk("mode"), bulk("standalone")),
+            (bulk("role"), bulk("master")),
+            (bulk("modules"), Response::Array(Vec::new())),
+        ]);
+        self.reply(vec![response], ctx);
+    }
+
+    /// Enable client-side caching, sending invalidations to this connection or the one it redirects
+    /// to
+    fn enable_tracking(&mut self, options: TrackingOptions, ctx: &mut Context<Self>) {
+        let error = if !options.prefixes.is_empty() && !options.broadcast {
+            Some(Error::PrefixWithoutBroadcast)
+        } else if options.opt_in && options.opt_out {
+            Some(Error::OptInAndOptOut)
+        } else if options.broadcast && (options.opt_in || options.opt_out) {
+            Some(Error::OptInOptOutWithBroadcast)
+        } else {
+            None
+        };
+        if let Some(err) = error {
+            return self.send_error(err, ctx);
+        }
+
+        let TrackingOptions {
+            redirect,
+            broadcast,
+            prefixes,
+            opt_in,
+            opt_out,
+        } = options;
+        let mode = if broadcast {
+            TrackingMode::Broadcast
+        } else if opt_in {
+            TrackingMode::OptIn
+        } else if opt_out {
+            TrackingMode::OptOut
+        } else {
+            TrackingMode::Default
+        };
+        // Broadcasting without prefixes invalidates every key
+        let prefixes = match prefixes {
+            _ if !broadcast => None,
+            ref prefixes if prefixes.is_empty() => Some(vec![Bytes::new()]),
+            prefixes => Some(prefixes),
+        };
+
+        let id = match redirect {
+            Some(id) => id,
+            None => {
+                self.start_tracking(mode, ctx.address().recipient(), false, prefixes);
+                return self.reply(vec![Response::Ok], ctx);
+            }
+        };
+        ctx.wait(self.clients.send(Lookup { id }).into_actor(self).then(
+            move |result, actor, ctx| {
+                let response = match result {
+                    Ok(Some(recipient)) => {
+                        actor.start_tracking(mode, recipient, true, prefixes);
+                        Response::Ok
+                    }
+                    Ok(None) => Response::Error(Error::NoSuchRedirectClient),
+                    Err(err) => {
+                        error!("Error while looking up client {}: {}", id, err);
+                        return actix::fut::err(());
+                    }
+                };
+                actor.reply(vec![response], ctx);
+                actix::fut::ok(())
+            },
+        ));
+    }
+
+    fn start_tracking(
+        &mut self,
+        mode: TrackingMode,
+        recipient: Recipient<Invalidate>,
+        redirected: bool,
+        prefixes: Option<Vec<Bytes>>,
+    ) {
+        self.tracking = Some(mode);
+        self.writer.do_send(EnableTracking {
+            client: self.client_id,
+            recipient,
+            redirected,
+            prefixes,
+        });
+    }
+
+    fn stop_tracking(&mut self) {
+        if self.tracking.take().is_some() {
+            self.writer.do_send(DisableTracking {
+                client: self.client_id,
+            });
+        }
+    }
+
+    /// Tell the `Writer` about the keys a command is about to read, if this connection caches them
+    ///
+    /// The command should only be executed once the keys are registered, so that it sees the writes
+    /// the `Writer` handled before, and the writes handled after invalidate the keys.
+    fn track(
+        &self,
+        command: &Command,
+        caching: Option<bool>,
+    ) -> Box<Future<Item = (), Error = MailboxError>> {
+        let tracked = match self.tracking {
+            Some(TrackingMode::Default) => true,
+            Some(TrackingMode::OptIn) => caching == Some(true),
+            Some(TrackingMode::OptOut) => caching != Some(false),
+            Some(TrackingMode::Broadcast) | None => false,
+        };
+        if !tracked || !command.reads() {
+            return Box::new(future::ok(()));
+        }
+
+        let keys = command.read_keys();
+        if keys.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        Box::new(self.writer.send(Track {
+            client: self.client_id,
+            keys,
+        }))
+    }
+
+    /// Stop watching all keys on behalf of this connection
+    fn unwatch(&mut self) {
+        if self.watching {
+            self.writer.do_send(Unwatch {
+                client: self.client_id,
+            });
+            self.watching = false;
+        }
+    }
+
+    /// Run a command that only concerns this connection (or the server as a whole), rather than
+    /// the stored data
+    fn run_on_connection(
+        &mut self,
+        cmd: Command,
+    ) -> Box<Future<Item = Response, Error = ConnectionError>> {
+        match cmd {
+            Command::ClientId => Box::new(future::ok(Response::Integer(self.id as i64))),
+            Command::ClientList(ids) => {
+                Box::new(self.clients.send(List { ids }).from_err().map(|clients| {
+                    let list: String = clients.iter().map(ClientInfo::render).collect();
+                    Response::Verbatim(Bytes::from_static(b"txt"), Bytes::from(list))
+                }))
+            }
+            Command::ClientInfo => {
+                self.update_info();
+                let info = self.info.lock().unwrap().render();
+                Box::new(future::ok(Response::Verbatim(
+                    Bytes::from_static(b"txt"),
+                    Bytes::from(info),
+                )))
+            }
+            Command::ClientSetName(ref name) if !is_valid_name(name) => {
+                Box::new(future::ok(Response::Error(Error::InvalidClientName)))
+            }
+            Command::ClientSetName(name) => {
+                self.set_name(if name.is_empty() { None } else { Some(name) });
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::ClientGetName => Box::new(future::ok(match self.name {
+                Some(ref name) => Response::Bulk(name.clone()),
+                None => Response::Nil,
+            })),
+            Command::ClientKill(filter) => {
+                let legacy = filter.legacy;
+                Box::new(
+                    self.clients
+                        .send(KillClients {
+                            filter,
+                            me: self.id,
+                        })
+                        .from_err()
+                        .map(move |killed| match killed {
+                            0 if legacy => Response::Error(Error::NoSuchClient),
+                            _ if legacy => Response::Ok,
+                            killed => Response::Integer(killed as i64),
+                        }),
+                )
+            }
+            Command::ClientPause(timeout, mode) => {
+                self.clients.do_send(PauseClients { timeout, mode });
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::ClientUnpause => {
+                self.clients.do_send(UnpauseClients);
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::CommandInfo(names) => Box::new(future::ok(commands::info(&names))),
+            Command::CommandCount => Box::new(future::ok(Response::Integer(
+                commands::COMMANDS.len() as i64,
+            ))),
+            Command::CommandDocs(names) => Box::new(future::ok(commands::docs(&names))),
+            Command::CommandList(filter) => Box::new(future::ok(commands::list(filter.as_ref()))),
+            Command::CommandGetKeys(args) => {
+                Box::new(future::ok(match commands::get_keys(&args) {
+                    Ok(keys) => Response::Array(keys.into_iter().map(Response::Bulk).collect()),
+                    Err(err) => Response::Error(err),
+                }))
+            }
+            Command::Info(sections) => {
+                let stats = self.stats.clone();
+                let config = self.config.clone();
+                Box::new(self.writer.send(Info).from_err().map(move |storage| {
+                    let info = info::render(&sections, &stats, &storage, &config.server());
+                    Response::Verbatim(Bytes::from_static(b"txt"), info)
+                }))
+            }
+            Command::ConfigGet(patterns) => {
+                let bulk = |value: &str| Response::Bulk(Bytes::from(value));
+                Box::new(future::ok(Response::Map(
+                    self.config
+                        .get(&patterns)
+                        .into_iter()
+                        .map(|(name, value)| (bulk(name), bulk(&value)))
+                        .collect(),
+                )))
+            }
+            Command::ConfigSet(values) => Box::new(future::ok(match self.config.set(values) {
+                Ok(()) => Response::Ok,
+                Err(err) => Response::Error(err),
+            })),
+            Command::ConfigResetStat => {
+                self.stats.reset();
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::ConfigRewrite => Box::new(future::ok(match self.config.rewrite() {
+                Ok(()) => Response::Ok,
+                Err(err) => Response::Error(err),
+            })),
+            cmd => unreachable!("{:?} doesn't run on the connection", cmd),
+        }
+    }
+
+    /// Execute the commands queued in a transaction
+    ///
+    /// The storage operations are executed atomically by the `Writer`. The commands that run on the
+    /// connection (like `INFO` or `CLIENT SETNAME`) follow, unless the transaction was aborted
+    /// because a watched key was modified.
+    fn exec(
+        &mut self,
+        operations: Vec<Operation>,
+        caching: Option<bool>,
+    ) -> Box<ActorFuture<Item = Response, Error = ConnectionError, Actor = Self>> {
+        let watcher = if self.watching {
+            Some(self.client_id)
+        } else {
+            None
+        };
+        self.watching = false;
+        let tracked: Vec<_> = operations
+            .iter()
+            .map(|operation| self.track(&operation.command, caching))
+            .collect();
+
+        let (local, stored): (Vec<_>, Vec<_>) = operations
+            .into_iter()
+            .enumerate()
+            .partition(|(_, operation)| operation.command.runs_on_connection());
+        let (indices, operations): (Vec<_>, Vec<_>) = stored.into_iter().unzip();
+        let writer = self.writer.clone();
+        let executed = future::join_all(tracked).from_err().and_then(move |_| {
+            writer
+                .send(Transaction {
+                    operations,
+                    watcher,
+                })
+                .then(|x| -> Result<_, ConnectionError> { Ok(x??) })
+        });
+
+        Box::new(executed.into_actor(self).and_then(
+            move |responses: Option<Vec<Response>>, actor, _| {
+                let local: Vec<_> = if responses.is_some() {
+                    local
+                        .into_iter()
+                        .map(|(index, operation)| {
+                            actor
+                                .run_on_connection(operation.command)
+                                .map(move |response| (index, response))
+                        })
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                future::join_all(local)
+                    .into_actor(actor)
+                    .map(move |local, _, _| match responses {
+                        Some(responses) => {
+                            let mut responses: Vec<_> =
+                                indices.into_iter().zip(responses).chain(local).collect();
+                            responses.sort_by_key(|&(index, _)| index);
+                            Response::Array(
+                                responses
+                                    .into_iter()
+                                    .map(|(_, response)| response)
+                                    .collect(),
+                            )
+                        }
+                        None => Response::Nil,
+                    })
+            },
+        ))
+    }
+
+    /// Send the response to a command once it is ready, unless the client turned replies off, and
+    /// record it in the command statistics
+    fn respond(
+        &mut self,
+        response: Box<ActorFuture<Item = Response, Error = ConnectionError, Actor = Self>>,
+        name: &'static str,
+        started: Instant,
+        ctx: &mut Context<Self>,
+    ) {
+        self.update_info();
+        let silent = self.silent;
+        let stats = self.stats.clone();
         ctx.wait(
-            tx.send(Response::Error(err))
+            response
+                .map(move |response, actor, ctx| {
+                    let failed = if let Response::Error(_) = response {
+                        true
+                    } else {
+                        false
+                    };
+                    stats.record_result(name, started.elapsed(), failed);
+                    if !silent {
+                        actor.send_all(vec![response], ctx);
+                    }
+                })
+                .map_err(|err, _, _| error!("Error while executing command: {}", err)),
+        );
+    }
+
+    /// Send a final response, and close the connection once it has been sent
+    ///
+    /// Commands received in the meantime are ignored.
+    fn close_with(&mut self, response: Response, ctx: &mut Context<Self>) {
+        self.closing = true;
+        self.send_all(vec![response], ctx);
+    }
+
+    fn send_error(&mut self, err: Error, ctx: &mut Context<Self>) {
+        self.reply(vec![Response::Error(err)], ctx);
+    }
+
+    /// Decide whether the replies to the next command are sent, given the mode it switches to if
+    /// it is a `CLIENT REPLY`
+    ///
+    /// A `SKIP` only silences the command that follows it, whether that command runs or fails.
+    fn begin_reply(&mut self, switch: Option<ReplyMode>) {
+        self.silent = match switch {
+            Some(mode) => {
+                self.reply_mode = mode;
+                mode != ReplyMode::On
+            }
+            None if self.reply_mode == ReplyMode::Skip => {
+                self.reply_mode = ReplyMode::On;
+                true
+            }
+            None => self.reply_mode == ReplyMode::Off,
+        };
+    }
+
+    /// Send the replies to a command, unless the client turned them off (with `CLIENT REPLY`)
+    fn reply(&mut self, responses: Vec<Response>, ctx: &mut Context<Self>) {
+        self.update_info();
+        if !self.silent {
+            self.send_all(responses, ctx);
+        }
+    }
+
+    /// Close the connection if it has been idle for longer than the configured `timeout`
+    ///
+    /// Subscribers are never closed. Neither are clients held by `CLIENT PAUSE`, as nothing else
+    /// runs while they wait.
+    fn close_if_idle(&mut self, ctx: &mut Context<Self>) {
+        let timeout = self.config.server().timeout;
+        if timeout == 0 || self.is_subscriber() {
+            return;
+        }
+        let idle = self.info.lock().unwrap().last_interaction.elapsed();
+        if idle >= Duration::from_secs(timeout) {
+            slog_info!(self.logger, "Closing idle connection");
+            ctx.stop();
+        }
+    }
+
+    /// Handle a command once clients are no longer paused
+    fn hold(&mut self, cmd: Command, ctx: &mut Context<Self>) {
+        slog_debug!(self.logger, "Holding command while clients are paused");
+        ctx.wait(self.clients.send(WaitUnpaused).into_actor(self).then(
+            move |result, actor, ctx| {
+                if let Err(err) = result {
+                    error!("Error while waiting for clients to be unpaused: {}", err);
+                }
+                <Self as StreamHandler<Command, ConnectionError>>::handle(actor, cmd, ctx);
+                actix::fut::ok(())
+            },
+        ));
+    }
+
+    /// Queue several responses to be sent in order
+    ///
+    /// Responses are sent in the background, so a client that is slow to read them doesn't hold
+    /// up the connection. Clients that let too many of them queue up are disconnected.
+    fn send_all(&mut self, responses: Vec<Response>, ctx: &mut Context<Self>) {
+        self.queued_responses += responses.len();
+        self.queued_bytes += responses.iter().map(Response::size).sum::<usize>();
+        self.pending.extend(responses);
+        if self.check_output_buffer(ctx) {
+            self.flush(ctx);
+        }
+    }
+
+    /// Start sending the pending responses, unless the sink is still busy (in which case they
+    /// are sent once it is done)
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let tx = match self.tx.take() {
+            Some(tx) => tx,
+            None => return,
+        };
+        let responses = std::mem::replace(&mut self.pending, Vec::new());
+        let (count, bytes) = (responses.len(), self.queued_bytes);
+        ctx.spawn(
+            tx.send_all(stream::iter_ok::<_, ConnectionError>(responses))
                 .into_actor(self)
-                .map(|sink, actor, _ctx| {
+                .map(move |(sink, _), actor, ctx| {
                     actor.tx = Some(sink);
+                    actor.queued_responses -= count;
+                    actor.queued_bytes -= bytes;
+                    if !actor.check_output_buffer(ctx) {
+                        return;
+                    }
+                    actor.update_info();
+                    if actor.closing && actor.pending.is_empty() {
+                        ctx.stop();
+                    } else {
+                        actor.flush(ctx);
+                    }
                 })
-                .map_err(|err, _, _| error!("Error while sending error response: {}", err)),
+                .map_err(|err, _, ctx| {
+                    error!("Error while sending responses: {}", err);
+                    ctx.stop();
+                }),
         );
     }
+
+    /// Disconnect the client if its queued responses exceed the `client-output-buffer-limit` of
+    /// its class, returning whether it is still connected
+    fn check_output_buffer(&mut self, ctx: &mut Context<Self>) -> bool {
+        let limits = self.config.server().client_output_buffer_limit;
+        let limit = if self.is_subscriber() {
+            limits.pubsub
+        } else {
+            limits.normal
+        };
+        let bytes = self.queued_bytes as u64;
+        let exceeded = if limit.hard_limit > 0 && bytes >= limit.hard_limit {
+            true
+        } else if limit.soft_limit > 0 && bytes >= limit.soft_limit {
+            let since = *self.soft_limit_since.get_or_insert_with(Instant::now);
+            since.elapsed() >= Duration::from_secs(limit.soft_seconds)
+        } else {
+            self.soft_limit_since = None;
+            false
+        };
+        if exceeded {
+            self.update_info();
+            let info = self.info.lock().unwrap().render();
+            slog_warn!(
+                self.logger,
+                "Client {} closed for overcoming of output buffer limits",
+                info.trim_end()
+            );
+            self.pending.clear();
+            ctx.stop();
+        }
+        !exceeded
+    }
 }
 
-impl<R, T> StreamHandler<Operation, ConnectionError> for Connection<R, T>
+impl<R, T> StreamHandler<Command, ConnectionError> for Connection<R, T>
 where
     R: Stream<Item = Command, Error = ConnectionError> + 'static,
     T: Sink<SinkItem = Response, SinkError = ConnectionError> + 'static,
 {
     fn error(&mut self, err: ConnectionError, ctx: &mut Self::Context) -> Running {
-        match err {
-            ConnectionError::CommandDecoding(DecodeError::UnexpectedNumberOfArguments)
-            | ConnectionError::CommandDecoding(DecodeError::InvalidArgument) => {
-                self.send_error(Error::Syntax, ctx);
-                Running::Continue
+        if let ConnectionError::CommandDecoding(ref err) = err {
+            if let Some(msg) = err.protocol_error() {
+                slog_info!(self.logger, "Protocol error: {}", msg);
+                self.close_with(Response::Error(Error::Protocol(msg)), ctx);
+                return Running::Continue;
             }
+        }
+
+        let response = match err {
+            ConnectionError::CommandDecoding(DecodeError::UnexpectedNumberOfArguments)
+            | ConnectionError::CommandDecoding(DecodeError::InvalidArgument) => Error::Syntax,
+            ConnectionError::CommandDecoding(DecodeError::InvalidInteger(_)) => Error::NotAnInteger,
             ConnectionError::CommandDecoding(DecodeError::UnrecognizedCommand(cmd)) => {
-                self.send_error(Error::UnknownCommand(cmd), ctx);
-                Running::Continue
+                Error::UnknownCommand(cmd)
+            }
+            ConnectionError::CommandDecoding(DecodeError::WrongArity(cmd)) => {
+                self.stats.record_rejected(cmd);
+                Error::WrongArity(cmd)
+            }
+            ConnectionError::CommandDecoding(DecodeError::UnknownSubcommand(cmd, subcommand)) => {
+                Error::UnknownSubcommand(cmd, subcommand)
             }
             _ => {
                 slog_error!(self.logger, "Connection error: {}", err);
-                Running::Stop
+                return Running::Stop;
             }
+        };
+
+        self.begin_reply(None);
+        if let Some(ref mut transaction) = self.transaction {
+            transaction.failed = true;
         }
+        self.send_error(response, ctx);
+        Running::Continue
     }
 
-    fn handle(&mut self, operation: Operation, ctx: &mut Self::Context) {
-        let cmd = operation.command;
+    fn handle(&mut self, cmd: Command, ctx: &mut Self::Context) {
+        if self.closing {
+            return;
+        }
         slog_debug!(self.logger, "Processing command {:?}", cmd);
 
+        // Commands queued in a transaction only write once they are executed
+        let writes = match (&cmd, &self.transaction) {
+            (Command::Exec, Some(transaction)) => transaction
+                .operations
+                .iter()
+                .any(|operation| operation.command.writes()),
+            (cmd, None) => cmd.writes(),
+            (_, Some(_)) => false,
+        };
+        let unpause = if let Command::ClientUnpause = cmd {
+            true
+        } else {
+            false
+        };
+        if !unpause && self.pause.holds(writes) {
+            return self.hold(cmd, ctx);
+        }
+
+        self.begin_reply(if let Command::ClientReply(mode) = cmd {
+            Some(mode)
+        } else {
+            None
+        });
+
+        let caching = self.caching.take();
+
+        let name = cmd.spec().name;
+        {
+            let mut info = self.info.lock().unwrap();
+            info.last_interaction = Instant::now();
+            info.last_command = name;
+        }
+        if self.is_subscriber() && !self.is_resp3() && !cmd.allowed_in_subscriber_mode() {
+            self.stats.record_rejected(name);
+            return self.send_error(Error::NotAllowedInSubscriberMode, ctx);
+        }
+        if !cmd.allowed_without_auth() && !self.is_authenticated() {
+            self.stats.record_rejected(name);
+            return self.send_error(Error::NoAuth, ctx);
+        }
+        let started = Instant::now();
+        self.stats.record_call(name);
+
         let response: Box<Future<Item = Response, Error = ConnectionError>> = match cmd {
+            Command::Quit => return self.close_with(Response::Ok, ctx),
+            Command::Multi if self.transaction.is_some() => {
+                Box::new(future::ok(Response::Error(Error::NestedMulti)))
+            }
+            Command::Watch(_) if self.transaction.is_some() => {
+                if let Some(ref mut transaction) = self.transaction {
+                    transaction.failed = true;
+                }
+                Box::new(future::ok(Response::Error(Error::WatchInsideMulti)))
+            }
+            ref cmd if self.transaction.is_some() && !cmd.allowed_in_transactions() => {
+                if let Some(ref mut transaction) = self.transaction {
+                    transaction.failed = true;
+                }
+                Box::new(future::ok(Response::Error(Error::NotAllowedInTransaction)))
+            }
+            Command::Subscribe(channels) => {
+                return self.subscribe(SubscriptionKind::Channel, channels, ctx);
+            }
+            Command::PSubscribe(patterns) => {
+                return self.subscribe(SubscriptionKind::Pattern, patterns, ctx);
+            }
+            Command::Unsubscribe(channels) => {
+                let responses = self.unsubscribe(SubscriptionKind::Channel, channels);
+                return self.reply(responses, ctx);
+            }
+            Command::PUnsubscribe(patterns) => {
+                let responses = self.unsubscribe(SubscriptionKind::Pattern, patterns);
+                return self.reply(responses, ctx);
+            }
+            Command::SSubscribe(channels) => {
+                return self.subscribe(SubscriptionKind::Shard, channels, ctx);
+            }
+            Command::SUnsubscribe(channels) => {
+                let responses = self.unsubscribe(SubscriptionKind::Shard, channels);
+                return self.reply(responses, ctx);
+            }
+            Command::Auth(username, password) => Box::new(future::ok(
+                match self.authenticate(username.as_ref(), &password) {
+                    Ok(()) => Response::Ok,
+                    Err(err) => Response::Error(err),
+                },
+            )),
+            Command::Hello(version, auth, name) => {
+                return self.hello(version, auth, name, ctx);
+            }
+            Command::ClientTracking(false, _) => {
+                self.stop_tracking();
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::ClientTracking(true, options) => {
+                return self.enable_tracking(options, ctx);
+            }
+            Command::ClientCaching(enabled) => match self.tracking {
+                Some(TrackingMode::OptIn) if enabled => {
+                    self.caching = Some(true);
+                    Box::new(future::ok(Response::Ok))
+                }
+                Some(TrackingMode::OptOut) if !enabled => {
+                    self.caching = Some(false);
+                    Box::new(future::ok(Response::Ok))
+                }
+                _ => Box::new(future::ok(Response::Error(Error::CachingWithoutOptInOut))),
+            },
+            Command::ClientReply(_) => Box::new(future::ok(Response::Ok)),
+            Command::Ping(ref msg) if self.is_subscriber() && !self.is_resp3() => {
+                Box::new(future::ok(Response::Array(vec![
+                    Response::Bulk(Bytes::from_static(b"pong")),
+                    Response::Bulk(msg.clone().unwrap_or_else(Bytes::new)),
+                ])))
+            }
+            Command::Multi => {
+                self.transaction = Some(QueuedTransaction {
+                    db: self.db,
+                    ..Default::default()
+                });
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::Discard => match self.transaction.take() {
+                Some(_) => {
+                    self.unwatch();
+                    Box::new(future::ok(Response::Ok))
+                }
+                None => Box::new(future::ok(Response::Error(Error::DiscardWithoutMulti))),
+            },
+            Command::Exec => match self.transaction.take() {
+                None => Box::new(future::ok(Response::Error(Error::ExecWithoutMulti))),
+                Some(QueuedTransaction { failed: true, .. }) => {
+                    self.unwatch();
+                    Box::new(future::ok(Response::Error(Error::ExecAbort)))
+                }
+                Some(QueuedTransaction { operations, db, .. }) => {
+                    self.db = db;
+                    let response = self.exec(operations, caching);
+                    return self.respond(response, name, started, ctx);
+                }
+            },
+            Command::Watch(keys) => {
+                self.watching = true;
+                Box::new(
+                    self.writer
+                        .send(Watch {
+                            client: self.client_id,
+                            db: self.db,
+                            keys,
+                        })
+                        .map(|()| Response::Ok)
+                        .from_err(),
+                )
+            }
+            Command::Unwatch if self.transaction.is_none() => {
+                self.unwatch();
+                Box::new(future::ok(Response::Ok))
+            }
+            _ if self.transaction.is_some() => {
+                let databases = self.config.server().databases;
+                let transaction = self.transaction.as_mut().expect("No transaction");
+                let db = transaction.db;
+                if let Command::Select(selected) = cmd {
+                    if selected < databases {
+                        transaction.db = selected;
+                    }
+                }
+                transaction.operations.push(Operation::new(cmd, db));
+                Box::new(future::ok(Response::Queued))
+            }
+            _ if cmd.runs_on_connection() => self.run_on_connection(cmd),
+            Command::Select(db) if db < self.config.server().databases => {
+                self.db = db;
+                Box::new(future::ok(Response::Ok))
+            }
+            Command::Select(_) => Box::new(future::ok(Response::Error(Error::DbIndexOutOfRange))),
+            Command::Publish(..)
+            | Command::SPublish(..)
+            | Command::PubSubShardChannels(_)
+            | Command::PubSubShardNumSub(_)
+            | Command::PubSubChannels(_)
+            | Command::PubSubNumSub(_)
+            | Command::PubSubNumPat => Box::new(self.broker.send(pubsub::Query(cmd)).from_err()),
             _ if cmd.is_async() && cmd.writes() => Box::new(
                 self.writer
-                    .try_send(Operation::from(cmd))
+                    .try_send(Operation::new(cmd, self.db))
                     .map(|()| Response::Ok)
                     .map_err(ConnectionError::from)
                     .into_future(),
             ),
             _ if cmd.is_async() => Box::new(
                 self.reader
-                    .try_send(Operation::from(cmd))
+                    .try_send(Operation::new(cmd, self.db))
                     .map(|()| Response::Ok)
                     .map_err(ConnectionError::from)
                     .into_future(),
             ),
-            _ if cmd.writes() => Box::new(self.writer.send(Operation::from(cmd)).then(|x| Ok(x??))),
-            _ => Box::new(self.reader.send(Operation::from(cmd)).then(|x| Ok(x??))),
+            _ if cmd.writes() => Box::new(
+                self.writer
+                    .send(Operation::new(cmd, self.db))
+                    .then(|x| Ok(x??)),
+            ),
+            _ => {
+                let tracked = self.track(&cmd, caching);
+                let reader = self.reader.clone();
+                let operation = Operation::new(cmd, self.db);
+                Box::new(
+                    tracked
+                        .from_err()
+                        .and_then(move |()| reader.send(operation).then(|x| Ok(x??))),
+                )
+            }
         };
 
-        let tx = self.tx.take().expect("Sink not available");
-        ctx.wait(
-            response
-                .and_then(|msg| tx.send(msg))
-                .into_actor(self)
-                .map(|sink, actor, _ctx| {
-                    actor.tx = Some(sink);
-                })
-                .map_err(|err, _, _| error!("Error while executing command: {}", err)),
-        );
+        let response = Box::new(response.into_actor(self));
+        self.respond(response, name, started, ctx);
+    }
+}
+
+impl<R, T> Handler<Push> for Connection<R, T>
+where
+    R: Stream<Item = Command, Error = ConnectionError> + 'static,
+    T: Sink<SinkItem = Response, SinkError = ConnectionError> + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, Push(response): Push, ctx: &mut Context<Self>) {
+        self.send_all(vec![response], ctx);
+    }
+}
+
+impl<R, T> Handler<Kill> for Connection<R, T>
+where
+    R: Stream<Item = Command, Error = ConnectionError> + 'static,
+    T: Sink<SinkItem = Response, SinkError = ConnectionError> + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Kill, ctx: &mut Context<Self>) {
+        slog_info!(self.logger, "Killed by CLIENT KILL");
+        ctx.stop();
+    }
+}
+
+impl<R, T> Handler<Invalidate> for Connection<R, T>
+where
+    R: Stream<Item = Command, Error = ConnectionError> + 'static,
+    T: Sink<SinkItem = Response, SinkError = ConnectionError> + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, invalidate: Invalidate, ctx: &mut Context<Self>) {
+        let keys = match invalidate.keys {
+            Some(keys) => Response::Array(keys.into_iter().map(Response::Bulk).collect()),
+            None => Response::Nil,
+        };
+        let channel = Bytes::from_static(INVALIDATE_CHANNEL);
+        let response = if self.is_resp3() {
+            Response::Push(vec![
+                Response::Bulk(Bytes::from_static(b"invalidate")),
+                keys,
+            ])
+        } else if invalidate.redirected && self.channels.contains(&channel) {
+            // RESP2 connections can only receive invalidations through a redirect, as messages on
+            // the invalidation channel
+            Response::Push(vec![
+                Response::Bulk(Bytes::from_static(b"message")),
+                Response::Bulk(channel),
+                keys,
+            ])
+        } else {
+            return;
+        };
+        self.send_all(vec![response], ctx);
     }
 }
 
@@ -176,13 +1299,52 @@ where
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         slog_info!(self.logger, "Opening connection");
-        Self::add_stream(
-            self.rx
-                .take()
-                .expect("Stream already consumed")
-                .map(Operation::from),
-            ctx,
+        // Counting first, so that clients connecting at the same time can't both take the last slot
+        let connected = self.stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+        if connected >= self.config.server().maxclients {
+            slog_info!(
+                self.logger,
+                "Rejecting connection: max number of clients reached"
+            );
+            Stats::decr(&self.stats.connected_clients);
+            Stats::incr(&self.stats.rejected_connections);
+            self.rejected = true;
+            return self.close_with(Response::Error(Error::MaxClients), ctx);
+        }
+        Stats::incr(&self.stats.connections_received);
+
+        ctx.wait(
+            self.clients
+                .send(Register {
+                    id: self.id,
+                    info: self.info.clone(),
+                    invalidate: ctx.address().recipient(),
+                    kill: ctx.address().recipient(),
+                })
+                .into_actor(self)
+                .map(|pause, actor, _ctx| actor.pause = pause)
+                .map_err(|err, _, _| error!("Error while registering client: {}", err)),
         );
+        Self::add_stream(self.rx.take().expect("Stream already consumed"), ctx);
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |actor, ctx| {
+            if actor.check_output_buffer(ctx) {
+                actor.close_if_idle(ctx);
+            }
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        slog_info!(self.logger, "Closing connection");
+        if self.rejected {
+            return;
+        }
+        self.unwatch();
+        self.unsubscribe(SubscriptionKind::Channel, Vec::new());
+        self.unsubscribe(SubscriptionKind::Pattern, Vec::new());
+        self.unsubscribe(SubscriptionKind::Shard, Vec::new());
+        self.stop_tracking();
+        self.clients.do_send(Deregister { id: self.id });
+        Stats::decr(&self.stats.connected_clients);
     }
 }
 
@@ -192,6 +1354,9 @@ pub fn accept<S: 'static, D: 'static>(
     codec: D,
     reader: Addr<Reader>,
     writer: Addr<Writer>,
+    config: Arc<LiveConfiguration>,
+    stats: Arc<Stats>,
+    accepted: Accepted,
 ) -> impl IntoFuture<Item = (), Error = ConnectionError>
 where
     S: AsyncRead + AsyncWrite,
@@ -199,9 +1364,914 @@ where
     D: Encoder<Item = Response, Error = ConnectionError>,
 {
     let (tx, rx) = codec.framed(stream).split();
-    let conn = Connection::new(rx, tx, reader, writer);
+    // Error replies are counted as they are sent, wherever they originate
+    let errors = stats.clone();
+    let tx = tx.with(move |response: Response| {
+        errors.record_errors(&response);
+        Ok::<_, ConnectionError>(response)
+    });
+    let conn = Connection::new(rx, tx, reader, writer, config, stats, accepted);
 
     conn.start();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc as std_mpsc;
+    use std::thread::{self, JoinHandle};
+
+    use bytes::BytesMut;
+    use futures::sync::mpsc;
+    use futures::{Async, AsyncSink, Poll, StartSend};
+
+    use crate::codecs::NegotiatedStreamCodec;
+    use crate::server::{OutputBufferLimit, OutputBufferLimits, ServerConfiguration};
+
+    /// How long to wait for a response (or the connection to close) before giving up
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    /// How long to wait before concluding that no response is coming (yet)
+    const NO_RESPONSE: Duration = Duration::from_millis(200);
+
+    /// A sink handing a connection's responses to the test thread
+    struct Responses {
+        sender: std_mpsc::Sender<Response>,
+        /// Whether the client never reads any responses, so they stay queued on the server
+        stalled: bool,
+    }
+    impl Sink for Responses {
+        type SinkItem = Response;
+        type SinkError = ConnectionError;
+
+        fn start_send(&mut self, response: Response) -> StartSend<Response, ConnectionError> {
+            if self.stalled {
+                return Ok(AsyncSink::NotReady(response));
+            }
+            // The test may have stopped listening already
+            let _ = self.sender.send(response);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), ConnectionError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Connect a client, given its end of the command channel, its response sink and its protocol
+    /// version
+    struct Connect(
+        mpsc::UnboundedReceiver<Result<Command, ConnectionError>>,
+        Responses,
+        ProtocolVersion,
+    );
+    impl Message for Connect {
+        type Result = ();
+    }
+
+    /// Stop the server
+    struct Stop;
+    impl Message for Stop {
+        type Result = ();
+    }
+
+    /// An actor connecting clients on the server's thread
+    struct Acceptor {
+        /// The port the next client connects from (so each client has an address of its own)
+        port: u16,
+        reader: Addr<Reader>,
+        writer: Addr<Writer>,
+        config: Arc<LiveConfiguration>,
+        stats: Arc<Stats>,
+    }
+    impl Actor for Acceptor {
+        type Context = Context<Self>;
+    }
+    impl Handler<Connect> for Acceptor {
+        type Result = ();
+
+        fn handle(&mut self, connect: Connect, _ctx: &mut Context<Self>) {
+            let Connect(commands, responses, protocol) = connect;
+            let laddr: SocketAddr = "127.0.0.1:6379".parse().unwrap();
+            let addr = SocketAddr::new(laddr.ip(), self.port);
+            self.port += 1;
+            let accepted = Accepted {
+                addr,
+                laddr,
+                protocol,
+                query_buffer: QueryBuffer::default(),
+            };
+            let commands = commands.then(|command| command.expect("Command channel failed"));
+            Connection::new(
+                commands,
+                responses,
+                self.reader.clone(),
+                self.writer.clone(),
+                self.config.clone(),
+                self.stats.clone(),
+                accepted,
+            )
+            .start();
+        }
+    }
+    impl Handler<Stop> for Acceptor {
+        type Result = ();
+
+        fn handle(&mut self, _: Stop, _ctx: &mut Context<Self>) {
+            System::current().stop();
+        }
+    }
+
+    /// A server running on a thread of its own
+    struct TestServer {
+        acceptor: Addr<Acceptor>,
+        thread: Option<JoinHandle<()>>,
+    }
+    impl TestServer {
+        fn start(config: ServerConfiguration) -> Self {
+            let (started, acceptor) = std_mpsc::channel();
+            let thread = thread::spawn(move || {
+                System::run(move || {
+                    let stats = Arc::new(Stats::default());
+                    let broker = Broker::default();
+                    let writer = Writer::new(config.databases)
+                        .with_subscriptions(broker.subscriptions())
+                        .with_stats(stats.clone())
+                        .start();
+                    System::current().registry().set(writer.clone());
+                    System::current().registry().set(broker.start());
+                    let acceptor = Acceptor {
+                        port: 50000,
+                        reader: Reader::from_registry(),
+                        writer,
+                        config: Arc::new(LiveConfiguration::new(config)),
+                        stats,
+                    };
+                    let _ = started.send(acceptor.start());
+                });
+            });
+            let acceptor = acceptor.recv_timeout(TIMEOUT).expect("Server didn't start");
+            TestServer {
+                acceptor,
+                thread: Some(thread),
+            }
+        }
+
+        fn connect(&self) -> Client {
+            self.connect_client(false)
+        }
+
+        /// Connect a client that never reads its responses
+        fn connect_stalled(&self) -> Client {
+            self.connect_client(true)
+        }
+
+        fn connect_client(&self, stalled: bool) -> Client {
+            let (commands, receiver) = mpsc::unbounded();
+            let (sender, responses) = std_mpsc::channel();
+            let codec = NegotiatedStreamCodec::default();
+            let sink = Responses { sender, stalled };
+            self.acceptor
+                .do_send(Connect(receiver, sink, codec.version()));
+            Client {
+                commands,
+                responses,
+                codec,
+            }
+        }
+    }
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.acceptor.do_send(Stop);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// A client connected to a `TestServer`
+    struct Client {
+        commands: mpsc::UnboundedSender<Result<Command, ConnectionError>>,
+        responses: std_mpsc::Receiver<Response>,
+        /// The codec the connection would encode its responses with
+        codec: NegotiatedStreamCodec<ConnectionError>,
+    }
+    impl Client {
+        /// Send a command (given as its name and arguments) as the codec would decode it
+        fn send(&self, args: &[&str]) {
+            let args: Vec<Bytes> = args.iter().map(|&x| Bytes::from(x)).collect();
+            let command = commands::parse(&args).map_err(ConnectionError::from);
+            self.commands
+                .unbounded_send(command)
+                .expect("Connection closed");
+        }
+
+        fn recv(&self) -> Response {
+            self.responses
+                .recv_timeout(TIMEOUT)
+                .expect("No response received")
+        }
+
+        /// The next response, if it arrives in a short while
+        fn try_recv(&self) -> Option<Response> {
+            self.responses.recv_timeout(NO_RESPONSE).ok()
+        }
+
+        /// Send a command and wait for its response
+        fn call(&self, args: &[&str]) -> Response {
+            self.send(args);
+            self.recv()
+        }
+
+        /// Encode a response the way it would be sent to the client
+        fn encode(&mut self, response: Response) -> BytesMut {
+            let mut buffer = BytesMut::new();
+            self.codec
+                .encode(response, &mut buffer)
+                .expect("Failed to encode response");
+            buffer
+        }
+
+        /// Whether the server closes the connection (skipping any remaining responses) in time
+        fn is_closed(&self) -> bool {
+            loop {
+                match self.responses.recv_timeout(TIMEOUT) {
+                    Ok(_) => {}
+                    Err(std_mpsc::RecvTimeoutError::Disconnected) => return true,
+                    Err(std_mpsc::RecvTimeoutError::Timeout) => return false,
+                }
+            }
+        }
+    }
+
+    fn bulk(data: &str) -> Response {
+        Response::Bulk(Bytes::from(data))
+    }
+
+    /// A pub/sub push message (or confirmation) with the given fields
+    fn push(kind: &str, fields: &[&str], count: Option<i64>) -> Response {
+        let mut response = vec![bulk(kind)];
+        response.extend(fields.iter().map(|&field| bulk(field)));
+        response.extend(count.map(Response::Integer));
+        Response::Push(response)
+    }
+
+    /// The invalidation pushed to a RESP3 client for the given keys
+    fn invalidation(keys: &[&str]) -> Response {
+        Response::Push(vec![
+            bulk("invalidate"),
+            Response::Array(keys.iter().map(|&key| bulk(key)).collect()),
+        ])
+    }
+
+    #[test]
+    fn transactions_are_executed_on_exec() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(client.call(&["MULTI"]), Response::Ok);
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Queued);
+        assert_eq!(client.call(&["GET", "key"]), Response::Queued);
+        assert_eq!(other.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(
+            client.call(&["EXEC"]),
+            Response::Array(vec![Response::Ok, bulk("value")])
+        );
+        assert_eq!(other.call(&["GET", "key"]), bulk("value"));
+        assert_eq!(
+            client.call(&["EXEC"]),
+            Response::Error(Error::ExecWithoutMulti)
+        );
+    }
+
+    #[test]
+    fn connection_commands_are_queued_in_transactions() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+
+        assert_eq!(client.call(&["MULTI"]), Response::Ok);
+        assert_eq!(
+            client.call(&["CLIENT", "SETNAME", "name"]),
+            Response::Queued
+        );
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Queued);
+        assert_eq!(client.call(&["INFO", "keyspace"]), Response::Queued);
+        assert_eq!(client.call(&["CONFIG", "GET", "timeout"]), Response::Queued);
+        assert_eq!(client.call(&["COMMAND", "COUNT"]), Response::Queued);
+        let responses = match client.call(&["EXEC"]) {
+            Response::Array(responses) => responses,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        assert_eq!(&responses[..2], &[Response::Ok, Response::Ok]);
+        match &responses[2..] {
+            [Response::Verbatim(..), Response::Map(..), Response::Integer(_)] => {}
+            _ => panic!("Unexpected responses: {:?}", responses),
+        }
+        assert_eq!(client.call(&["CLIENT", "GETNAME"]), bulk("name"));
+    }
+
+    #[test]
+    fn discarded_transactions_are_not_executed() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+
+        assert_eq!(client.call(&["MULTI"]), Response::Ok);
+        assert_eq!(client.call(&["MULTI"]), Response::Error(Error::NestedMulti));
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Queued);
+        assert_eq!(client.call(&["DISCARD"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(
+            client.call(&["DISCARD"]),
+            Response::Error(Error::DiscardWithoutMulti)
+        );
+    }
+
+    #[test]
+    fn errors_while_queueing_abort_the_transaction() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+
+        assert_eq!(client.call(&["MULTI"]), Response::Ok);
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Queued);
+        match client.call(&["GET"]) {
+            Response::Error(Error::WrongArity(_)) => {}
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        assert_eq!(client.call(&["EXEC"]), Response::Error(Error::ExecAbort));
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+    }
+
+    #[test]
+    fn modified_watched_keys_abort_the_transaction() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(client.call(&["WATCH", "key"]), Response::Ok);
+        assert_eq!(other.call(&["SET", "key", "other"]), Response::Ok);
+        assert_eq!(client.call(&["MULTI"]), Response::Ok);
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Queued);
+        assert_eq!(client.call(&["EXEC"]), Response::Nil);
+        assert_eq!(client.call(&["GET", "key"]), bulk("other"));
+    }
+
+    #[test]
+    fn databases_are_selected_moved_and_swapped() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Ok);
+        assert_eq!(client.call(&["SELECT", "1"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(
+            client.call(&["SELECT", "16"]),
+            Response::Error(Error::DbIndexOutOfRange)
+        );
+        assert_eq!(client.call(&["SET", "other", "value"]), Response::Ok);
+
+        assert_eq!(other.call(&["MOVE", "key", "1"]), Response::Integer(1));
+        assert_eq!(other.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(client.call(&["GET", "key"]), bulk("value"));
+
+        assert_eq!(other.call(&["SWAPDB", "0", "1"]), Response::Ok);
+        assert_eq!(other.call(&["GET", "other"]), bulk("value"));
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(
+            client.call(&["SWAPDB", "0", "16"]),
+            Response::Error(Error::DbIndexOutOfRange)
+        );
+    }
+
+    #[test]
+    fn write_pauses_only_hold_writes_until_unpaused() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let first = server.connect();
+        let second = server.connect();
+
+        assert_eq!(
+            client.call(&["CLIENT", "PAUSE", "10000", "WRITE"]),
+            Response::Ok
+        );
+        // The pause is in effect once the registry handles the next request
+        client.call(&["CLIENT", "LIST"]);
+        assert_eq!(first.call(&["GET", "key"]), Response::Nil);
+        first.send(&["SET", "key", "first"]);
+        second.send(&["SET", "key", "second"]);
+        assert_eq!(first.try_recv(), None);
+        assert_eq!(second.try_recv(), None);
+
+        assert_eq!(client.call(&["CLIENT", "UNPAUSE"]), Response::Ok);
+        assert_eq!(first.recv(), Response::Ok);
+        assert_eq!(second.recv(), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), bulk("second"));
+    }
+
+    #[test]
+    fn pauses_of_all_commands_end_with_their_timeout() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        let paused = Instant::now();
+        assert_eq!(
+            client.call(&["CLIENT", "PAUSE", "500", "ALL"]),
+            Response::Ok
+        );
+        other.send(&["GET", "key"]);
+        assert_eq!(other.try_recv(), None);
+        assert_eq!(other.recv(), Response::Nil);
+        assert!(paused.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn unpausing_is_never_held() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(
+            client.call(&["CLIENT", "PAUSE", "10000", "ALL"]),
+            Response::Ok
+        );
+        other.send(&["GET", "key"]);
+        assert_eq!(other.try_recv(), None);
+        assert_eq!(client.call(&["CLIENT", "UNPAUSE"]), Response::Ok);
+        assert_eq!(other.recv(), Response::Nil);
+    }
+
+    #[test]
+    fn replies_can_be_turned_off() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+
+        client.send(&["CLIENT", "REPLY", "OFF"]);
+        client.send(&["SET", "key", "value"]);
+        client.send(&["GET"]);
+        assert_eq!(client.try_recv(), None);
+        assert_eq!(client.call(&["CLIENT", "REPLY", "ON"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), bulk("value"));
+    }
+
+    #[test]
+    fn skipped_replies_include_errors() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+
+        client.send(&["CLIENT", "REPLY", "SKIP"]);
+        client.send(&["SET", "key", "value"]);
+        assert_eq!(client.call(&["GET", "key"]), bulk("value"));
+
+        client.send(&["CLIENT", "REPLY", "SKIP"]);
+        client.send(&["NOSUCHCOMMAND"]);
+        assert_eq!(client.call(&["GET", "key"]), bulk("value"));
+        match client.call(&["NOSUCHCOMMAND"]) {
+            Response::Error(Error::UnknownCommand(_)) => {}
+            response => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn hello_switches_the_protocol_of_the_connection() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let mut client = server.connect();
+
+        let response = client.call(&["GET", "key"]);
+        assert_eq!(&client.encode(response)[..], b"$-1\r\n");
+        match client.call(&["HELLO", "3"]) {
+            Response::Map(ref entries) => {
+                assert!(entries.contains(&(bulk("proto"), Response::Integer(3))))
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        let response = client.call(&["GET", "key"]);
+        assert_eq!(&client.encode(response)[..], b"_\r\n");
+    }
+
+    #[test]
+    fn hello_rejects_unknown_protocol_versions() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let mut client = server.connect();
+
+        for version in &["1", "4", "300"] {
+            assert_eq!(
+                client.call(&["HELLO", version]),
+                Response::Error(Error::UnsupportedProtocol)
+            );
+        }
+        let response = client.call(&["GET", "key"]);
+        assert_eq!(&client.encode(response)[..], b"$-1\r\n");
+    }
+
+    #[test]
+    fn read_keys_are_invalidated_once() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        client.call(&["HELLO", "3"]);
+        assert_eq!(client.call(&["CLIENT", "TRACKING", "ON"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(other.call(&["SET", "key", "value"]), Response::Ok);
+        assert_eq!(client.recv(), invalidation(&["key"]));
+
+        assert_eq!(other.call(&["SET", "key", "other"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), bulk("other"));
+        assert_eq!(other.call(&["DEL", "key"]), Response::Integer(1));
+        assert_eq!(client.recv(), invalidation(&["key"]));
+    }
+
+    #[test]
+    fn opt_in_clients_only_track_keys_they_ask_for() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        client.call(&["HELLO", "3"]);
+        assert_eq!(
+            client.call(&["CLIENT", "TRACKING", "ON", "OPTIN"]),
+            Response::Ok
+        );
+        assert_eq!(client.call(&["GET", "uncached"]), Response::Nil);
+        assert_eq!(client.call(&["CLIENT", "CACHING", "YES"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "cached"]), Response::Nil);
+        assert_eq!(other.call(&["SET", "uncached", "value"]), Response::Ok);
+        assert_eq!(other.call(&["SET", "cached", "value"]), Response::Ok);
+        assert_eq!(client.recv(), invalidation(&["cached"]));
+    }
+
+    #[test]
+    fn redirected_invalidations_follow_the_protocol_of_the_target() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let resp2 = server.connect();
+        let resp3 = server.connect();
+
+        let id = |target: &Client| match target.call(&["CLIENT", "ID"]) {
+            Response::Integer(id) => id.to_string(),
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        resp3.call(&["HELLO", "3"]);
+        assert_eq!(
+            resp2.call(&["SUBSCRIBE", "__redis__:invalidate"]),
+            push("subscribe", &["__redis__:invalidate"], Some(1))
+        );
+
+        let target = id(&resp3);
+        assert_eq!(
+            client.call(&["CLIENT", "TRACKING", "ON", "REDIRECT", &target]),
+            Response::Ok
+        );
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(client.call(&["SET", "key", "value"]), Response::Ok);
+        assert_eq!(resp3.recv(), invalidation(&["key"]));
+
+        let target = id(&resp2);
+        assert_eq!(
+            client.call(&["CLIENT", "TRACKING", "ON", "REDIRECT", &target]),
+            Response::Ok
+        );
+        assert_eq!(client.call(&["GET", "key"]), bulk("value"));
+        assert_eq!(client.call(&["DEL", "key"]), Response::Integer(1));
+        assert_eq!(
+            resp2.recv(),
+            Response::Push(vec![
+                bulk("message"),
+                bulk("__redis__:invalidate"),
+                Response::Array(vec![bulk("key")]),
+            ])
+        );
+        assert_eq!(resp3.try_recv(), None);
+    }
+
+    #[test]
+    fn sharded_channels_can_span_slots() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        client.send(&["SSUBSCRIBE", "foo", "bar"]);
+        assert_eq!(client.recv(), push("ssubscribe", &["foo"], Some(1)));
+        assert_eq!(client.recv(), push("ssubscribe", &["bar"], Some(2)));
+        assert_eq!(
+            other.call(&["SPUBLISH", "bar", "message"]),
+            Response::Integer(1)
+        );
+        assert_eq!(client.recv(), push("smessage", &["bar", "message"], None));
+
+        client.send(&["SUNSUBSCRIBE", "foo", "bar"]);
+        assert_eq!(client.recv(), push("sunsubscribe", &["foo"], Some(1)));
+        assert_eq!(client.recv(), push("sunsubscribe", &["bar"], Some(0)));
+    }
+
+    #[test]
+    fn messages_are_pushed_to_subscribers() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(
+            client.call(&["SUBSCRIBE", "channel"]),
+            push("subscribe", &["channel"], Some(1))
+        );
+        assert_eq!(
+            other.call(&["PUBLISH", "channel", "message"]),
+            Response::Integer(1)
+        );
+        assert_eq!(
+            client.recv(),
+            push("message", &["channel", "message"], None)
+        );
+
+        assert_eq!(
+            client.call(&["PSUBSCRIBE", "chan*"]),
+            push("psubscribe", &["chan*"], Some(2))
+        );
+        let script = "return redis.call('publish', KEYS[1], ARGV[1])";
+        assert_eq!(
+            other.call(&["EVAL", script, "1", "channel", "scripted"]),
+            Response::Integer(2)
+        );
+        assert_eq!(
+            client.recv(),
+            push("message", &["channel", "scripted"], None)
+        );
+        assert_eq!(
+            client.recv(),
+            push("pmessage", &["chan*", "channel", "scripted"], None)
+        );
+    }
+
+    #[test]
+    fn subscribers_only_accept_pubsub_commands() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(
+            client.call(&["SUBSCRIBE", "channel"]),
+            push("subscribe", &["channel"], Some(1))
+        );
+        assert_eq!(
+            client.call(&["GET", "key"]),
+            Response::Error(Error::NotAllowedInSubscriberMode)
+        );
+        assert_eq!(
+            client.call(&["UNSUBSCRIBE"]),
+            push("unsubscribe", &["channel"], Some(0))
+        );
+        assert_eq!(
+            other.call(&["PUBLISH", "channel", "message"]),
+            Response::Integer(0)
+        );
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+    }
+
+    #[test]
+    fn idle_clients_are_closed() {
+        let server = TestServer::start(ServerConfiguration {
+            timeout: 1,
+            ..Default::default()
+        });
+        let client = server.connect();
+        let subscriber = server.connect();
+
+        assert_eq!(client.call(&["PING"]), Response::Pong);
+        assert_eq!(
+            subscriber.call(&["SUBSCRIBE", "channel"]),
+            push("subscribe", &["channel"], Some(1))
+        );
+        assert!(client.is_closed());
+        assert!(!subscriber.is_closed());
+    }
+
+    #[test]
+    fn clients_over_the_limit_are_rejected() {
+        let server = TestServer::start(ServerConfiguration {
+            maxclients: 1,
+            ..Default::default()
+        });
+        let client = server.connect();
+        assert_eq!(client.call(&["PING"]), Response::Pong);
+
+        let rejected = server.connect();
+        assert_eq!(rejected.recv(), Response::Error(Error::MaxClients));
+        assert!(rejected.is_closed());
+        assert_eq!(client.call(&["PING"]), Response::Pong);
+
+        assert_eq!(client.call(&["QUIT"]), Response::Ok);
+        assert!(client.is_closed());
+        let client = server.connect();
+        assert_eq!(client.call(&["PING"]), Response::Pong);
+    }
+
+    /// A configuration requiring clients to authenticate with the password `secret`
+    fn with_requirepass() -> ServerConfiguration {
+        ServerConfiguration {
+            requirepass: Some("secret".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn clients_must_authenticate_with_the_password() {
+        let server = TestServer::start(with_requirepass());
+        let client = server.connect();
+
+        assert_eq!(client.call(&["GET", "key"]), Response::Error(Error::NoAuth));
+        assert_eq!(
+            client.call(&["AUTH", "wrong"]),
+            Response::Error(Error::WrongPass)
+        );
+        assert_eq!(client.call(&["GET", "key"]), Response::Error(Error::NoAuth));
+        assert_eq!(
+            client.call(&["AUTH", "other", "secret"]),
+            Response::Error(Error::WrongPass)
+        );
+        assert_eq!(client.call(&["AUTH", "secret"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+        assert_eq!(client.call(&["AUTH", "default", "secret"]), Response::Ok);
+    }
+
+    #[test]
+    fn auth_without_requirepass_only_accepts_the_default_user() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+
+        assert_eq!(
+            client.call(&["AUTH", "secret"]),
+            Response::Error(Error::AuthNotConfigured)
+        );
+        assert_eq!(client.call(&["AUTH", "default", "anything"]), Response::Ok);
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+    }
+
+    #[test]
+    fn hello_can_authenticate() {
+        let server = TestServer::start(with_requirepass());
+        let client = server.connect();
+
+        assert_eq!(
+            client.call(&["HELLO", "3"]),
+            Response::Error(Error::HelloNoAuth)
+        );
+        assert_eq!(
+            client.call(&["HELLO", "3", "AUTH", "default", "wrong"]),
+            Response::Error(Error::WrongPass)
+        );
+        match client.call(&["HELLO", "3", "AUTH", "default", "secret"]) {
+            Response::Map(_) => {}
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        assert_eq!(client.call(&["GET", "key"]), Response::Nil);
+    }
+
+    #[test]
+    fn connection_commands_run_without_authentication() {
+        let server = TestServer::start(with_requirepass());
+        let client = server.connect();
+
+        assert_eq!(client.call(&["PING"]), Response::Error(Error::NoAuth));
+        assert_eq!(client.call(&["HELLO"]), Response::Error(Error::HelloNoAuth));
+        assert_eq!(
+            client.call(&["AUTH", "wrong"]),
+            Response::Error(Error::WrongPass)
+        );
+        assert_eq!(client.call(&["QUIT"]), Response::Ok);
+        assert!(client.is_closed());
+    }
+
+    /// A configuration with the given output buffer limit for subscribers
+    fn with_pubsub_limit(pubsub: OutputBufferLimit) -> ServerConfiguration {
+        ServerConfiguration {
+            client_output_buffer_limit: OutputBufferLimits {
+                pubsub,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Subscribe a client that doesn't read its responses, making sure it is subscribed by
+    /// publishing (small) messages until it receives them
+    fn subscribe_stalled(subscriber: &Client, publisher: &Client) {
+        subscriber.send(&["SUBSCRIBE", "channel"]);
+        let subscribed = (0..100)
+            .any(|_| publisher.call(&["PUBLISH", "channel", "hello"]) == Response::Integer(1));
+        assert!(subscribed, "The client didn't subscribe");
+    }
+
+    #[test]
+    fn subscribers_over_the_hard_output_buffer_limit_are_disconnected() {
+        let server = TestServer::start(with_pubsub_limit(OutputBufferLimit {
+            hard_limit: 1024,
+            ..Default::default()
+        }));
+        let subscriber = server.connect_stalled();
+        let client = server.connect_stalled();
+        let publisher = server.connect();
+
+        subscribe_stalled(&subscriber, &publisher);
+        let message = "x".repeat(256);
+        assert_eq!(publisher.call(&["SET", "key", &message]), Response::Ok);
+        for _ in 0..8 {
+            publisher.call(&["PUBLISH", "channel", &message]);
+            client.send(&["GET", "key"]);
+        }
+        assert!(subscriber.is_closed());
+        // The limit only applies to subscribers
+        assert!(!client.is_closed());
+    }
+
+    #[test]
+    fn subscribers_over_the_soft_output_buffer_limit_for_too_long_are_disconnected() {
+        let server = TestServer::start(with_pubsub_limit(OutputBufferLimit {
+            soft_limit: 1024,
+            soft_seconds: 1,
+            ..Default::default()
+        }));
+        let subscriber = server.connect_stalled();
+        let publisher = server.connect();
+
+        subscribe_stalled(&subscriber, &publisher);
+        let published = Instant::now();
+        let message = "x".repeat(2048);
+        assert_eq!(
+            publisher.call(&["PUBLISH", "channel", &message]),
+            Response::Integer(1)
+        );
+        assert!(subscriber.is_closed());
+        assert!(published.elapsed() >= Duration::from_secs(1));
+    }
+
+    /// A field of the `CLIENT INFO` of a client
+    fn client_info(client: &Client, field: &str) -> String {
+        let info = match client.call(&["CLIENT", "INFO"]) {
+            Response::Verbatim(_, info) => info,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        let prefix = format!("{}=", field);
+        String::from_utf8_lossy(&info)
+            .split_whitespace()
+            .find(|x| x.starts_with(&prefix))
+            .map(|x| x[prefix.len()..].to_owned())
+            .expect("Missing field")
+    }
+
+    #[test]
+    fn clients_can_be_killed_by_id_or_address() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let by_id = server.connect();
+        let by_addr = server.connect();
+
+        let id = client_info(&by_id, "id");
+        assert_eq!(
+            client.call(&["CLIENT", "KILL", "ID", &id]),
+            Response::Integer(1)
+        );
+        assert!(by_id.is_closed());
+
+        let addr = client_info(&by_addr, "addr");
+        assert_ne!(addr, client_info(&client, "addr"));
+        assert_eq!(
+            client.call(&["CLIENT", "KILL", "ADDR", &addr]),
+            Response::Integer(1)
+        );
+        assert!(by_addr.is_closed());
+        assert_eq!(
+            client.call(&["CLIENT", "KILL", &addr]),
+            Response::Error(Error::NoSuchClient)
+        );
+        assert_eq!(client.call(&["PING"]), Response::Pong);
+    }
+
+    #[test]
+    fn client_names_are_listed() {
+        let server = TestServer::start(ServerConfiguration::default());
+        let client = server.connect();
+        let other = server.connect();
+
+        assert_eq!(
+            client.call(&["CLIENT", "SETNAME", "my name"]),
+            Response::Error(Error::InvalidClientName)
+        );
+        assert_eq!(client.call(&["CLIENT", "GETNAME"]), Response::Nil);
+        assert_eq!(client.call(&["CLIENT", "SETNAME", "name"]), Response::Ok);
+        assert_eq!(client.call(&["CLIENT", "GETNAME"]), bulk("name"));
+
+        let list = match other.call(&["CLIENT", "LIST"]) {
+            Response::Verbatim(_, list) => list,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        let list = String::from_utf8_lossy(&list).into_owned();
+        assert_eq!(list.lines().count(), 2);
+        assert!(list
+            .lines()
+            .any(|line| line.split_whitespace().any(|field| field == "name=name")));
+    }
+}
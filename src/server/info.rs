@@ -0,0 +1,297 @@
+//! The sections reported by `INFO`
+
+use std::fmt::Write;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::server::ServerConfiguration;
+use crate::stats::Stats;
+use crate::storage::writer::StorageInfo;
+
+/// All sections (and their titles), in the order they are reported
+const SECTIONS: &[(&str, &str)] = &[
+    ("server", "Server"),
+    ("clients", "Clients"),
+    ("memory", "Memory"),
+    ("persistence", "Persistence"),
+    ("stats", "Stats"),
+    ("replication", "Replication"),
+    ("cpu", "CPU"),
+    ("commandstats", "Commandstats"),
+    ("errorstats", "Errorstats"),
+    ("keyspace", "Keyspace"),
+];
+
+/// The sections that are only reported when asked for explicitly (or by `all`)
+const EXTRA_SECTIONS: &[&str] = &["commandstats"];
+
+/// The Redis version evredis claims compatibility with
+const REDIS_VERSION: &str = "7.0.0";
+
+/// The number of clock ticks per second (`/proc/<pid>/stat` reports CPU time in ticks)
+const CLOCK_TICKS: f64 = 100.0;
+
+/// Whether a section was requested (all default sections if none are given)
+fn requested(sections: &[Bytes], section: &str) -> bool {
+    if sections.is_empty() {
+        return !EXTRA_SECTIONS.contains(&section);
+    }
+    sections.iter().any(|requested| {
+        requested.eq_ignore_ascii_case(section.as_bytes())
+            || requested.eq_ignore_ascii_case(b"all")
+            || requested.eq_ignore_ascii_case(b"everything")
+            || (requested.eq_ignore_ascii_case(b"default") && !EXTRA_SECTIONS.contains(&section))
+    })
+}
+
+/// Render the requested sections as `INFO` reports them
+pub fn render(
+    sections: &[Bytes],
+    stats: &Stats,
+    storage: &StorageInfo,
+    config: &ServerConfiguration,
+) -> Bytes {
+    let mut out = String::new();
+    for &(section, title) in SECTIONS {
+        if !requested(sections, section) {
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        write!(out, "# {}\r\n", title).unwrap();
+
+        let fields = match section {
+            "server" => server(stats, config),
+            "clients" => clients(stats, storage, config),
+            "memory" => memory(config),
+            "persistence" => persistence(),
+            "stats" => self::stats(stats, storage),
+            "replication" => replication(),
+            "cpu" => cpu(),
+            "commandstats" => commandstats(stats),
+            "errorstats" => errorstats(stats),
+            "keyspace" => keyspace(storage),
+            _ => unreachable!(),
+        };
+        for (field, value) in fields {
+            write!(out, "{}:{}\r\n", field, value).unwrap();
+        }
+    }
+    Bytes::from(out)
+}
+
+fn server(stats: &Stats, config: &ServerConfiguration) -> Vec<(String, String)> {
+    let uptime = stats.started.elapsed().as_secs();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() * 1_000_000 + u64::from(x.subsec_micros()))
+        .unwrap_or(0);
+    let port = config.listen_on.first().map(|x| x.port()).unwrap_or(0);
+    vec![
+        field("redis_version", REDIS_VERSION),
+        field("evredis_version", env!("CARGO_PKG_VERSION")),
+        field("redis_mode", "standalone"),
+        field("os", std::env::consts::OS),
+        field("arch_bits", 8 * std::mem::size_of::<usize>()),
+        field("process_id", std::process::id()),
+        field("run_id", &stats.run_id),
+        field("tcp_port", port),
+        field("server_time_usec", now),
+        field("uptime_in_seconds", uptime),
+        field("uptime_in_days", uptime / 86400),
+    ]
+}
+
+fn clients(
+    stats: &Stats,
+    storage: &StorageInfo,
+    config: &ServerConfiguration,
+) -> Vec<(String, String)> {
+    vec![
+        field("connected_clients", Stats::get(&stats.connected_clients)),
+        field("maxclients", config.maxclients),
+        field("blocked_clients", 0),
+        field("tracking_clients", storage.tracking_clients),
+    ]
+}
+
+fn memory(config: &ServerConfiguration) -> Vec<(String, String)> {
+    let rss = proc_status("VmRSS").unwrap_or(0);
+    let peak = proc_status("VmHWM").unwrap_or(rss);
+    vec![
+        field("used_memory_rss", rss),
+        field("used_memory_rss_human", human(rss)),
+        field("used_memory_peak", peak),
+        field("used_memory_peak_human", human(peak)),
+        field("maxmemory", config.maxmemory),
+        field("maxmemory_human", human(config.maxmemory)),
+        field("maxmemory_policy", "noeviction"),
+    ]
+}
+
+/// evredis doesn't persist any data (besides the function libraries)
+fn persistence() -> Vec<(String, String)> {
+    vec![
+        field("loading", 0),
+        field("rdb_changes_since_last_save", 0),
+        field("rdb_bgsave_in_progress", 0),
+        field("rdb_last_save_time", 0),
+        field("aof_enabled", 0),
+        field("aof_rewrite_in_progress", 0),
+    ]
+}
+
+fn stats(stats: &Stats, storage: &StorageInfo) -> Vec<(String, String)> {
+    vec![
+        field(
+            "total_connections_received",
+            Stats::get(&stats.connections_received),
+        ),
+        field(
+            "rejected_connections",
+            Stats::get(&stats.rejected_connections),
+        ),
+        field(
+            "total_commands_processed",
+            Stats::get(&stats.commands_processed),
+        ),
+        field("expired_keys", Stats::get(&stats.expired_keys)),
+        field("evicted_keys", 0),
+        field("keyspace_hits", Stats::get(&stats.keyspace_hits)),
+        field("keyspace_misses", Stats::get(&stats.keyspace_misses)),
+        field("pubsub_channels", storage.pubsub_channels),
+        field("pubsub_patterns", storage.pubsub_patterns),
+        field("pubsub_shardchannels", storage.pubsub_shard_channels),
+        field("total_error_replies", Stats::get(&stats.error_replies)),
+    ]
+}
+
+/// evredis doesn't support replication
+fn replication() -> Vec<(String, String)> {
+    vec![field("role", "master"), field("connected_slaves", 0)]
+}
+
+fn cpu() -> Vec<(String, String)> {
+    let (user, system) = proc_cpu_times().unwrap_or((0.0, 0.0));
+    vec![
+        field("used_cpu_sys", format!("{:.6}", system)),
+        field("used_cpu_user", format!("{:.6}", user)),
+    ]
+}
+
+fn commandstats(stats: &Stats) -> Vec<(String, String)> {
+    stats
+        .commands()
+        .into_iter()
+        .map(|(name, stats)| {
+            let usec_per_call = if stats.calls > 0 {
+                stats.usec as f64 / stats.calls as f64
+            } else {
+                0.0
+            };
+            field(
+                &format!("cmdstat_{}", name),
+                format!(
+                    "calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                    stats.calls,
+                    stats.usec,
+                    usec_per_call,
+                    stats.rejected_calls,
+                    stats.failed_calls
+                ),
+            )
+        })
+        .collect()
+}
+
+fn errorstats(stats: &Stats) -> Vec<(String, String)> {
+    stats
+        .errors()
+        .into_iter()
+        .map(|(code, count)| field(&format!("errorstat_{}", code), format!("count={}", count)))
+        .collect()
+}
+
+/// Only databases holding keys are reported
+fn keyspace(storage: &StorageInfo) -> Vec<(String, String)> {
+    storage
+        .keyspace
+        .iter()
+        .enumerate()
+        .filter(|(_, info)| info.keys > 0)
+        .map(|(db, info)| {
+            let avg_ttl = info.avg_ttl.as_secs() * 1000 + u64::from(info.avg_ttl.subsec_millis());
+            field(
+                &format!("db{}", db),
+                format!(
+                    "keys={},expires={},avg_ttl={}",
+                    info.keys, info.expires, avg_ttl
+                ),
+            )
+        })
+        .collect()
+}
+
+fn field(name: &str, value: impl ToString) -> (String, String) {
+    (name.to_owned(), value.to_string())
+}
+
+/// Format a number of bytes with a binary unit suffix (like `1.50M`)
+fn human(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, units[0])
+    } else {
+        format!("{:.2}{}", value, units[unit])
+    }
+}
+
+/// Read a memory field (in bytes) from `/proc/self/status`
+fn proc_status(name: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find(|line| line.split(':').next() == Some(name))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Read the user and system CPU time (in seconds) from `/proc/self/stat`
+fn proc_cpu_times() -> Option<(f64, f64)> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces, so fields are counted from its closing parenthesis
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let user: f64 = fields.get(11)?.parse().ok()?;
+    let system: f64 = fields.get(12)?.parse().ok()?;
+    Some((user / CLOCK_TICKS, system / CLOCK_TICKS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_sections_exclude_commandstats() {
+        assert!(requested(&[], "keyspace"));
+        assert!(!requested(&[], "commandstats"));
+        assert!(requested(&[Bytes::from("ALL")], "commandstats"));
+        assert!(requested(&[Bytes::from("Keyspace")], "keyspace"));
+        assert!(!requested(&[Bytes::from("keyspace")], "server"));
+    }
+
+    #[test]
+    fn sizes_are_humanized() {
+        assert_eq!(human(512), "512B");
+        assert_eq!(human(1536), "1.50K");
+        assert_eq!(human(3 * 1024 * 1024), "3.00M");
+    }
+}
@@ -0,0 +1,70 @@
+//! Reloading the configuration on `SIGHUP`
+
+use slog::{slog_error, slog_info, slog_warn};
+use slog_scope::{error, info, warn};
+
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::prelude::*;
+
+use crate::server::{RunningServer, ServerConfiguration};
+use crate::utils::configuration::ConfigurationError;
+use crate::utils::logging::LoggingConfiguration;
+
+/// A function loading the server and logging configuration
+pub type Loader =
+    Box<Fn() -> Result<(ServerConfiguration, LoggingConfiguration), ConfigurationError>>;
+
+/// An actor that reloads the configuration of a running server when the process receives
+/// `SIGHUP`
+///
+/// If the configuration can't be loaded or is invalid, the server keeps its current settings.
+pub struct Reloader {
+    server: RunningServer,
+    load: Loader,
+}
+impl Reloader {
+    /// Construct a reloader for a running server, using a function to load its configuration
+    pub fn new(server: RunningServer, load: Loader) -> Self {
+        Reloader { server, load }
+    }
+
+    fn reload(&mut self) {
+        info!("Reloading configuration");
+        let (server, logging) = match (self.load)() {
+            Ok(config) => config,
+            Err(err) => {
+                error!(
+                    "Failed to load configuration, keeping the current one: {}",
+                    err
+                );
+                return;
+            }
+        };
+        match self.server.reload(server, logging) {
+            Ok(restart) => {
+                for setting in restart {
+                    warn!("Changing {} requires a restart", setting);
+                }
+                info!("Reloaded configuration");
+            }
+            Err(err) => error!("Invalid configuration, keeping the current one: {}", err),
+        }
+    }
+}
+impl Actor for Reloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ProcessSignals::from_registry().do_send(Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<Signal> for Reloader {
+    type Result = ();
+
+    fn handle(&mut self, signal: Signal, _ctx: &mut Context<Self>) {
+        if let SignalType::Hup = signal.0 {
+            self.reload();
+        }
+    }
+}
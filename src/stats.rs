@@ -0,0 +1,187 @@
+//! Server-wide counters (reported by `INFO`)
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::protocol::Response;
+
+/// The calls of a single command
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommandStats {
+    /// The number of times the command was executed
+    pub calls: u64,
+    /// The total execution time (in microseconds)
+    pub usec: u64,
+    /// The number of calls rejected before execution (e.g. because of a wrong number of
+    /// arguments)
+    pub rejected_calls: u64,
+    /// The number of calls that replied with an error
+    pub failed_calls: u64,
+}
+
+/// Counters shared by connections and storage actors
+///
+/// All counters except the gauges (`connected_clients`) are cleared by `reset`.
+#[derive(Debug)]
+pub struct Stats {
+    /// A random identifier of this server process
+    pub run_id: String,
+    /// When the server was started
+    pub started: Instant,
+    /// The number of open connections
+    pub connected_clients: AtomicUsize,
+    /// The number of connections accepted
+    pub connections_received: AtomicUsize,
+    /// The number of connections rejected because of `maxclients`
+    pub rejected_connections: AtomicUsize,
+    /// The number of commands processed
+    pub commands_processed: AtomicUsize,
+    /// The number of successful key lookups
+    pub keyspace_hits: AtomicUsize,
+    /// The number of failed key lookups
+    pub keyspace_misses: AtomicUsize,
+    /// The number of keys removed because they expired
+    pub expired_keys: AtomicUsize,
+    /// The number of error replies sent
+    pub error_replies: AtomicUsize,
+    /// The calls of each command (by full name)
+    commands: Mutex<HashMap<&'static str, CommandStats>>,
+    /// The number of error replies sent, by error code
+    errors: Mutex<HashMap<String, u64>>,
+}
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            run_id: format!(
+                "{}{}",
+                Uuid::new_v4().to_simple(),
+                Uuid::new_v4().to_simple()
+            )[..40]
+                .to_owned(),
+            started: Instant::now(),
+            connected_clients: AtomicUsize::new(0),
+            connections_received: AtomicUsize::new(0),
+            rejected_connections: AtomicUsize::new(0),
+            commands_processed: AtomicUsize::new(0),
+            keyspace_hits: AtomicUsize::new(0),
+            keyspace_misses: AtomicUsize::new(0),
+            expired_keys: AtomicUsize::new(0),
+            error_replies: AtomicUsize::new(0),
+            commands: Mutex::new(HashMap::new()),
+            errors: Mutex::new(HashMap::new()),
+        }
+    }
+}
+impl Stats {
+    /// Increment a counter
+    pub fn incr(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrement a gauge
+    pub fn decr(counter: &AtomicUsize) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Read a counter
+    pub fn get(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Count a call of a command
+    pub fn record_call(&self, name: &'static str) {
+        Stats::incr(&self.commands_processed);
+        self.commands.lock().unwrap().entry(name).or_default().calls += 1;
+    }
+
+    /// Record how long a call of a command took, and whether it failed
+    pub fn record_result(&self, name: &'static str, duration: Duration, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(name).or_default();
+        stats.usec += duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros());
+        if failed {
+            stats.failed_calls += 1;
+        }
+    }
+
+    /// Count a call of a command that was rejected before execution
+    pub fn record_rejected(&self, name: &'static str) {
+        self.commands
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .rejected_calls += 1;
+    }
+
+    /// Count the error replies in a response (including those nested in arrays, such as the
+    /// replies to `EXEC`)
+    pub fn record_errors(&self, response: &Response) {
+        match response {
+            Response::Error(err) => {
+                let message = err.message();
+                let code = message.split(|&x| x == b' ').next().unwrap_or(&[]);
+                Stats::incr(&self.error_replies);
+                *self
+                    .errors
+                    .lock()
+                    .unwrap()
+                    .entry(String::from_utf8_lossy(code).into_owned())
+                    .or_insert(0) += 1;
+            }
+            Response::Array(responses) => {
+                for response in responses {
+                    self.record_errors(response);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The calls of all commands that have been called, sorted by name
+    pub fn commands(&self) -> Vec<(&'static str, CommandStats)> {
+        let mut commands: Vec<_> = self
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&name, stats)| (name, stats.clone()))
+            .collect();
+        commands.sort_by_key(|&(name, _)| name);
+        commands
+    }
+
+    /// The number of error replies by error code, sorted by code
+    pub fn errors(&self) -> Vec<(String, u64)> {
+        let mut errors: Vec<_> = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(code, &count)| (code.clone(), count))
+            .collect();
+        errors.sort();
+        errors
+    }
+
+    /// Clear all counters (for `CONFIG RESETSTAT`)
+    pub fn reset(&self) {
+        for counter in &[
+            &self.connections_received,
+            &self.rejected_connections,
+            &self.commands_processed,
+            &self.keyspace_hits,
+            &self.keyspace_misses,
+            &self.expired_keys,
+            &self.error_replies,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.commands.lock().unwrap().clear();
+        self.errors.lock().unwrap().clear();
+    }
+}
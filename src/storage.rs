@@ -7,13 +7,19 @@ use quick_error::quick_error;
 
 use bytes::Bytes;
 use evmap::shallow_copy::ShallowCopy;
+use uuid::Uuid;
 
 use actix::clock;
 use actix_derive::Message;
 
 use crate::protocol::{Command, Error, Response};
+use crate::stats::Stats;
 
+pub mod functions;
+pub mod notifications;
 pub mod reader;
+pub mod scripting;
+pub mod tracking;
 pub mod writer;
 
 quick_error! {
@@ -31,6 +37,9 @@ quick_error! {
     }
 }
 
+/// The number of databases a `Writer` manages unless configured otherwise
+pub const DEFAULT_DATABASES: usize = 16;
+
 /// A storage key
 pub type Key = Bytes;
 
@@ -83,39 +92,111 @@ impl ShallowCopy for Item {
 #[rtype(result = "Result<Response, StorageError>")]
 pub struct Operation {
     pub command: Command,
+    pub db: usize,
+}
+impl Operation {
+    /// Create an operation that executes a command against the given database
+    pub fn new(command: Command, db: usize) -> Self {
+        Operation { command, db }
+    }
 }
 impl From<Command> for Operation {
     fn from(command: Command) -> Self {
-        Operation { command }
+        Operation { command, db: 0 }
+    }
+}
+
+/// A batch of storage operations that is executed atomically by the `Writer`
+///
+/// Readers will either observe all of the transaction's changes to a database, or none of them
+/// (a transaction spanning several databases is published one database at a time). If an
+/// operation fails, none of the changes are published. If a `watcher` is given and any of the
+/// keys it watches were modified, the transaction is aborted and `None` is returned.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Option<Vec<Response>>, StorageError>")]
+pub struct Transaction {
+    pub operations: Vec<Operation>,
+    pub watcher: Option<Uuid>,
+}
+
+/// A read-only view on the databases of a storage actor
+pub(crate) trait View {
+    /// The number of databases in this view
+    fn databases(&self) -> usize;
+
+    /// Get the non-expired item stored under a key
+    fn get(&self, db: usize, key: &Key) -> Option<Item>;
+
+    /// The counters keyspace hits and misses are recorded in
+    fn stats(&self) -> &Stats;
+
+    /// Get the non-expired item stored under a key on behalf of a client, counting the lookup
+    fn lookup(&self, db: usize, key: &Key) -> Option<Item> {
+        let item = self.get(db, key);
+        match item {
+            Some(_) => Stats::incr(&self.stats().keyspace_hits),
+            None => Stats::incr(&self.stats().keyspace_misses),
+        }
+        item
+    }
+
+    /// Check whether a non-expired item is stored under a key
+    fn contains_key(&self, db: usize, key: &Key) -> bool {
+        self.get(db, key).is_some()
+    }
+
+    /// Execute a read command against this view
+    fn read(&self, db: usize, command: Command) -> Result<Response, StorageError> {
+        if db >= self.databases() {
+            return Ok(Response::Error(Error::DbIndexOutOfRange));
+        }
+
+        Ok(match command {
+            Command::Ping(None) => Response::Pong,
+            Command::Ping(Some(msg)) => Response::Bulk(msg),
+            Command::Get(key) => match self.lookup(db, &key) {
+                Some(Item {
+                    value: Value::String(data),
+                    ..
+                }) => Response::Bulk(data),
+                Some(_) => Response::Error(Error::WrongType),
+                None => Response::Nil,
+            },
+            Command::Exists(keys) => Response::Integer(
+                keys.into_iter()
+                    .filter(|k| self.lookup(db, k).is_some())
+                    .count() as i64,
+            ),
+            // Commands the command table knows, but storage doesn't implement (yet)
+            ref cmd if cmd.reads() => Response::Error(Error::UnknownCommand(Bytes::from_static(
+                cmd.spec().name.as_bytes(),
+            ))),
+            _ => Err(StorageError::NoWriteAccess)?,
+        })
     }
 }
 
 mod ops {
     use super::*;
 
-    pub fn get_item(values: &[Item]) -> Item {
-        values[0].clone()
+    pub fn is_live(item: &Item) -> bool {
+        item.meta
+            .expiration
+            .map(|x| x > Instant::now())
+            .unwrap_or(true)
     }
 
-    pub fn get_metadata(values: &[Item]) -> Metadata {
-        match values[0] {
-            Item { ref meta, .. } => meta.clone(),
+    pub fn get_live_item(values: &[Item]) -> Option<Item> {
+        if is_live(&values[0]) {
+            Some(values[0].clone())
+        } else {
+            None
         }
     }
 
-    pub fn get_string_as_bulk(values: &[Item]) -> Response {
+    pub fn get_metadata(values: &[Item]) -> Metadata {
         match values[0] {
-            Item {
-                value: Value::String(ref data),
-                ref meta,
-            } => {
-                if meta.expiration.map(|x| x > Instant::now()).unwrap_or(true) {
-                    Response::Bulk(data.clone())
-                } else {
-                    Response::Nil
-                }
-            }
-            _ => Response::Error(Error::WrongType),
+            Item { ref meta, .. } => meta.clone(),
         }
     }
 }
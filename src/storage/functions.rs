@@ -0,0 +1,311 @@
+//! Registry of Lua function libraries (loaded with `FUNCTION LOAD`)
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use sha1::Sha1;
+
+use actix::prelude::*;
+use actix_derive::Message;
+
+use crate::protocol::{Error, Response, RestorePolicy};
+use crate::utils::glob;
+
+/// The magic bytes at the start of a `FUNCTION DUMP` payload
+const DUMP_MAGIC: &[u8] = b"EVRF";
+
+/// The version of the `FUNCTION DUMP` payload format
+const DUMP_VERSION: u8 = 1;
+
+/// The length of the checksum at the end of a `FUNCTION DUMP` payload
+const CHECKSUM_LENGTH: usize = 20;
+
+/// The flags a function may be registered with
+pub const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// Check whether a library or function name is valid (non-empty, alphanumeric or underscores)
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'_')
+}
+
+/// A function registered by a library
+#[derive(Debug, Clone)]
+pub struct FunctionInfo {
+    pub description: Option<Bytes>,
+    pub flags: Vec<String>,
+}
+impl FunctionInfo {
+    /// Whether the function promised not to write (and may thus run on the readers)
+    pub fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+/// A library of functions, along with the code it was loaded from
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub name: String,
+    pub engine: String,
+    pub code: Bytes,
+    pub functions: BTreeMap<String, FunctionInfo>,
+}
+
+/// All loaded libraries
+///
+/// The registry is owned by the `Writer` and shared with the readers, which compile their own
+/// copy of each library whenever the version changes.
+#[derive(Debug, Clone, Default)]
+pub struct Libraries {
+    libraries: BTreeMap<String, Library>,
+    version: u64,
+}
+impl Libraries {
+    /// A counter that changes whenever libraries are added or removed
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Iterate over all libraries, ordered by name
+    pub fn iter(&self) -> impl Iterator<Item = &Library> {
+        self.libraries.values()
+    }
+
+    /// Find a function by name
+    pub fn function(&self, name: &str) -> Option<&FunctionInfo> {
+        self.libraries
+            .values()
+            .filter_map(|library| library.functions.get(name))
+            .next()
+    }
+
+    /// Describe the libraries whose name matches a pattern, as returned by `FUNCTION LIST`
+    pub fn list(&self, pattern: Option<&[u8]>, with_code: bool) -> Response {
+        let bulk = |value: &str| Response::Bulk(Bytes::from(value));
+
+        Response::Array(
+            self.libraries
+                .values()
+                .filter(|library| {
+                    pattern
+                        .map(|pattern| glob::matches(pattern, library.name.as_bytes()))
+                        .unwrap_or(true)
+                })
+                .map(|library| {
+                    let functions = library
+                        .functions
+                        .iter()
+                        .map(|(name, function)| {
+                            Response::Map(vec![
+                                (bulk("name"), bulk(name)),
+                                (
+                                    bulk("description"),
+                                    function
+                                        .description
+                                        .clone()
+                                        .map(Response::Bulk)
+                                        .unwrap_or(Response::Nil),
+                                ),
+                                (
+                                    bulk("flags"),
+                                    Response::Set(
+                                        function.flags.iter().map(|flag| bulk(flag)).collect(),
+                                    ),
+                                ),
+                            ])
+                        })
+                        .collect();
+
+                    let mut fields = vec![
+                        (bulk("library_name"), bulk(&library.name)),
+                        (bulk("engine"), bulk(&library.engine)),
+                        (bulk("functions"), Response::Array(functions)),
+                    ];
+                    if with_code {
+                        fields.push((bulk("library_code"), Response::Bulk(library.code.clone())));
+                    }
+                    Response::Map(fields)
+                })
+                .collect(),
+        )
+    }
+
+    /// Add a library, optionally replacing an existing library with the same name
+    pub fn insert(&mut self, library: Library, replace: bool) -> Result<(), Error> {
+        if !replace && self.libraries.contains_key(&library.name) {
+            return Err(Error::Reply(Bytes::from(format!(
+                "ERR Library '{}' already exists",
+                library.name
+            ))));
+        }
+
+        for other in self.libraries.values() {
+            if other.name == library.name {
+                continue;
+            }
+            if let Some(name) = library
+                .functions
+                .keys()
+                .find(|name| other.functions.contains_key(*name))
+            {
+                return Err(Error::Reply(Bytes::from(format!(
+                    "ERR Function {} already exists",
+                    name
+                ))));
+            }
+        }
+
+        self.libraries.insert(library.name.clone(), library);
+        self.version += 1;
+        Ok(())
+    }
+
+    /// Remove a library by name, returning whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let removed = self.libraries.remove(name).is_some();
+        if removed {
+            self.version += 1;
+        }
+        removed
+    }
+
+    /// Remove all libraries
+    pub fn clear(&mut self) {
+        self.libraries.clear();
+        self.version += 1;
+    }
+
+    /// Add several libraries at once, either all of them or none
+    pub fn restore(&mut self, libraries: Vec<Library>, policy: RestorePolicy) -> Result<(), Error> {
+        let mut restored = match policy {
+            RestorePolicy::Flush => Libraries {
+                libraries: BTreeMap::new(),
+                version: self.version,
+            },
+            _ => self.clone(),
+        };
+        for library in libraries {
+            restored.insert(library, policy == RestorePolicy::Replace)?;
+        }
+        restored.version += 1;
+
+        *self = restored;
+        Ok(())
+    }
+
+    /// Serialize the code of all libraries
+    ///
+    /// The payload consists of a magic header and format version, the length-prefixed code of
+    /// each library, and a SHA1 checksum of everything before it.
+    pub fn dump(&self) -> Bytes {
+        let mut payload = BytesMut::with_capacity(
+            DUMP_MAGIC.len()
+                + 1
+                + self
+                    .libraries
+                    .values()
+                    .map(|library| 4 + library.code.len())
+                    .sum::<usize>()
+                + CHECKSUM_LENGTH,
+        );
+        payload.put(DUMP_MAGIC);
+        payload.put_u8(DUMP_VERSION);
+        for library in self.libraries.values() {
+            payload.put_u32_be(library.code.len() as u32);
+            payload.put(&library.code[..]);
+        }
+        let checksum = Sha1::from(&payload[..]).digest().bytes();
+        payload.put(&checksum[..]);
+        payload.freeze()
+    }
+}
+
+/// An actor that writes `FUNCTION DUMP` payloads to the functions file
+///
+/// It runs on a thread of its own (in a `SyncArbiter`), so the `Writer` isn't blocked by disk IO.
+pub struct FunctionsFile {
+    path: PathBuf,
+}
+impl FunctionsFile {
+    pub fn new(path: PathBuf) -> Self {
+        FunctionsFile { path }
+    }
+}
+impl Actor for FunctionsFile {
+    type Context = SyncContext<Self>;
+}
+
+/// Replace the contents of the functions file with a `FUNCTION DUMP` payload
+#[derive(Message)]
+#[rtype(result = "io::Result<()>")]
+pub struct Save(pub Bytes);
+
+impl Handler<Save> for FunctionsFile {
+    type Result = io::Result<()>;
+
+    fn handle(&mut self, save: Save, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, &save.0)
+    }
+}
+
+/// Extract the code of each library from a `FUNCTION DUMP` payload
+pub fn parse_dump(payload: &[u8]) -> Result<Vec<Bytes>, Error> {
+    let invalid = || {
+        Error::Reply(Bytes::from_static(
+            b"ERR payload version or checksum are wrong",
+        ))
+    };
+
+    let header = DUMP_MAGIC.len() + 1;
+    if payload.len() < header + CHECKSUM_LENGTH
+        || &payload[..DUMP_MAGIC.len()] != DUMP_MAGIC
+        || payload[DUMP_MAGIC.len()] != DUMP_VERSION
+    {
+        return Err(invalid());
+    }
+
+    let (body, checksum) = payload.split_at(payload.len() - CHECKSUM_LENGTH);
+    if Sha1::from(body).digest().bytes()[..] != checksum[..] {
+        return Err(invalid());
+    }
+
+    let mut codes = Vec::new();
+    let mut rest = &body[header..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(invalid());
+        }
+        let len = ((rest[0] as usize) << 24)
+            | ((rest[1] as usize) << 16)
+            | ((rest[2] as usize) << 8)
+            | (rest[3] as usize);
+        if rest.len() < 4 + len {
+            return Err(invalid());
+        }
+        codes.push(Bytes::from(&rest[4..4 + len]));
+        rest = &rest[4 + len..];
+    }
+    Ok(codes)
+}
+
+/// Read the code of all libraries from a file written by the `FunctionsFile` actor
+pub fn load_dump(path: &Path) -> io::Result<Vec<Bytes>> {
+    let payload = fs::read(path)?;
+    parse_dump(&payload).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid or corrupted functions file",
+        )
+    })
+}
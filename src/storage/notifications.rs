@@ -0,0 +1,152 @@
+//! Keyspace notifications (configured with `notify-keyspace-events`)
+
+use std::fmt;
+use std::str::FromStr;
+
+use quick_error::quick_error;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+quick_error! {
+    /// An error encountered while parsing keyspace event flags
+    #[derive(Debug)]
+    pub enum KeyspaceEventsError {
+        /// An unknown flag character
+        UnknownFlag(flag: char) {
+            display("Unknown keyspace event flag: {}", flag)
+        }
+    }
+}
+
+/// A class of keyspace events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    /// Type-independent commands (`DEL`, `EXPIRE`, `RENAME`, ...)
+    Generic,
+    /// String commands
+    String,
+    /// List commands
+    List,
+    /// Set commands
+    Set,
+    /// Hash commands
+    Hash,
+    /// Sorted set commands
+    SortedSet,
+    /// Keys expiring
+    Expired,
+    /// Keys being evicted
+    Evicted,
+    /// Stream commands
+    Stream,
+    /// Reads of missing keys
+    KeyMiss,
+    /// Keys being created
+    New,
+}
+impl EventClass {
+    fn bit(self) -> u16 {
+        1 << match self {
+            EventClass::Generic => 2,
+            EventClass::String => 3,
+            EventClass::List => 4,
+            EventClass::Set => 5,
+            EventClass::Hash => 6,
+            EventClass::SortedSet => 7,
+            EventClass::Expired => 8,
+            EventClass::Evicted => 9,
+            EventClass::Stream => 10,
+            EventClass::KeyMiss => 11,
+            EventClass::New => 12,
+        }
+    }
+}
+
+const KEYSPACE: u16 = 1;
+const KEYEVENT: u16 = 1 << 1;
+
+/// The flags characters, in the order they are displayed
+const FLAGS: &[(char, u16)] = &[
+    ('g', 1 << 2),
+    ('$', 1 << 3),
+    ('l', 1 << 4),
+    ('s', 1 << 5),
+    ('h', 1 << 6),
+    ('z', 1 << 7),
+    ('x', 1 << 8),
+    ('e', 1 << 9),
+    ('t', 1 << 10),
+    ('m', 1 << 11),
+    ('n', 1 << 12),
+];
+
+/// The classes included in the `A` alias (all but key misses and new keys)
+const ALL: u16 = 0b0111_1111_1100;
+
+/// The set of keyspace events that are published
+///
+/// Events are published on `__keyspace@<db>__:<key>` (with the event as message) if `K` is set,
+/// and on `__keyevent@<db>__:<event>` (with the key as message) if `E` is set.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeyspaceEvents(u16);
+impl KeyspaceEvents {
+    /// Whether events of the given class are published at all
+    pub fn includes(self, class: EventClass) -> bool {
+        self.0 & (KEYSPACE | KEYEVENT) != 0 && self.0 & class.bit() != 0
+    }
+
+    /// The channels and messages an event should be published with
+    pub fn messages(self, event: &str, db: usize, key: &Bytes) -> Vec<(Bytes, Bytes)> {
+        let mut messages = Vec::with_capacity(2);
+        if self.0 & KEYSPACE != 0 {
+            let prefix = format!("__keyspace@{}__:", db);
+            let mut channel = BytesMut::with_capacity(prefix.len() + key.len());
+            channel.put(prefix);
+            channel.put(&key[..]);
+            messages.push((channel.freeze(), Bytes::from(event)));
+        }
+        if self.0 & KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            messages.push((Bytes::from(channel), key.clone()));
+        }
+        messages
+    }
+}
+impl FromStr for KeyspaceEvents {
+    type Err = KeyspaceEventsError;
+
+    fn from_str(flags: &str) -> Result<Self, Self::Err> {
+        let mut bits = 0;
+        for flag in flags.chars() {
+            bits |= match flag {
+                'K' => KEYSPACE,
+                'E' => KEYEVENT,
+                'A' => ALL,
+                _ => match FLAGS.iter().find(|(x, _)| *x == flag) {
+                    Some((_, bit)) => *bit,
+                    None => return Err(KeyspaceEventsError::UnknownFlag(flag)),
+                },
+            };
+        }
+        Ok(KeyspaceEvents(bits))
+    }
+}
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 & ALL == ALL {
+            write!(f, "A")?;
+        }
+        for (flag, bit) in FLAGS {
+            if self.0 & bit != 0 && (bit & ALL == 0 || self.0 & ALL != ALL) {
+                write!(f, "{}", flag)?;
+            }
+        }
+        if self.0 & KEYSPACE != 0 {
+            write!(f, "K")?;
+        }
+        if self.0 & KEYEVENT != 0 {
+            write!(f, "E")?;
+        }
+        Ok(())
+    }
+}
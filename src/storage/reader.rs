@@ -2,29 +2,106 @@
 
 use super::*;
 
-use slog::slog_info;
-use slog_scope::info;
+use std::sync::{Arc, RwLock};
+
+use slog::{slog_debug, slog_info};
+use slog_scope::{debug, info};
 
 use evmap::ReadHandle;
 
 use actix::prelude::*;
 
 use crate::protocol::Response;
+use crate::stats::Stats;
+
+use super::functions::Libraries;
+use super::scripting::{self, Scripts};
 
 /// An actor that wraps a database reader handle
 pub struct Reader {
-    store: Option<ReadHandle<Key, Item>>,
+    stores: Vec<ReadHandle<Key, Item>>,
+    /// The store each database number refers to (shared with the `Writer`, which swaps them)
+    layout: Arc<RwLock<Vec<usize>>>,
+    /// The function libraries loaded by the `Writer`
+    libraries: Arc<RwLock<Libraries>>,
+    /// The script interpreter for read-only functions (taken while a function is running)
+    scripts: Option<Scripts>,
+    /// The server counters (shared with the `Writer`)
+    stats: Arc<Stats>,
 }
 
 impl Reader {
-    /// Construct a new reader for the given handle
-    pub fn new(store: ReadHandle<Key, Item>) -> Self {
-        Reader { store: Some(store) }
+    /// Construct a new reader for the given handles (indexed by database number)
+    pub fn new(stores: Vec<ReadHandle<Key, Item>>) -> Self {
+        Reader {
+            layout: Arc::new(RwLock::new((0..stores.len()).collect())),
+            stores,
+            ..Default::default()
+        }
+    }
+
+    /// Call a read-only library function
+    fn run_function(
+        &mut self,
+        db: usize,
+        name: &[u8],
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    ) -> Response {
+        match self
+            .libraries
+            .read()
+            .unwrap()
+            .function(&String::from_utf8_lossy(name))
+        {
+            Some(function) if function.is_read_only() => {}
+            Some(_) => return Response::Error(Error::WriteFlagInReadOnlyCall),
+            None => return Response::Error(Error::NoSuchFunction),
+        }
+
+        let mut scripts = self.scripts.take().expect("Script interpreter in use");
+        scripts.sync_functions(&self.libraries.read().unwrap());
+        let mut db = db;
+        let response = scripts.call_function(name, keys, args, |args| {
+            self.call_from_script(&mut db, args)
+        });
+        self.scripts = Some(scripts);
+        response.unwrap_or_else(Response::Error)
+    }
+
+    /// Execute a command on behalf of a read-only function
+    fn call_from_script(&self, db: &mut usize, args: Vec<Bytes>) -> Response {
+        let command = match scripting::parse_script_command(&args) {
+            Ok(command) => command,
+            Err(err) => return Response::Error(err),
+        };
+        debug!("Executing command from function: {:?}", command);
+
+        match command {
+            Command::Select(index) if index < self.stores.len() => {
+                *db = index;
+                Response::Ok
+            }
+            Command::Select(_) => Response::Error(Error::DbIndexOutOfRange),
+            ref command if !command.allowed_in_scripts() => {
+                Response::Error(Error::NotAllowedFromScript)
+            }
+            ref command if command.writes() => Response::Error(Error::WriteFromReadOnlyScript),
+            command => self.read(*db, command).unwrap_or_else(|err| {
+                Response::Error(Error::Reply(Bytes::from(format!("ERR {}", err))))
+            }),
+        }
     }
 }
 impl Default for Reader {
     fn default() -> Self {
-        Reader { store: None }
+        Reader {
+            stores: Vec::new(),
+            layout: Arc::new(RwLock::new(Vec::new())),
+            libraries: Arc::new(RwLock::new(Libraries::default())),
+            scripts: Some(Scripts::new(scripting::DEFAULT_TIME_LIMIT)),
+            stats: Arc::new(Stats::default()),
+        }
     }
 }
 impl Supervised for Reader {}
@@ -36,37 +113,76 @@ impl Actor for Reader {
         info!("Spawned reader");
 
         writer::Writer::from_registry()
-            .send(writer::Subscribe)
+            .send(writer::Subscribe(ctx.address().recipient()))
             .into_actor(self)
-            .map(|writer::Subscription(store), actor, _ctx| {
-                actor.store = Some(store);
+            .map(|subscription, actor, _ctx| {
+                actor.stores = subscription.stores;
+                actor.layout = subscription.layout;
+                actor.libraries = subscription.libraries;
+                actor.stats = subscription.stats;
+                if let Some(ref mut scripts) = actor.scripts {
+                    scripts.set_time_limit(subscription.script_time_limit);
+                }
             })
             .map_err(|_, _, _| ())
             .wait(ctx);
     }
 }
 
+impl View for Reader {
+    fn databases(&self) -> usize {
+        self.stores.len()
+    }
+
+    fn get(&self, db: usize, key: &Key) -> Option<Item> {
+        use super::ops::*;
+
+        let store = self.layout.read().unwrap().get(db).cloned();
+        store
+            .and_then(|store| self.stores.get(store))
+            .and_then(|store| store.get_and(key, get_live_item))
+            .and_then(|x| x)
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl Handler<writer::SetScriptTimeLimit> for Reader {
+    type Result = ();
+
+    fn handle(&mut self, limit: writer::SetScriptTimeLimit, _ctx: &mut Context<Self>) {
+        if let Some(ref mut scripts) = self.scripts {
+            scripts.set_time_limit(limit.0);
+        }
+    }
+}
+
 impl Handler<Operation> for Reader {
     type Result = Result<Response, StorageError>;
 
     fn handle(&mut self, operation: Operation, _ctx: &mut Context<Self>) -> Self::Result {
-        use super::ops::*;
-
         debug_assert!(operation.command.reads());
 
-        let reader = self.store.as_ref().ok_or(StorageError::NoReadAccess)?;
+        if self.stores.is_empty() {
+            return Err(StorageError::NoReadAccess);
+        }
 
-        Ok(match operation.command {
-            Command::Ping(None) => Response::Pong,
-            Command::Ping(Some(msg)) => Response::Bulk(msg),
-            Command::Get(key) => reader
-                .get_and(&key, get_string_as_bulk)
-                .unwrap_or(Response::Nil),
-            Command::Exists(keys) => {
-                Response::Integer(keys.into_iter().filter(|k| reader.contains_key(k)).count() as i64)
-            }
-            ref cmd if cmd.reads() => unimplemented!(),
-            _ => Err(StorageError::NoWriteAccess)?,
+        let Operation { command, db } = operation;
+        if db >= self.stores.len() {
+            return Ok(Response::Error(Error::DbIndexOutOfRange));
+        }
+
+        Ok(match command {
+            Command::FCallRO(name, keys, args) => self.run_function(db, &name, keys, args),
+            Command::FunctionList(pattern, with_code) => self
+                .libraries
+                .read()
+                .unwrap()
+                .list(pattern.as_ref().map(|x| &x[..]), with_code),
+            Command::FunctionDump => Response::Bulk(self.libraries.read().unwrap().dump()),
+            command => return self.read(db, command),
         })
     }
 }
@@ -0,0 +1,648 @@
+//! Lua scripting support for the storage actors
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use slog::{slog_debug, slog_info, slog_warn};
+use slog_scope::{debug, info, warn};
+
+use bytes::Bytes;
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value as LuaValue,
+    Variadic,
+};
+use sha1::Sha1;
+
+use crate::codecs::{parse_command, DecodeError};
+use crate::protocol::{Command, Error, Response};
+
+use super::functions::{self, FunctionInfo, Libraries, Library};
+
+/// The maximum execution time of a script unless configured otherwise
+pub const DEFAULT_TIME_LIMIT: Duration = Duration::from_millis(5000);
+
+/// The number of Lua instructions between two checks of the time limit
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Lua code that locks down the interpreter, returning a function that gives a chunk a fresh
+/// environment (with the given `KEYS` and `ARGV`)
+///
+/// Functions that load code or escape the environment are removed, and the environment only
+/// offers read-only views of the globals and libraries, so a script can't change what the next
+/// one sees.
+const SANDBOX: &str = r#"
+local error, getmetatable, pairs, setfenv, setmetatable, type =
+    error, getmetatable, pairs, setfenv, setmetatable, type
+
+for _, name in pairs({
+    "dofile", "getfenv", "load", "loadfile", "loadstring", "print", "rawset", "setfenv",
+}) do
+    _G[name] = nil
+end
+
+local function deny()
+    error("Attempt to modify a readonly table", 2)
+end
+
+local function readonly(index)
+    return { __index = index, __newindex = deny, __metatable = false }
+end
+
+local globals = {}
+for name, value in pairs(_G) do
+    if type(value) == "table" then
+        value = setmetatable({}, readonly(value))
+    end
+    globals[name] = value
+end
+local environment = readonly(globals)
+globals._G = setmetatable({}, environment)
+getmetatable("").__metatable = false
+
+return function(chunk, keys, argv)
+    return setfenv(chunk, setmetatable({ KEYS = keys, ARGV = argv }, environment))
+end
+"#;
+
+/// Compute the (lowercase, hexadecimal) SHA1 digest of a script
+pub fn sha1_hex(data: &[u8]) -> String {
+    Sha1::from(data).digest().to_string()
+}
+
+/// A Lua interpreter with a cache of compiled scripts and library functions
+pub struct Scripts {
+    lua: Lua,
+    /// The function that gives a chunk its own environment (see `SANDBOX`)
+    sandbox: RegistryKey,
+    /// Compiled scripts by SHA1 digest
+    cache: HashMap<String, RegistryKey>,
+    /// Compiled library functions by name
+    functions: HashMap<String, RegistryKey>,
+    /// The version of the libraries the functions were compiled from
+    functions_version: u64,
+    /// The maximum execution time of a single script
+    time_limit: Duration,
+}
+
+impl Scripts {
+    /// Create a new interpreter with the `redis` library installed
+    ///
+    /// Only the `table`, `string` and `math` libraries are loaded, so scripts can't reach the
+    /// file system or run other programs.
+    pub fn new(time_limit: Duration) -> Self {
+        let lua = Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+            LuaOptions::default(),
+        )
+        .expect("Failed to create the Lua interpreter");
+        let sandbox = install(&lua).expect("Failed to set up the Lua environment");
+
+        Scripts {
+            lua,
+            sandbox,
+            cache: HashMap::new(),
+            functions: HashMap::new(),
+            functions_version: 0,
+            time_limit,
+        }
+    }
+
+    /// The maximum execution time of scripts
+    pub fn time_limit(&self) -> Duration {
+        self.time_limit
+    }
+
+    /// Change the maximum execution time of scripts
+    pub fn set_time_limit(&mut self, time_limit: Duration) {
+        self.time_limit = time_limit;
+    }
+
+    /// Compile a script and add it to the cache, returning its SHA1 digest
+    pub fn load(&mut self, source: &[u8]) -> Result<String, Error> {
+        let sha = sha1_hex(source);
+        if !self.cache.contains_key(&sha) {
+            debug!("Compiling script {}", sha);
+            let function = self
+                .lua
+                .load(source)
+                .set_name(format!("@user_script:{}", sha))
+                .into_function()
+                .map_err(|err| {
+                    Error::Reply(Bytes::from(format!(
+                        "ERR Error compiling script (new function): {}",
+                        error_message(&err)
+                    )))
+                })?;
+            let key = self
+                .lua
+                .create_registry_value(function)
+                .map_err(script_error)?;
+            self.cache.insert(sha.clone(), key);
+        }
+        Ok(sha)
+    }
+
+    /// Check whether a script (by SHA1 digest) is in the cache
+    pub fn exists(&self, sha: &[u8]) -> bool {
+        std::str::from_utf8(sha)
+            .map(|sha| self.cache.contains_key(&sha.to_lowercase()))
+            .unwrap_or(false)
+    }
+
+    /// Remove all scripts from the cache
+    pub fn flush(&mut self) {
+        info!("Flushing {} cached scripts", self.cache.len());
+        for (_, key) in self.cache.drain() {
+            let _ = self.lua.remove_registry_value(key);
+        }
+    }
+
+    /// Run a cached script
+    ///
+    /// Calls to `redis.call` and `redis.pcall` are dispatched to `call` with the command name
+    /// and its arguments. The script is aborted once it exceeds the time limit. An error is
+    /// returned if the script raised one (or was aborted), as opposed to returning an error reply.
+    pub fn run<F>(
+        &mut self,
+        sha: &[u8],
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        call: F,
+    ) -> Result<Response, Error>
+    where
+        F: FnMut(Vec<Bytes>) -> Response,
+    {
+        let sha = std::str::from_utf8(sha)
+            .map_err(|_| Error::NoScript)?
+            .to_lowercase();
+        match self.cache.get(&sha) {
+            Some(key) => self.invoke(&sha, key, keys, args, Invocation::Script, call),
+            None => Err(Error::NoScript),
+        }
+    }
+
+    /// Compile a function library, returning its metadata
+    pub fn inspect_library(&mut self, code: &Bytes) -> Result<Library, Error> {
+        let (library, _) = self.compile_library(code)?;
+        self.lua.expire_registry_values();
+        Ok(library)
+    }
+
+    /// Make sure the compiled functions match the given libraries
+    pub fn sync_functions(&mut self, libraries: &Libraries) {
+        if self.functions_version == libraries.version() {
+            return;
+        }
+
+        debug!("Compiling functions of version {}", libraries.version());
+        self.functions.clear();
+        for library in libraries.iter() {
+            match self.compile_library(&library.code) {
+                Ok((_, functions)) => self.functions.extend(functions),
+                Err(err) => warn!(
+                    "Failed to compile library {}: {}",
+                    library.name,
+                    String::from_utf8_lossy(&err.message())
+                ),
+            }
+        }
+        self.lua.expire_registry_values();
+        self.functions_version = libraries.version();
+    }
+
+    /// Call a library function
+    ///
+    /// The function is passed the keys and arguments as two tables, and is otherwise run just
+    /// like a script.
+    pub fn call_function<F>(
+        &mut self,
+        name: &[u8],
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        call: F,
+    ) -> Result<Response, Error>
+    where
+        F: FnMut(Vec<Bytes>) -> Response,
+    {
+        let name = String::from_utf8_lossy(name);
+        match self.functions.get(name.as_ref()) {
+            Some(key) => self.invoke(&name, key, keys, args, Invocation::Function, call),
+            None => Err(Error::NoSuchFunction),
+        }
+    }
+
+    /// Compile a function library, returning its metadata and the registered functions
+    ///
+    /// The library's code (and so its functions) runs in an environment of its own, just like a
+    /// script's.
+    fn compile_library(
+        &self,
+        code: &Bytes,
+    ) -> Result<(Library, Vec<(String, RegistryKey)>), Error> {
+        let (engine, name, offset) = parse_metadata(code)?;
+
+        let registered = RefCell::new(Vec::new());
+        let lua = &self.lua;
+        let result = lua.scope(|scope| {
+            let redis: Table = lua.globals().get("redis")?;
+            redis.set(
+                "register_function",
+                scope.create_function(|lua, args: Variadic<LuaValue>| {
+                    let (name, callback, info) = to_registration(&args)?;
+                    if registered
+                        .borrow()
+                        .iter()
+                        .any(|(other, _, _)| *other == name)
+                    {
+                        return Err(mlua::Error::RuntimeError(
+                            "Function already exists in the library".into(),
+                        ));
+                    }
+                    let key = lua.create_registry_value(callback)?;
+                    registered.borrow_mut().push((name, info, key));
+                    Ok(())
+                })?,
+            )?;
+
+            let chunk = lua
+                .load(&code[offset..])
+                .set_name(format!("@user_function:{}", name))
+                .into_function()?;
+            let sandbox: Function = lua.registry_value(&self.sandbox)?;
+            let chunk: Function = sandbox.call((chunk, LuaValue::Nil, LuaValue::Nil))?;
+            chunk.call::<_, ()>(())
+        });
+
+        if let Ok(redis) = lua.globals().get::<_, Table>("redis") {
+            let _ = redis.set("register_function", LuaValue::Nil);
+        }
+        if let Err(err) = result {
+            return Err(Error::Reply(Bytes::from(format!(
+                "ERR Error registering functions: {}",
+                error_message(&err)
+            ))));
+        }
+
+        let registered = registered.into_inner();
+        if registered.is_empty() {
+            return Err(Error::Reply(Bytes::from_static(
+                b"ERR No functions registered",
+            )));
+        }
+
+        let mut library = Library {
+            name,
+            engine,
+            code: code.clone(),
+            functions: BTreeMap::new(),
+        };
+        let mut functions = Vec::with_capacity(registered.len());
+        for (name, info, key) in registered {
+            library.functions.insert(name.clone(), info);
+            functions.push((name, key));
+        }
+        Ok((library, functions))
+    }
+
+    /// Call a compiled script or function, aborting it once it exceeds the time limit
+    fn invoke<F>(
+        &self,
+        name: &str,
+        key: &RegistryKey,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        invocation: Invocation,
+        call: F,
+    ) -> Result<Response, Error>
+    where
+        F: FnMut(Vec<Bytes>) -> Response,
+    {
+        let started = Instant::now();
+        let time_limit = self.time_limit;
+        self.lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(HOOK_INTERVAL),
+                ..Default::default()
+            },
+            move |_lua, _debug| {
+                if started.elapsed() > time_limit {
+                    Err(mlua::Error::RuntimeError(
+                        "Script killed by timeout (lua-time-limit exceeded)".into(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        let call = RefCell::new(call);
+        let lua = &self.lua;
+        let result = lua.scope(|scope| {
+            let redis: Table = lua.globals().get("redis")?;
+            redis.set(
+                "call",
+                scope.create_function(|lua, args: Variadic<LuaValue>| {
+                    match (&mut *call.borrow_mut())(to_args(&args)?) {
+                        Response::Error(err) => Err(mlua::Error::RuntimeError(
+                            String::from_utf8_lossy(&err.message()).into_owned(),
+                        )),
+                        response => to_lua(lua, response),
+                    }
+                })?,
+            )?;
+            redis.set(
+                "pcall",
+                scope.create_function(|lua, args: Variadic<LuaValue>| {
+                    let response = match to_args(&args) {
+                        Ok(args) => (&mut *call.borrow_mut())(args),
+                        Err(err) => Response::Error(Error::Reply(Bytes::from(error_message(&err)))),
+                    };
+                    to_lua(lua, response)
+                })?,
+            )?;
+
+            let keys = lua.create_sequence_from(to_strings(lua, keys)?)?;
+            let args = lua.create_sequence_from(to_strings(lua, args)?)?;
+            let function: Function = lua.registry_value(key)?;
+            let value: LuaValue = match invocation {
+                Invocation::Script => {
+                    let sandbox: Function = lua.registry_value(&self.sandbox)?;
+                    let function: Function = sandbox.call((function, keys, args))?;
+                    function.call(())?
+                }
+                Invocation::Function => function.call((keys, args))?,
+            };
+            Ok(from_lua(value))
+        });
+
+        self.lua.remove_hook();
+
+        let elapsed = started.elapsed();
+        if elapsed > time_limit {
+            warn!("Script {} exceeded the time limit ({:?})", name, elapsed);
+        }
+
+        result.map_err(script_error)
+    }
+}
+
+/// How a compiled chunk receives its keys and arguments
+enum Invocation {
+    /// As the `KEYS` and `ARGV` globals (of an environment of its own)
+    Script,
+    /// As two table parameters
+    Function,
+}
+
+/// Parse the `#!<engine> name=<library>` line at the start of a library's code, returning the
+/// engine, the library name and the offset of the remaining code
+fn parse_metadata(code: &[u8]) -> Result<(String, String, usize), Error> {
+    let reply = |msg: String| Error::Reply(Bytes::from(msg));
+
+    if !code.starts_with(b"#!") {
+        return Err(reply("ERR Missing library metadata".into()));
+    }
+    let end = code.iter().position(|&x| x == b'\n').unwrap_or(code.len());
+    let line = String::from_utf8_lossy(&code[2..end]).into_owned();
+
+    let mut parts = line.split_whitespace();
+    let engine = parts.next().unwrap_or("").to_owned();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(reply(format!("ERR Engine '{}' not found", engine)));
+    }
+
+    let mut name = None;
+    for part in parts {
+        if part.starts_with("name=") && name.is_none() {
+            name = Some(part["name=".len()..].to_owned());
+        } else {
+            return Err(reply(format!("ERR Invalid metadata value given: {}", part)));
+        }
+    }
+
+    match name {
+        None => Err(reply("ERR Library name was not given".into())),
+        Some(ref name) if !functions::is_valid_name(name) => Err(reply(
+            "ERR Library names can only contain letters, numbers, or underscores(_) and must \
+             be at least one character long"
+                .into(),
+        )),
+        // Keep the newline so line numbers in error messages stay correct
+        Some(name) => Ok((engine.to_uppercase(), name, end)),
+    }
+}
+
+/// Convert the arguments of `redis.register_function` to a function name, callback and metadata
+fn to_registration<'lua>(
+    args: &[LuaValue<'lua>],
+) -> mlua::Result<(String, Function<'lua>, FunctionInfo)> {
+    let error = |msg: &str| Err(mlua::Error::RuntimeError(msg.into()));
+
+    let (name, callback, description, flags) = match args {
+        [LuaValue::String(name), LuaValue::Function(callback)] => {
+            (name.clone(), callback.clone(), None, None)
+        }
+        [LuaValue::Table(table)] => {
+            let mut name = None;
+            let mut callback = None;
+            let mut description = None;
+            let mut flags = None;
+            for pair in table.clone().pairs::<mlua::String, LuaValue>() {
+                let (key, value) = pair?;
+                match (key.as_bytes(), value) {
+                    (b"function_name", LuaValue::String(value)) => name = Some(value),
+                    (b"callback", LuaValue::Function(value)) => callback = Some(value),
+                    (b"description", LuaValue::String(value)) => description = Some(value),
+                    (b"flags", LuaValue::Table(value)) => flags = Some(value),
+                    _ => return error("unknown argument given to redis.register_function"),
+                }
+            }
+            match (name, callback) {
+                (Some(name), Some(callback)) => (name, callback, description, flags),
+                (None, _) => {
+                    return error("redis.register_function must get a function name argument")
+                }
+                (_, None) => return error("redis.register_function must get a callback argument"),
+            }
+        }
+        _ => return error("wrong number of arguments to redis.register_function"),
+    };
+
+    let name = String::from_utf8_lossy(name.as_bytes()).into_owned();
+    if !functions::is_valid_name(&name) {
+        return error(
+            "Function names can only contain letters, numbers, or underscores(_) and must be at \
+             least one character long",
+        );
+    }
+
+    let mut info = FunctionInfo {
+        description: description.map(|x| Bytes::from(x.as_bytes())),
+        flags: Vec::new(),
+    };
+    if let Some(flags) = flags {
+        for flag in flags.sequence_values::<mlua::String>() {
+            let flag = String::from_utf8_lossy(flag?.as_bytes()).into_owned();
+            if !functions::FUNCTION_FLAGS.contains(&flag.as_str()) {
+                return error("unknown flag given");
+            }
+            info.flags.push(flag);
+        }
+    }
+
+    Ok((name, callback, info))
+}
+
+/// Parse a command called from a script
+pub fn parse_script_command(args: &[Bytes]) -> Result<Command, Error> {
+    parse_command(args).map_err(|err| match err {
+        DecodeError::UnrecognizedCommand(_) => Error::UnknownScriptCommand,
+        DecodeError::InvalidInteger(_) => Error::NotAnInteger,
+        DecodeError::WrongArity(cmd) => Error::WrongArity(cmd),
+        _ => Error::Syntax,
+    })
+}
+
+/// Install the `redis` library in a Lua environment and lock it down, returning the function
+/// that sandboxes chunks
+fn install(lua: &Lua) -> mlua::Result<RegistryKey> {
+    let redis = lua.create_table()?;
+
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, msg: mlua::String| {
+            let reply = lua.create_table()?;
+            reply.set("ok", msg)?;
+            Ok(reply)
+        })?,
+    )?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, msg: mlua::String| {
+            let reply = lua.create_table()?;
+            reply.set("err", msg)?;
+            Ok(reply)
+        })?,
+    )?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_lua, data: mlua::String| Ok(sha1_hex(data.as_bytes())))?,
+    )?;
+    redis.set(
+        "log",
+        lua.create_function(|_lua, (level, msg): (i64, mlua::String)| {
+            let msg = String::from_utf8_lossy(msg.as_bytes()).into_owned();
+            match level {
+                0 | 1 => debug!("Script: {}", msg),
+                2 => info!("Script: {}", msg),
+                _ => warn!("Script: {}", msg),
+            }
+            Ok(())
+        })?,
+    )?;
+    redis.set("LOG_DEBUG", 0)?;
+    redis.set("LOG_VERBOSE", 1)?;
+    redis.set("LOG_NOTICE", 2)?;
+    redis.set("LOG_WARNING", 3)?;
+
+    lua.globals().set("redis", redis)?;
+
+    let sandbox: Function = lua.load(SANDBOX).set_name("=sandbox").call(())?;
+    lua.create_registry_value(sandbox)
+}
+
+/// Convert the arguments of `redis.call` to a command
+fn to_args(args: &[LuaValue]) -> mlua::Result<Vec<Bytes>> {
+    args.iter()
+        .map(|arg| match arg {
+            LuaValue::String(data) => Ok(Bytes::from(data.as_bytes())),
+            LuaValue::Integer(value) => Ok(Bytes::from(value.to_string())),
+            LuaValue::Number(value) => Ok(Bytes::from(value.to_string())),
+            _ => Err(mlua::Error::RuntimeError(
+                "ERR Lua redis() command arguments must be strings or integers".into(),
+            )),
+        })
+        .collect()
+}
+
+fn to_strings(lua: &Lua, values: Vec<Bytes>) -> mlua::Result<Vec<mlua::String>> {
+    values
+        .iter()
+        .map(|value| lua.create_string(&value[..]))
+        .collect()
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, msg: &[u8]) -> mlua::Result<LuaValue<'lua>> {
+    let reply = lua.create_table()?;
+    reply.set(field, lua.create_string(msg)?)?;
+    Ok(LuaValue::Table(reply))
+}
+
+/// Convert a response to a Lua value
+///
+/// Scripts always get RESP2 replies, so RESP3-only types are converted first.
+fn to_lua(lua: &Lua, response: Response) -> mlua::Result<LuaValue> {
+    Ok(match response.downgrade() {
+        Response::Nil => LuaValue::Boolean(false),
+        Response::Ok => reply_table(lua, "ok", b"OK")?,
+        Response::Pong => reply_table(lua, "ok", b"PONG")?,
+        Response::Queued => reply_table(lua, "ok", b"QUEUED")?,
+        Response::Status(msg) => reply_table(lua, "ok", &msg)?,
+        Response::Error(err) => reply_table(lua, "err", &err.message())?,
+        Response::Integer(value) => LuaValue::Integer(value as mlua::Integer),
+        Response::Bulk(data) => LuaValue::String(lua.create_string(&data[..])?),
+        Response::Array(elements) => {
+            let table = lua.create_table()?;
+            for (i, element) in elements.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, element)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        _ => unreachable!(),
+    })
+}
+
+/// Convert a Lua value to a response
+fn from_lua(value: LuaValue) -> Response {
+    match value {
+        LuaValue::Nil | LuaValue::Boolean(false) => Response::Nil,
+        LuaValue::Boolean(true) => Response::Integer(1),
+        LuaValue::Integer(value) => Response::Integer(value as i64),
+        LuaValue::Number(value) => Response::Integer(value as i64),
+        LuaValue::String(data) => Response::Bulk(Bytes::from(data.as_bytes())),
+        LuaValue::Table(table) => {
+            if let Ok(Some(msg)) = table.raw_get::<_, Option<mlua::String>>("err") {
+                Response::Error(Error::Reply(Bytes::from(msg.as_bytes())))
+            } else if let Ok(Some(msg)) = table.raw_get::<_, Option<mlua::String>>("ok") {
+                Response::Status(Bytes::from(msg.as_bytes()))
+            } else {
+                Response::Array(
+                    table
+                        .sequence_values::<LuaValue>()
+                        .map(|value| value.map(from_lua).unwrap_or(Response::Nil))
+                        .collect(),
+                )
+            }
+        }
+        _ => Response::Nil,
+    }
+}
+
+fn error_message(err: &mlua::Error) -> String {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        err => err.to_string(),
+    }
+}
+
+/// Convert a Lua error to an error reply
+///
+/// Errors raised by `redis.call` are passed on as-is.
+fn script_error(err: mlua::Error) -> Error {
+    Error::Reply(Bytes::from(match err {
+        mlua::Error::CallbackError { ref cause, .. } => error_message(cause),
+        err => format!("ERR Error running script: {}", error_message(&err)),
+    }))
+}
@@ -0,0 +1,154 @@
+//! Client-side caching (`CLIENT TRACKING`)
+
+use std::collections::{HashMap, HashSet};
+
+use slog::slog_debug;
+use slog_scope::debug;
+
+use bytes::Bytes;
+use uuid::Uuid;
+
+use actix::prelude::*;
+use actix_derive::Message;
+
+use super::Key;
+
+/// Invalidated keys pushed to a tracking client (or `None` if all keys were invalidated)
+#[derive(Debug, Message)]
+pub struct Invalidate {
+    pub keys: Option<Vec<Key>>,
+    /// Whether the client receives the invalidations on behalf of another client (`REDIRECT`)
+    pub redirected: bool,
+}
+
+/// A client with tracking enabled
+struct TrackingClient {
+    /// The connection invalidations are sent to
+    recipient: Recipient<Invalidate>,
+    redirected: bool,
+    /// The key prefixes the client is interested in, if in broadcasting mode
+    prefixes: Option<Vec<Bytes>>,
+}
+
+/// The keys each tracking client may have cached
+///
+/// Keys are tracked across databases, so modifying a key in any database invalidates it.
+/// Invalidations are collected and only sent once the changes are visible to the readers.
+#[derive(Default)]
+pub struct Tracking {
+    clients: HashMap<Uuid, TrackingClient>,
+    /// The clients that read each key
+    keys: HashMap<Key, HashSet<Uuid>>,
+    /// Invalidations that will be sent on the next refresh
+    pending: HashMap<Uuid, Vec<Key>>,
+    /// Whether all keys will be invalidated on the next refresh
+    flushed: bool,
+}
+impl Tracking {
+    /// Start tracking keys for a client, replacing its previous settings
+    pub fn enable(
+        &mut self,
+        client: Uuid,
+        recipient: Recipient<Invalidate>,
+        redirected: bool,
+        prefixes: Option<Vec<Bytes>>,
+    ) {
+        self.disable(client);
+        self.clients.insert(
+            client,
+            TrackingClient {
+                recipient,
+                redirected,
+                prefixes,
+            },
+        );
+    }
+
+    /// Stop tracking keys for a client
+    pub fn disable(&mut self, client: Uuid) {
+        if self.clients.remove(&client).is_none() {
+            return;
+        }
+        self.keys.retain(|_, clients| {
+            clients.remove(&client);
+            !clients.is_empty()
+        });
+        self.pending.remove(&client);
+    }
+
+    /// The number of clients with tracking enabled
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Remember that a client read the given keys
+    pub fn track(&mut self, client: Uuid, keys: Vec<Key>) {
+        match self.clients.get(&client) {
+            Some(TrackingClient { prefixes: None, .. }) => {}
+            _ => return,
+        }
+        for key in keys {
+            self.keys
+                .entry(key)
+                .or_insert_with(HashSet::new)
+                .insert(client);
+        }
+    }
+
+    /// Queue an invalidation of a key for all clients that may have cached it
+    pub fn invalidate(&mut self, key: &Key) {
+        if self.clients.is_empty() {
+            return;
+        }
+
+        let mut clients = self.keys.remove(key).unwrap_or_default();
+        for (&client, state) in self.clients.iter() {
+            if let Some(ref prefixes) = state.prefixes {
+                if prefixes.iter().any(|prefix| key.starts_with(prefix)) {
+                    clients.insert(client);
+                }
+            }
+        }
+        for client in clients {
+            self.pending
+                .entry(client)
+                .or_insert_with(Vec::new)
+                .push(key.clone());
+        }
+    }
+
+    /// Queue an invalidation of all keys for all tracking clients
+    pub fn invalidate_all(&mut self) {
+        if self.clients.is_empty() {
+            return;
+        }
+
+        self.keys.clear();
+        self.pending.clear();
+        self.flushed = true;
+    }
+
+    /// Send all queued invalidations
+    pub fn flush(&mut self) {
+        if self.flushed {
+            self.flushed = false;
+            for state in self.clients.values() {
+                let invalidate = Invalidate {
+                    keys: None,
+                    redirected: state.redirected,
+                };
+                let _ = state.recipient.do_send(invalidate);
+            }
+        }
+        for (client, keys) in self.pending.drain() {
+            if let Some(state) = self.clients.get(&client) {
+                debug!("Invalidating {} keys for client {}", keys.len(), client);
+                let invalidate = Invalidate {
+                    keys: Some(keys),
+                    redirected: state.redirected,
+                };
+                let _ = state.recipient.do_send(invalidate);
+            }
+        }
+    }
+}
@@ -2,132 +2,681 @@
 //!
 use super::*;
 
-use std::time::Duration;
+use std::collections::hash_map::Entry;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use slog::{slog_debug, slog_info};
-use slog_scope::{debug, info};
+use slog::{slog_debug, slog_error, slog_info};
+use slog_scope::{debug, error, info};
 
 use evmap::{ReadHandle, WriteHandle};
 
 use actix_derive::{Message, MessageResponse};
 
 use actix::prelude::*;
+use futures::{future, Future};
 
 use crate::protocol::Response;
+use crate::pubsub::Subscriptions;
+use crate::stats::Stats;
 
-/// An actor that wraps a database reader handle
-pub struct Writer {
+use super::functions::{self, FunctionsFile, Libraries};
+use super::notifications::{EventClass, KeyspaceEvents};
+use super::scripting::{self, Scripts};
+use super::tracking::{Invalidate, Tracking};
+
+/// The read and write handles of a single database
+struct Database {
     reader: ReadHandle<Key, Item>,
     writer: WriteHandle<Key, Item>,
+}
+impl Database {
+    fn new() -> Self {
+        let (reader, writer) = evmap::new();
+        Database { reader, writer }
+    }
+}
+
+/// The uncommitted changes of a `Writer` at some point, to roll back to
+struct Savepoint {
+    layout: Vec<usize>,
+    pending: HashMap<(usize, Key), Option<Item>>,
+    purged: BTreeSet<usize>,
+    dirty: BTreeSet<usize>,
+    events: usize,
+}
+
+/// The keys a client is watching
+#[derive(Debug, Default)]
+struct WatchState {
+    /// The watched keys, and whether they held a non-expired item when the watch started
+    keys: Vec<(usize, Key, bool)>,
+    /// Whether any of the watched keys have been modified
+    dirty: bool,
+}
+
+/// An actor that wraps a database reader handle
+pub struct Writer {
+    /// The stores of the databases, in the order they were created
+    databases: Vec<Database>,
+    /// The store each database number refers to (`SWAPDB` swaps these)
+    layout: Vec<usize>,
+    /// The layout as last published to the readers
+    published_layout: Arc<RwLock<Vec<usize>>>,
+    /// Changes that haven't been applied to the stores yet (applied in the next commit)
+    pending: HashMap<(usize, Key), Option<Item>>,
+    /// Stores that will be purged in the next commit (before applying the pending changes)
+    purged: BTreeSet<usize>,
+    /// Stores that need to be refreshed
+    dirty: BTreeSet<usize>,
+    /// The clients watching each key
+    watchers: HashMap<(usize, Key), HashSet<Uuid>>,
+    /// The watched keys of each client
+    watches: HashMap<Uuid, WatchState>,
+    /// The script interpreter (taken while a script is running)
+    scripts: Option<Scripts>,
+    /// The loaded function libraries (shared with the readers)
+    libraries: Arc<RwLock<Libraries>>,
+    /// The file function libraries are persisted to
+    functions_file: Option<PathBuf>,
+    /// The actor writing the functions file (started along with the writer)
+    functions_saver: Option<Addr<FunctionsFile>>,
+    /// Whether the function libraries changed since they were last saved
+    functions_changed: bool,
+    /// The pub/sub subscriptions (shared with the `Broker`)
+    subscriptions: Arc<RwLock<Subscriptions>>,
+    /// The keyspace events that are published
+    keyspace_events: KeyspaceEvents,
+    /// Keyspace events that will be published on the next refresh
+    events: Vec<(Bytes, Bytes)>,
+    /// The keys cached by clients with tracking enabled
+    tracking: Tracking,
+    /// The server counters (shared with the readers and connections)
+    stats: Arc<Stats>,
+    /// The readers that subscribed to this writer
+    readers: Vec<Recipient<SetScriptTimeLimit>>,
     operation_id: u64,
 }
 
 impl Writer {
-    /// Construct a new writer for the given handle
-    pub fn new(store: WriteHandle<Key, Item>) -> Self {
+    /// Construct a new writer with the given number of databases
+    pub fn new(databases: usize) -> Self {
+        assert!(databases > 0, "A writer needs at least one database");
+        let layout: Vec<usize> = (0..databases).collect();
         Writer {
-            reader: store.clone(),
-            writer: store,
+            databases: (0..databases).map(|_| Database::new()).collect(),
+            published_layout: Arc::new(RwLock::new(layout.clone())),
+            layout,
+            pending: HashMap::new(),
+            purged: BTreeSet::new(),
+            dirty: BTreeSet::new(),
+            watchers: HashMap::new(),
+            watches: HashMap::new(),
+            scripts: Some(Scripts::new(scripting::DEFAULT_TIME_LIMIT)),
+            libraries: Arc::new(RwLock::new(Libraries::default())),
+            functions_file: None,
+            functions_saver: None,
+            functions_changed: false,
+            subscriptions: Arc::new(RwLock::new(Subscriptions::default())),
+            keyspace_events: KeyspaceEvents::default(),
+            events: Vec::new(),
+            tracking: Tracking::default(),
+            stats: Arc::new(Stats::default()),
+            readers: Vec::new(),
             operation_id: 0,
         }
     }
+
+    /// Set the maximum execution time of scripts
+    pub fn with_script_time_limit(mut self, time_limit: Duration) -> Self {
+        if let Some(ref mut scripts) = self.scripts {
+            scripts.set_time_limit(time_limit);
+        }
+        self
+    }
+
+    /// Publish messages to the given pub/sub subscriptions
+    pub fn with_subscriptions(mut self, subscriptions: Arc<RwLock<Subscriptions>>) -> Self {
+        self.subscriptions = subscriptions;
+        self
+    }
+
+    /// Publish the given classes of keyspace events
+    pub fn with_keyspace_events(mut self, keyspace_events: KeyspaceEvents) -> Self {
+        self.keyspace_events = keyspace_events;
+        self
+    }
+
+    /// Record expired keys and keyspace hits and misses in the given counters
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Persist function libraries to the given file, loading any libraries it already contains
+    pub fn with_functions_file(mut self, path: PathBuf) -> Self {
+        if path.exists() {
+            match functions::load_dump(&path) {
+                Ok(codes) => {
+                    let mut libraries = self.libraries.write().unwrap();
+                    for code in codes {
+                        match self
+                            .scripts
+                            .as_mut()
+                            .expect("Script interpreter in use")
+                            .inspect_library(&code)
+                            .and_then(|library| libraries.insert(library, true))
+                        {
+                            Ok(()) => {}
+                            Err(err) => error!(
+                                "Failed to load library from {}: {}",
+                                path.display(),
+                                String::from_utf8_lossy(&err.message())
+                            ),
+                        }
+                    }
+                    info!(
+                        "Loaded {} function libraries from {}",
+                        libraries.iter().count(),
+                        path.display()
+                    );
+                }
+                Err(err) => error!("Failed to read {}: {}", path.display(), err),
+            }
+        }
+        self.functions_file = Some(path);
+        self
+    }
 }
 impl Default for Writer {
     fn default() -> Self {
-        let (reader, writer) = evmap::new();
-        Writer {
-            reader,
-            writer,
-            operation_id: 0,
+        Writer::new(DEFAULT_DATABASES)
+    }
+}
+impl View for Writer {
+    fn databases(&self) -> usize {
+        self.databases.len()
+    }
+
+    fn get(&self, db: usize, key: &Key) -> Option<Item> {
+        use super::ops::*;
+
+        let store = self.layout[db];
+        match self.pending.get(&(store, key.clone())) {
+            Some(Some(item)) if is_live(item) => Some(item.clone()),
+            Some(_) => None,
+            None if self.purged.contains(&store) => None,
+            None => self.databases[store]
+                .reader
+                .get_and(key, get_live_item)
+                .and_then(|x| x),
         }
     }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
 }
 impl Writer {
-    fn expire(&self, ctx: &mut Context<Self>, key: Bytes, duration: Duration) {
+    /// Mark all clients watching a key as dirty, and invalidate it for tracking clients
+    fn touch(&mut self, db: usize, key: &Key) {
+        self.tracking.invalidate(key);
+        if self.watchers.is_empty() {
+            return;
+        }
+
+        if let Some(clients) = self.watchers.get(&(db, key.clone())) {
+            for client in clients {
+                if let Some(state) = self.watches.get_mut(client) {
+                    state.dirty = true;
+                }
+            }
+        }
+    }
+
+    /// Stop watching all keys for a client
+    fn unwatch(&mut self, client: Uuid) {
+        if let Some(state) = self.watches.remove(&client) {
+            for (db, key, _) in state.keys {
+                if let Entry::Occupied(mut entry) = self.watchers.entry((db, key)) {
+                    entry.get_mut().remove(&client);
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check whether any of the keys a client watches have been modified or have expired
+    fn is_dirty(&self, client: Uuid) -> bool {
+        self.watches
+            .get(&client)
+            .map(|state| {
+                state.dirty
+                    || state
+                        .keys
+                        .iter()
+                        .any(|(db, key, live)| *live && !self.contains_key(*db, key))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Store an item under a key
+    fn put(&mut self, db: usize, key: Key, item: Item) {
+        self.touch(db, &key);
+        let store = self.layout[db];
+        self.dirty.insert(store);
+        self.pending.insert((store, key), Some(item));
+    }
+
+    /// Remove a key, returning whether it held a non-expired item
+    fn remove(&mut self, db: usize, key: Key) -> bool {
+        let existed = self.contains_key(db, &key);
+        if existed {
+            self.touch(db, &key);
+        }
+        let store = self.layout[db];
+        self.dirty.insert(store);
+        self.pending.insert((store, key), None);
+        existed
+    }
+
+    /// Mark the clients watching keys of a database as dirty, for the keys that exist in it or
+    /// in the database whose contents it is about to get
+    fn touch_all(&mut self, db: usize, replacement: usize) {
+        let watched: Vec<Key> = self
+            .watchers
+            .keys()
+            .filter(|&&(x, _)| x == db)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in watched {
+            if self.contains_key(db, &key) || self.contains_key(replacement, &key) {
+                self.touch(db, &key);
+            }
+        }
+    }
+
+    /// Remove all keys from a database
+    fn purge(&mut self, db: usize) {
+        self.touch_all(db, db);
+        self.tracking.invalidate_all();
+
+        let store = self.layout[db];
+        self.dirty.insert(store);
+        self.pending.retain(|&(x, _), _| x != store);
+        self.purged.insert(store);
+    }
+
+    /// Swap the contents of two databases
+    fn swap(&mut self, a: usize, b: usize) {
+        self.touch_all(a, b);
+        self.touch_all(b, a);
+        self.tracking.invalidate_all();
+        self.layout.swap(a, b);
+    }
+
+    /// Find the database number of a store
+    fn database_of(&self, store: usize) -> usize {
+        self.layout
+            .iter()
+            .position(|&x| x == store)
+            .expect("Store without a database")
+    }
+
+    /// Queue a keyspace event for a key, if events of its class are enabled
+    fn notify(&mut self, class: EventClass, event: &str, db: usize, key: &Key) {
+        if self.keyspace_events.includes(class) {
+            let messages = self.keyspace_events.messages(event, db, key);
+            self.events.extend(messages);
+        }
+    }
+
+    /// Publish all pending changes to the readers, followed by their invalidations and keyspace
+    /// events
+    ///
+    /// Databases are refreshed one after another, so readers may briefly see the changes to one
+    /// database before those to another. Swapped databases are published (after refreshing) in
+    /// one step.
+    fn commit(&mut self) {
+        info!("COMMITTING");
+        for store in std::mem::replace(&mut self.purged, BTreeSet::new()) {
+            self.databases[store].writer.purge();
+        }
+        for ((store, key), item) in self.pending.drain() {
+            match item {
+                Some(item) => {
+                    self.databases[store].writer.update(key, item);
+                }
+                None => {
+                    self.databases[store].writer.empty(key);
+                }
+            }
+        }
+        for store in std::mem::replace(&mut self.dirty, BTreeSet::new()) {
+            self.databases[store].writer.refresh();
+        }
+        if *self.published_layout.read().unwrap() != self.layout {
+            *self.published_layout.write().unwrap() = self.layout.clone();
+        }
+        self.tracking.flush();
+
+        if !self.events.is_empty() {
+            let subscriptions = self.subscriptions.read().unwrap();
+            for (channel, message) in self.events.drain(..) {
+                subscriptions.publish(&channel, &message);
+            }
+        }
+    }
+
+    /// Remember the uncommitted changes, so they can be rolled back
+    fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            layout: self.layout.clone(),
+            pending: self.pending.clone(),
+            purged: self.purged.clone(),
+            dirty: self.dirty.clone(),
+            events: self.events.len(),
+        }
+    }
+
+    /// Discard the changes made since a savepoint
+    ///
+    /// Watching clients and tracking clients may still have been told the keys were modified.
+    fn rollback(&mut self, savepoint: Savepoint) {
+        self.layout = savepoint.layout;
+        self.pending = savepoint.pending;
+        self.purged = savepoint.purged;
+        self.dirty = savepoint.dirty;
+        self.events.truncate(savepoint.events);
+    }
+
+    fn expire(&self, ctx: &mut Context<Self>, db: usize, key: Bytes, duration: Duration) {
         use super::ops::*;
 
         let operation_id = self.operation_id;
+        let store = self.layout[db];
         ctx.run_later(duration, move |act, _ctx| {
-            debug!("Expiring key {:?}", key);
-            if act
+            if act.databases[store]
                 .writer
                 .get_and(&key, get_metadata)
                 .map(|meta| meta.operation_id == operation_id)
                 .unwrap_or(false)
             {
-                act.writer.empty(key);
-                act.writer.refresh();
+                // The database may have been swapped since the expiration was set
+                let db = act.database_of(store);
+                debug!("Expiring key {:?} in database {}", key, db);
+                act.notify(EventClass::Expired, "expired", db, &key);
+                act.tracking.invalidate(&key);
+                act.remove(db, key);
+                act.commit();
+                Stats::incr(&act.stats.expired_keys);
             }
         });
     }
-}
-impl Actor for Writer {
-    type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Context<Self>) {
-        info!("Spawned writer");
+    fn expire_at(&self, ctx: &mut Context<Self>, db: usize, key: Bytes, expires_at: Instant) {
+        let now = clock::now();
+        let duration = if expires_at > now {
+            expires_at - now
+        } else {
+            Duration::from_secs(0)
+        };
+        self.expire(ctx, db, key, duration);
+    }
+
+    /// Store an item under a new key, carrying over its expiration
+    fn transfer(&mut self, ctx: &mut Context<Self>, db: usize, key: Bytes, item: Item) {
+        let expiration = item.meta.expiration;
+        let operation_id = self.operation_id;
+        self.put(
+            db,
+            key.clone(),
+            Item {
+                value: item.value,
+                meta: Metadata {
+                    expiration,
+                    operation_id,
+                },
+            },
+        );
+
+        if let Some(expires_at) = expiration {
+            self.expire_at(ctx, db, key, expires_at);
+        }
     }
-}
-impl Supervised for Writer {}
-impl SystemService for Writer {}
-impl Handler<Subscribe> for Writer {
-    type Result = Subscription;
 
-    fn handle(&mut self, _: Subscribe, _ctx: &mut Context<Self>) -> Self::Result {
-        Subscription(self.reader.clone())
+    fn scripts(&mut self) -> &mut Scripts {
+        self.scripts.as_mut().expect("Script interpreter in use")
     }
-}
 
-impl Handler<Operation> for Writer {
-    type Result = Result<Response, StorageError>;
+    /// Run a cached script, executing its commands without publishing their changes
+    fn run_script(
+        &mut self,
+        ctx: &mut Context<Self>,
+        db: usize,
+        sha: &[u8],
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    ) -> Response {
+        let savepoint = self.savepoint();
+        let mut scripts = self.scripts.take().expect("Script interpreter in use");
+        let mut db = db;
+        let result = scripts.run(sha, keys, args, |args| {
+            self.call_from_script(ctx, &mut db, false, args)
+        });
+        self.scripts = Some(scripts);
+        self.settle(result, savepoint)
+    }
 
-    fn handle(&mut self, operation: Operation, ctx: &mut Context<Self>) -> Self::Result {
-        use super::ops::*;
-        debug_assert!(operation.command.writes());
+    /// Roll back the changes of a script (or function) that raised an error or was aborted
+    fn settle(&mut self, result: Result<Response, Error>, savepoint: Savepoint) -> Response {
+        result.unwrap_or_else(|err| {
+            debug!("Rolling back the changes of a failed script");
+            self.rollback(savepoint);
+            Response::Error(err)
+        })
+    }
+
+    /// Call a library function, executing its commands without publishing their changes
+    ///
+    /// Only functions flagged with `no-writes` may be called through `FCALL_RO`, and those may
+    /// not execute any write commands.
+    fn run_function(
+        &mut self,
+        ctx: &mut Context<Self>,
+        db: usize,
+        name: &[u8],
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        read_only_call: bool,
+    ) -> Response {
+        let read_only = match self
+            .libraries
+            .read()
+            .unwrap()
+            .function(&String::from_utf8_lossy(name))
+        {
+            Some(function) => function.is_read_only(),
+            None => return Response::Error(Error::NoSuchFunction),
+        };
+        if read_only_call && !read_only {
+            return Response::Error(Error::WriteFlagInReadOnlyCall);
+        }
+
+        let savepoint = self.savepoint();
+        let mut scripts = self.scripts.take().expect("Script interpreter in use");
+        let libraries = self.libraries.clone();
+        scripts.sync_functions(&libraries.read().unwrap());
+        let mut db = db;
+        let result = scripts.call_function(name, keys, args, |args| {
+            self.call_from_script(ctx, &mut db, read_only, args)
+        });
+        self.scripts = Some(scripts);
+        self.settle(result, savepoint)
+    }
+
+    /// Execute a command on behalf of a script
+    fn call_from_script(
+        &mut self,
+        ctx: &mut Context<Self>,
+        db: &mut usize,
+        read_only: bool,
+        args: Vec<Bytes>,
+    ) -> Response {
+        let command = match scripting::parse_script_command(&args) {
+            Ok(command) => command,
+            Err(err) => return Response::Error(err),
+        };
+        debug!("Executing command from script: {:?}", command);
+
+        match command {
+            Command::Select(index) if index < self.databases.len() => {
+                *db = index;
+                Response::Ok
+            }
+            Command::Select(_) => Response::Error(Error::DbIndexOutOfRange),
+            ref command if !command.allowed_in_scripts() => {
+                Response::Error(Error::NotAllowedFromScript)
+            }
+            ref command if read_only && command.writes() => {
+                Response::Error(Error::WriteFromReadOnlyScript)
+            }
+            command => self
+                .execute(ctx, Operation::new(command, *db))
+                .unwrap_or_else(|err| {
+                    Response::Error(Error::Reply(Bytes::from(format!("ERR {}", err))))
+                }),
+        }
+    }
+
+    /// Change the function libraries, marking them to be saved if they were changed successfully
+    fn update_libraries<F>(&mut self, update: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Scripts, &mut Libraries) -> Result<(), Error>,
+    {
+        let libraries = self.libraries.clone();
+        let mut libraries = libraries.write().unwrap();
+        update(self.scripts(), &mut libraries)?;
+        self.functions_changed = true;
+        Ok(())
+    }
+
+    /// Persist the function libraries if they changed, resolving once they were written
+    ///
+    /// The file is written by the `FunctionsFile` actor, on a thread of its own. If it can't be
+    /// written, the libraries stay changed in memory but the error is returned to the client.
+    fn save_functions(&mut self) -> Box<Future<Item = (), Error = Error>> {
+        if !std::mem::replace(&mut self.functions_changed, false) {
+            return Box::new(future::ok(()));
+        }
+        let (path, saver) = match (&self.functions_file, &self.functions_saver) {
+            (Some(path), Some(saver)) => (path.clone(), saver),
+            _ => return Box::new(future::ok(())),
+        };
+
+        let payload = self.libraries.read().unwrap().dump();
+        Box::new(saver.send(functions::Save(payload)).then(move |result| {
+            let err = match result {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(err)) => err.to_string(),
+                Err(err) => err.to_string(),
+            };
+            error!("Failed to save functions to {}: {}", path.display(), err);
+            Err(Error::Reply(Bytes::from(format!(
+                "ERR Failed to save functions: {}",
+                err
+            ))))
+        }))
+    }
+
+    /// Execute a single command without publishing its changes
+    fn execute(
+        &mut self,
+        ctx: &mut Context<Self>,
+        operation: Operation,
+    ) -> Result<Response, StorageError> {
+        let Operation { command, db } = operation;
+        if db >= self.databases.len() {
+            return Ok(Response::Error(Error::DbIndexOutOfRange));
+        }
+        if command.reads() {
+            return Ok(match command {
+                Command::FCallRO(name, keys, args) => {
+                    self.run_function(ctx, db, &name, keys, args, true)
+                }
+                Command::FunctionList(pattern, with_code) => self
+                    .libraries
+                    .read()
+                    .unwrap()
+                    .list(pattern.as_ref().map(|x| &x[..]), with_code),
+                Command::FunctionDump => Response::Bulk(self.libraries.read().unwrap().dump()),
+                command @ Command::PubSubChannels(_)
+                | command @ Command::PubSubNumSub(_)
+                | command @ Command::PubSubNumPat
+                | command @ Command::PubSubShardChannels(_)
+                | command @ Command::PubSubShardNumSub(_) => {
+                    self.subscriptions.read().unwrap().execute(command)
+                }
+                command => return self.read(db, command),
+            });
+        }
 
         self.operation_id += 1;
         let operation_id = self.operation_id;
 
-        let response = Ok(match operation.command {
-            Command::Set(key, value, expiration, conditional) => conditional
-                .when(self.writer.contains_key(&key), || {
-                    info!("Setting key {:?}", key);
-                    let expires_at = expiration.map(|x| clock::now() + x);
+        Ok(match command {
+            Command::Set(key, value, expiration, conditional) => {
+                let exists = self.contains_key(db, &key);
+                conditional
+                    .when(exists, || {
+                        info!("Setting key {:?}", key);
+                        let expires_at = expiration.map(|x| clock::now() + x);
 
-                    self.writer.update(
-                        key.clone(),
-                        Item {
-                            value: Value::String(value),
-                            meta: Metadata {
-                                expiration: expires_at,
-                                operation_id,
+                        if !exists {
+                            self.notify(EventClass::New, "new", db, &key);
+                        }
+                        self.notify(EventClass::String, "set", db, &key);
+
+                        self.put(
+                            db,
+                            key.clone(),
+                            Item {
+                                value: Value::String(value),
+                                meta: Metadata {
+                                    expiration: expires_at,
+                                    operation_id,
+                                },
                             },
-                        },
-                    );
+                        );
 
-                    if let Some(t) = expiration {
-                        self.expire(ctx, key, t);
-                    }
+                        if let Some(t) = expiration {
+                            self.notify(EventClass::Generic, "expire", db, &key);
+                            self.expire(ctx, db, key, t);
+                        }
 
-                    Response::Ok
-                })
-                .unwrap_or(Response::Nil),
+                        Response::Ok
+                    })
+                    .unwrap_or(Response::Nil)
+            }
             Command::Del(keys) => {
                 let mut updated = 0;
                 for key in keys {
-                    if self.writer.contains_key(&key) {
+                    if self.remove(db, key.clone()) {
+                        self.notify(EventClass::Generic, "del", db, &key);
                         updated += 1;
-                        self.writer.empty(key);
                     }
                 }
                 Response::Integer(updated)
             }
             Command::Expire(key, expiration) => self
-                .reader
-                .get_and(&key, get_item)
+                .get(db, &key)
                 .map(|Item { value, .. }| {
                     let expires_at = clock::now() + expiration;
-                    self.writer.update(
+                    self.put(
+                        db,
                         key.clone(),
                         Item {
                             value,
@@ -138,16 +687,18 @@ impl Handler<Operation> for Writer {
                         },
                     );
 
-                    self.expire(ctx, key, expiration);
+                    self.notify(EventClass::Generic, "expire", db, &key);
+                    self.expire(ctx, db, key, expiration);
 
                     Response::Integer(1)
                 })
                 .unwrap_or(Response::Integer(0)),
             Command::Persist(key) => self
-                .reader
-                .get_and(&key, get_item)
+                .get(db, &key)
                 .map(|Item { value, .. }| {
-                    self.writer.update(
+                    self.notify(EventClass::Generic, "persist", db, &key);
+                    self.put(
+                        db,
                         key,
                         Item {
                             value,
@@ -160,26 +711,1015 @@ impl Handler<Operation> for Writer {
                     Response::Integer(1)
                 })
                 .unwrap_or(Response::Integer(0)),
-            Command::FlushAll(_) | Command::FlushDB(_) => {
-                info!("Flushing the database");
-                self.writer.purge();
+            Command::Rename(source, destination) => match self.get(db, &source) {
+                Some(item) => {
+                    info!("Renaming key {:?} to {:?}", source, destination);
+                    self.notify(EventClass::Generic, "rename_from", db, &source);
+                    self.notify(EventClass::Generic, "rename_to", db, &destination);
+                    if source != destination {
+                        self.remove(db, source);
+                        self.transfer(ctx, db, destination, item);
+                    }
+                    Response::Ok
+                }
+                None => Response::Error(Error::NoSuchKey),
+            },
+            Command::RenameNX(source, destination) => match self.get(db, &source) {
+                Some(_) if self.contains_key(db, &destination) => Response::Integer(0),
+                Some(item) => {
+                    info!("Renaming key {:?} to {:?}", source, destination);
+                    self.notify(EventClass::Generic, "rename_from", db, &source);
+                    self.notify(EventClass::Generic, "rename_to", db, &destination);
+                    self.remove(db, source);
+                    self.transfer(ctx, db, destination, item);
+                    Response::Integer(1)
+                }
+                None => Response::Error(Error::NoSuchKey),
+            },
+            Command::Copy(_, _, Some(target), _) if target >= self.databases.len() => {
+                Response::Error(Error::DbIndexOutOfRange)
+            }
+            Command::Copy(ref source, ref destination, target, _)
+                if source == destination && target.unwrap_or(db) == db =>
+            {
+                Response::Error(Error::SameObject)
+            }
+            Command::Copy(source, destination, target, conditional) => {
+                let target = target.unwrap_or(db);
+                match self.get(db, &source) {
+                    Some(item) => conditional
+                        .when(self.contains_key(target, &destination), || {
+                            info!("Copying key {:?} to {:?}", source, destination);
+                            self.notify(EventClass::Generic, "copy_to", target, &destination);
+                            self.transfer(ctx, target, destination, item);
+                            Response::Integer(1)
+                        })
+                        .unwrap_or(Response::Integer(0)),
+                    None => Response::Integer(0),
+                }
+            }
+            Command::Move(_, target) if target >= self.databases.len() => {
+                Response::Error(Error::DbIndexOutOfRange)
+            }
+            Command::Move(_, target) if target == db => Response::Error(Error::SameObject),
+            Command::Move(key, target) => match self.get(db, &key) {
+                Some(_) if self.contains_key(target, &key) => Response::Integer(0),
+                Some(item) => {
+                    info!("Moving key {:?} to database {}", key, target);
+                    self.notify(EventClass::Generic, "move_from", db, &key);
+                    self.notify(EventClass::Generic, "move_to", target, &key);
+                    self.remove(db, key.clone());
+                    self.transfer(ctx, target, key, item);
+                    Response::Integer(1)
+                }
+                None => Response::Integer(0),
+            },
+            Command::SwapDB(a, b) if a >= self.databases.len() || b >= self.databases.len() => {
+                Response::Error(Error::DbIndexOutOfRange)
+            }
+            Command::SwapDB(a, b) => {
+                info!("Swapping databases {} and {}", a, b);
+                if a != b {
+                    self.swap(a, b);
+                }
+                Response::Ok
+            }
+            Command::Eval(script, keys, args) => match self.scripts().load(&script) {
+                Ok(sha) => self.run_script(ctx, db, sha.as_bytes(), keys, args),
+                Err(err) => Response::Error(err),
+            },
+            Command::EvalSha(sha, keys, args) => self.run_script(ctx, db, &sha, keys, args),
+            Command::ScriptLoad(script) => match self.scripts().load(&script) {
+                Ok(sha) => Response::Bulk(Bytes::from(sha)),
+                Err(err) => Response::Error(err),
+            },
+            Command::ScriptExists(shas) => Response::Array(
+                shas.iter()
+                    .map(|sha| Response::Integer(self.scripts().exists(sha) as i64))
+                    .collect(),
+            ),
+            Command::ScriptFlush => {
+                self.scripts().flush();
                 Response::Ok
             }
-            ref cmd if cmd.writes() => unimplemented!(),
+            Command::FCall(name, keys, args) => {
+                self.run_function(ctx, db, &name, keys, args, false)
+            }
+            Command::FunctionLoad(code, replace) => {
+                let mut name = String::new();
+                match self.update_libraries(|scripts, libraries| {
+                    let library = scripts.inspect_library(&code)?;
+                    name = library.name.clone();
+                    libraries.insert(library, replace)
+                }) {
+                    Ok(()) => {
+                        info!("Loaded function library {}", name);
+                        Response::Bulk(Bytes::from(name))
+                    }
+                    Err(err) => Response::Error(err),
+                }
+            }
+            Command::FunctionDelete(name) => {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                match self.update_libraries(|_, libraries| {
+                    if libraries.remove(&name) {
+                        Ok(())
+                    } else {
+                        Err(Error::NoSuchLibrary)
+                    }
+                }) {
+                    Ok(()) => {
+                        info!("Deleted function library {}", name);
+                        Response::Ok
+                    }
+                    Err(err) => Response::Error(err),
+                }
+            }
+            Command::FunctionFlush => {
+                info!("Flushing all function libraries");
+                match self.update_libraries(|_, libraries| {
+                    libraries.clear();
+                    Ok(())
+                }) {
+                    Ok(()) => Response::Ok,
+                    Err(err) => Response::Error(err),
+                }
+            }
+            Command::FunctionRestore(payload, policy) => {
+                match self.update_libraries(|scripts, libraries| {
+                    let restored = functions::parse_dump(&payload)?
+                        .iter()
+                        .map(|code| scripts.inspect_library(code))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    libraries.restore(restored, policy)
+                }) {
+                    Ok(()) => {
+                        info!("Restored function libraries ({:?})", policy);
+                        Response::Ok
+                    }
+                    Err(err) => Response::Error(err),
+                }
+            }
+            command @ Command::Publish(..) | command @ Command::SPublish(..) => {
+                self.subscriptions.read().unwrap().execute(command)
+            }
+            Command::FlushDB(_) => {
+                info!("Flushing database {}", db);
+                self.purge(db);
+                Response::Ok
+            }
+            Command::FlushAll(_) => {
+                info!("Flushing all databases");
+                for db in 0..self.databases.len() {
+                    self.purge(db);
+                }
+                Response::Ok
+            }
+            // Commands the command table knows, but storage doesn't implement (yet)
+            ref cmd if cmd.writes() => Response::Error(Error::UnknownCommand(Bytes::from_static(
+                cmd.spec().name.as_bytes(),
+            ))),
             _ => Err(StorageError::NoReadAccess)?,
-        });
+        })
+    }
+}
+impl Actor for Writer {
+    type Context = Context<Self>;
 
-        info!("COMMITTING");
-        self.writer.refresh();
-        response
+    fn started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Spawned writer with {} databases", self.databases.len());
+
+        if let Some(ref path) = self.functions_file {
+            let path = path.clone();
+            self.functions_saver = Some(SyncArbiter::start(1, move || {
+                FunctionsFile::new(path.clone())
+            }));
+        }
+    }
+}
+impl Supervised for Writer {}
+impl SystemService for Writer {}
+impl Handler<Subscribe> for Writer {
+    type Result = Subscription;
+
+    fn handle(&mut self, subscribe: Subscribe, _ctx: &mut Context<Self>) -> Self::Result {
+        self.readers.push(subscribe.0);
+        Subscription {
+            stores: self.databases.iter().map(|db| db.reader.clone()).collect(),
+            layout: self.published_layout.clone(),
+            libraries: self.libraries.clone(),
+            script_time_limit: self.scripts().time_limit(),
+            stats: self.stats.clone(),
+        }
     }
 }
 
-/// A subscription request to get a reader handle for a `Writer`'s dataset
-#[derive(Debug, Message)]
+impl Handler<Info> for Writer {
+    type Result = StorageInfo;
+
+    fn handle(&mut self, _: Info, _ctx: &mut Context<Self>) -> Self::Result {
+        use super::ops::*;
+
+        let now = Instant::now();
+        let keyspace = self
+            .layout
+            .iter()
+            .map(|&store| &self.databases[store])
+            .map(|db| {
+                let mut info = KeyspaceInfo::default();
+                let mut ttl = Duration::from_secs(0);
+                db.reader.for_each(|_, values| {
+                    let item = &values[0];
+                    if !is_live(item) {
+                        return;
+                    }
+                    info.keys += 1;
+                    if let Some(expiration) = item.meta.expiration {
+                        info.expires += 1;
+                        ttl += expiration - now;
+                    }
+                });
+                if info.expires > 0 {
+                    info.avg_ttl = ttl / info.expires as u32;
+                }
+                info
+            })
+            .collect();
+        let (pubsub_channels, pubsub_patterns, pubsub_shard_channels) =
+            self.subscriptions.read().unwrap().counts();
+
+        StorageInfo {
+            keyspace,
+            tracking_clients: self.tracking.clients(),
+            pubsub_channels,
+            pubsub_patterns,
+            pubsub_shard_channels,
+        }
+    }
+}
+
+impl Handler<Operation> for Writer {
+    type Result = Box<Future<Item = Response, Error = StorageError>>;
+
+    fn handle(&mut self, operation: Operation, ctx: &mut Context<Self>) -> Self::Result {
+        debug_assert!(operation.command.writes());
+
+        let response = self.execute(ctx, operation);
+        self.commit();
+        match response {
+            Ok(response) => Box::new(self.save_functions().then(
+                |saved| -> Result<_, StorageError> {
+                    Ok(saved.map(|()| response).unwrap_or_else(Response::Error))
+                },
+            )),
+            Err(err) => Box::new(future::err(err)),
+        }
+    }
+}
+
+impl Handler<Transaction> for Writer {
+    type Result = Box<Future<Item = Option<Vec<Response>>, Error = StorageError>>;
+
+    fn handle(&mut self, transaction: Transaction, ctx: &mut Context<Self>) -> Self::Result {
+        info!(
+            "Executing transaction of {} commands",
+            transaction.operations.len()
+        );
+
+        if let Some(client) = transaction.watcher {
+            let dirty = self.is_dirty(client);
+            self.unwatch(client);
+            if dirty {
+                info!("Aborting transaction because watched keys were modified");
+                return Box::new(future::ok(None));
+            }
+        }
+
+        let savepoint = self.savepoint();
+        let mut responses = Vec::with_capacity(transaction.operations.len());
+        // The commands that changed the function libraries, which fail if they can't be saved
+        let mut function_updates = Vec::new();
+        for operation in transaction.operations {
+            let changed = std::mem::replace(&mut self.functions_changed, false);
+            responses.push(match operation.command {
+                // The connection tracks its selected database itself
+                Command::Select(db) if db < self.databases.len() => Response::Ok,
+                Command::Select(_) => Response::Error(Error::DbIndexOutOfRange),
+                // Watches are always cleared by executing the transaction
+                Command::Unwatch => Response::Ok,
+                _ => match self.execute(ctx, operation) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        info!("Discarding transaction that failed: {}", err);
+                        self.rollback(savepoint);
+                        return Box::new(future::err(err));
+                    }
+                },
+            });
+            if self.functions_changed {
+                function_updates.push(responses.len() - 1);
+            }
+            self.functions_changed |= changed;
+        }
+
+        self.commit();
+        Box::new(
+            self.save_functions()
+                .then(move |saved| -> Result<_, StorageError> {
+                    if let Err(err) = saved {
+                        for index in function_updates {
+                            responses[index] = Response::Error(Error::Reply(err.message()));
+                        }
+                    }
+                    Ok(Some(responses))
+                }),
+        )
+    }
+}
+
+impl Handler<SetKeyspaceEvents> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, events: SetKeyspaceEvents, _ctx: &mut Context<Self>) {
+        info!("Publishing keyspace events: {}", events.0);
+        self.keyspace_events = events.0;
+    }
+}
+
+impl Handler<SetScriptTimeLimit> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, limit: SetScriptTimeLimit, _ctx: &mut Context<Self>) {
+        info!("Limiting script execution time to {:?}", limit.0);
+        self.scripts().set_time_limit(limit.0);
+        // Readers that have stopped are forgotten
+        self.readers
+            .retain(|reader| reader.do_send(SetScriptTimeLimit(limit.0)).is_ok());
+    }
+}
+
+impl Handler<EnableTracking> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, enable: EnableTracking, _ctx: &mut Context<Self>) {
+        let EnableTracking {
+            client,
+            recipient,
+            redirected,
+            prefixes,
+        } = enable;
+        self.tracking
+            .enable(client, recipient, redirected, prefixes);
+    }
+}
+
+impl Handler<DisableTracking> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, disable: DisableTracking, _ctx: &mut Context<Self>) {
+        self.tracking.disable(disable.client);
+    }
+}
+
+impl Handler<Track> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, track: Track, _ctx: &mut Context<Self>) {
+        self.tracking.track(track.client, track.keys);
+    }
+}
+
+impl Handler<Watch> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, watch: Watch, _ctx: &mut Context<Self>) {
+        let Watch { client, db, keys } = watch;
+        for key in keys {
+            let live = self.contains_key(db, &key);
+            self.watchers
+                .entry((db, key.clone()))
+                .or_insert_with(HashSet::new)
+                .insert(client);
+            self.watches
+                .entry(client)
+                .or_insert_with(WatchState::default)
+                .keys
+                .push((db, key, live));
+        }
+    }
+}
+
+impl Handler<Unwatch> for Writer {
+    type Result = ();
+
+    fn handle(&mut self, unwatch: Unwatch, _ctx: &mut Context<Self>) {
+        self.unwatch(unwatch.client);
+    }
+}
+
+/// A subscription request to get reader handles for a `Writer`'s databases
+///
+/// The reader is notified when the script time limit changes.
+#[derive(Message)]
 #[rtype(result = "Subscription")]
-pub struct Subscribe;
+pub struct Subscribe(pub Recipient<SetScriptTimeLimit>);
+
+/// A request for the number of keys (and other state) the `Writer` holds, for `INFO`
+#[derive(Debug, Message)]
+#[rtype(result = "StorageInfo")]
+pub struct Info;
+
+/// A request to change the maximum execution time of scripts
+#[derive(Debug, Message)]
+pub struct SetScriptTimeLimit(pub Duration);
+
+/// A request to change the keyspace events that are published
+#[derive(Debug, Message)]
+pub struct SetKeyspaceEvents(pub KeyspaceEvents);
+
+/// A request to send invalidations for the keys a client reads (or that match the given
+/// prefixes, in broadcasting mode) to a connection
+#[derive(Message)]
+pub struct EnableTracking {
+    pub client: Uuid,
+    pub recipient: Recipient<Invalidate>,
+    /// Whether the recipient is a different connection than the client (`REDIRECT`)
+    pub redirected: bool,
+    pub prefixes: Option<Vec<Bytes>>,
+}
+
+/// A request to stop sending invalidations for a client
+#[derive(Debug, Message)]
+pub struct DisableTracking {
+    pub client: Uuid,
+}
 
-/// A reader handle for a `Writer`'s dataset
+/// A notification that a tracking client is about to read (and possibly cache) the given keys
+#[derive(Debug, Message)]
+pub struct Track {
+    pub client: Uuid,
+    pub keys: Vec<Key>,
+}
+
+/// A request to abort a client's next transaction if any of the given keys are modified
+#[derive(Debug, Message)]
+pub struct Watch {
+    pub client: Uuid,
+    pub db: usize,
+    pub keys: Vec<Key>,
+}
+
+/// A request to stop watching all keys for a client
+#[derive(Debug, Message)]
+pub struct Unwatch {
+    pub client: Uuid,
+}
+
+/// Reader handles for a `Writer`'s databases, along with the state readers need to run
+/// read-only functions
 #[derive(MessageResponse)]
-pub struct Subscription(pub ReadHandle<Key, Item>);
+pub struct Subscription {
+    /// Reader handles, in the order the databases were created
+    pub stores: Vec<ReadHandle<Key, Item>>,
+    /// The reader handle each database number refers to (changed by `SWAPDB`)
+    pub layout: Arc<RwLock<Vec<usize>>>,
+    /// The loaded function libraries
+    pub libraries: Arc<RwLock<Libraries>>,
+    /// The maximum execution time of scripts
+    pub script_time_limit: Duration,
+    /// The server counters
+    pub stats: Arc<Stats>,
+}
+
+/// The keys stored in a single database
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyspaceInfo {
+    /// The number of non-expired keys
+    pub keys: usize,
+    /// The number of non-expired keys with an expiration
+    pub expires: usize,
+    /// The average remaining time to live of the keys with an expiration
+    pub avg_ttl: Duration,
+}
+
+/// The state of a `Writer`, as reported by `INFO`
+#[derive(Debug, MessageResponse)]
+pub struct StorageInfo {
+    /// The keys of each database, indexed by database number
+    pub keyspace: Vec<KeyspaceInfo>,
+    /// The number of clients with tracking enabled
+    pub tracking_clients: usize,
+    /// The number of channels with subscribers
+    pub pubsub_channels: usize,
+    /// The number of subscribed patterns
+    pub pubsub_patterns: usize,
+    /// The number of shard channels with subscribers
+    pub pubsub_shard_channels: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use futures::{future, Future};
+
+    use crate::protocol::{Conditional, RestorePolicy};
+
+    /// Run a test against a new writer on a running system, returning its outcome
+    fn run<F, T>(writer: Writer, test: F) -> T
+    where
+        F: FnOnce(Addr<Writer>) -> Box<Future<Item = T, Error = MailboxError>> + 'static,
+        T: 'static,
+    {
+        let outcome = Rc::new(RefCell::new(None));
+        let result = outcome.clone();
+        System::run(move || {
+            Arbiter::spawn(test(writer.start()).then(move |outcome| {
+                *result.borrow_mut() = Some(outcome.expect("Writer stopped"));
+                System::current().stop();
+                Ok(())
+            }));
+        });
+        let outcome = outcome.borrow_mut().take();
+        outcome.expect("Test didn't finish")
+    }
+
+    /// Execute commands (in order) against the first database
+    fn send(
+        writer: &Addr<Writer>,
+        commands: Vec<Command>,
+    ) -> Box<Future<Item = Vec<Response>, Error = MailboxError>> {
+        let sent: Vec<_> = commands
+            .into_iter()
+            .map(|command| {
+                writer
+                    .send(Operation::from(command))
+                    .map(|response| response.expect("Storage error"))
+            })
+            .collect();
+        Box::new(future::join_all(sent))
+    }
+
+    /// Execute commands as a single transaction against the first database
+    fn transaction(
+        writer: &Addr<Writer>,
+        commands: Vec<Command>,
+    ) -> Box<Future<Item = Vec<Response>, Error = MailboxError>> {
+        let operations = commands.into_iter().map(Operation::from).collect();
+        Box::new(
+            writer
+                .send(Transaction {
+                    operations,
+                    watcher: None,
+                })
+                .map(|responses| {
+                    responses
+                        .expect("Storage error")
+                        .expect("Transaction aborted")
+                }),
+        )
+    }
+
+    /// Execute commands against a new writer
+    fn execute(commands: Vec<Command>) -> Vec<Response> {
+        run(Writer::default(), move |writer| send(&writer, commands))
+    }
+
+    fn bytes(values: &[&str]) -> Vec<Bytes> {
+        values.iter().map(|&x| Bytes::from(x)).collect()
+    }
+
+    fn eval(script: &str, keys: &[&str], args: &[&str]) -> Command {
+        Command::Eval(Bytes::from(script), bytes(keys), bytes(args))
+    }
+
+    /// Build an `FCALL` or `FCALL_RO` command
+    fn fcall(
+        command: fn(Bytes, Vec<Bytes>, Vec<Bytes>) -> Command,
+        function: &str,
+        keys: &[&str],
+        args: &[&str],
+    ) -> Command {
+        command(Bytes::from(function), bytes(keys), bytes(args))
+    }
+
+    fn is_error(response: &Response) -> bool {
+        match response {
+            Response::Error(_) => true,
+            _ => false,
+        }
+    }
+
+    const LIBRARY: &str = "#!lua name=lib
+redis.register_function('echo', function(keys, args) return {keys[1], args[1]} end)
+redis.register_function{
+    function_name = 'get',
+    callback = function(keys) return redis.call('get', keys[1]) end,
+    flags = {'no-writes'},
+}
+redis.register_function{
+    function_name = 'sneaky_set',
+    callback = function(keys) return redis.call('set', keys[1], 'value') end,
+    flags = {'no-writes'},
+}
+";
+
+    fn is_script_error(response: &Response) -> bool {
+        match response {
+            Response::Error(Error::Reply(msg)) => msg.starts_with(b"ERR Error running script"),
+            _ => false,
+        }
+    }
+
+    fn bulk(data: &str) -> Response {
+        Response::Bulk(Bytes::from(data))
+    }
+
+    fn set(key: &str, value: &str, ttl: Option<Duration>) -> Command {
+        Command::Set(
+            Bytes::from(key),
+            Bytes::from(value),
+            ttl,
+            Conditional::Always,
+        )
+    }
+
+    #[test]
+    fn scripts_get_their_keys_and_arguments() {
+        let responses = execute(vec![
+            eval(
+                "return {KEYS[1], ARGV[1], #ARGV}",
+                &["key"],
+                &["arg", "more"],
+            ),
+            eval("return KEYS[1]", &[], &[]),
+            eval("leaked = 1", &[], &[]),
+            eval("return leaked", &[], &[]),
+        ]);
+        assert_eq!(
+            responses[0],
+            Response::Array(vec![bulk("key"), bulk("arg"), Response::Integer(2)])
+        );
+        assert_eq!(responses[1], Response::Nil);
+        assert!(is_script_error(&responses[2]), "{:?}", responses[2]);
+        assert_eq!(responses[3], Response::Nil);
+    }
+
+    #[test]
+    fn scripts_are_cached_until_flushed() {
+        let script = "return redis.call('get', KEYS[1])";
+        let sha = Bytes::from(scripting::sha1_hex(script.as_bytes()));
+        let unknown = Bytes::from(scripting::sha1_hex(b"return 1"));
+        let evalsha = || Command::EvalSha(sha.clone(), vec![Bytes::from("key")], vec![]);
+        let responses = execute(vec![
+            evalsha(),
+            Command::ScriptLoad(Bytes::from(script)),
+            Command::Set(
+                Bytes::from("key"),
+                Bytes::from("value"),
+                None,
+                Conditional::Always,
+            ),
+            evalsha(),
+            Command::ScriptExists(vec![sha.clone(), unknown]),
+            Command::ScriptFlush,
+            Command::ScriptExists(vec![sha.clone()]),
+            evalsha(),
+        ]);
+        assert_eq!(responses[0], Response::Error(Error::NoScript));
+        assert_eq!(responses[1], Response::Bulk(sha.clone()));
+        assert_eq!(responses[3], bulk("value"));
+        assert_eq!(
+            responses[4],
+            Response::Array(vec![Response::Integer(1), Response::Integer(0)])
+        );
+        assert_eq!(responses[5], Response::Ok);
+        assert_eq!(responses[6], Response::Array(vec![Response::Integer(0)]));
+        assert_eq!(responses[7], Response::Error(Error::NoScript));
+    }
+
+    #[test]
+    fn failed_scripts_are_rolled_back() {
+        let responses = execute(vec![
+            eval(
+                "redis.call('set', KEYS[1], 'value'); error('failed')",
+                &["key"],
+                &[],
+            ),
+            eval("return redis.call('get', KEYS[1])", &["key"], &[]),
+            eval(
+                "redis.call('set', KEYS[1], 'value'); return redis.error_reply('failed')",
+                &["key"],
+                &[],
+            ),
+            eval("return redis.call('get', KEYS[1])", &["key"], &[]),
+        ]);
+        assert!(is_script_error(&responses[0]), "{:?}", responses[0]);
+        assert_eq!(responses[1], Response::Nil);
+        assert_eq!(responses[3], bulk("value"));
+    }
+
+    #[test]
+    fn functions_are_called_with_keys_and_arguments() {
+        let responses = run(Writer::default(), |writer| {
+            transaction(
+                &writer,
+                vec![
+                    Command::FunctionLoad(Bytes::from(LIBRARY), false),
+                    fcall(Command::FCall, "echo", &["key"], &["arg"]),
+                    Command::Set(
+                        Bytes::from("key"),
+                        Bytes::from("value"),
+                        None,
+                        Conditional::Always,
+                    ),
+                    fcall(Command::FCallRO, "get", &["key"], &[]),
+                    fcall(Command::FCallRO, "echo", &["key"], &["arg"]),
+                    fcall(Command::FCall, "missing", &[], &[]),
+                ],
+            )
+        });
+        assert_eq!(responses[0], bulk("lib"));
+        assert_eq!(
+            responses[1],
+            Response::Array(vec![bulk("key"), bulk("arg")])
+        );
+        assert_eq!(responses[3], bulk("value"));
+        assert_eq!(
+            responses[4],
+            Response::Error(Error::WriteFlagInReadOnlyCall)
+        );
+        assert_eq!(responses[5], Response::Error(Error::NoSuchFunction));
+    }
+
+    #[test]
+    fn no_writes_functions_cant_write() {
+        let responses = run(Writer::default(), |writer| {
+            transaction(
+                &writer,
+                vec![
+                    Command::FunctionLoad(Bytes::from(LIBRARY), false),
+                    fcall(Command::FCall, "sneaky_set", &["key"], &[]),
+                    fcall(Command::FCallRO, "sneaky_set", &["key"], &[]),
+                    Command::Exists(bytes(&["key"])),
+                ],
+            )
+        });
+        assert!(is_error(&responses[1]), "{:?}", responses[1]);
+        assert!(is_error(&responses[2]), "{:?}", responses[2]);
+        assert_eq!(responses[3], Response::Integer(0));
+    }
+
+    #[test]
+    fn libraries_are_sandboxed() {
+        let library = |body: &str| {
+            let code = format!("#!lua name=lib\n{}\n", body);
+            Command::FunctionLoad(Bytes::from(code), true)
+        };
+        let register = "redis.register_function('f', function() leaked = 1 end)";
+        let responses = execute(vec![
+            library(&format!("os.execute('id') {}", register)),
+            library(&format!("counter = 0 {}", register)),
+            library(register),
+            fcall(Command::FCall, "f", &[], &[]),
+        ]);
+        assert!(is_error(&responses[0]), "{:?}", responses[0]);
+        assert!(is_error(&responses[1]), "{:?}", responses[1]);
+        assert_eq!(responses[2], bulk("lib"));
+        assert!(is_error(&responses[3]), "{:?}", responses[3]);
+    }
+
+    #[test]
+    fn functions_survive_a_dump_and_restore() {
+        let responses = run(Writer::default(), |writer| {
+            let dumped = transaction(
+                &writer,
+                vec![
+                    Command::FunctionLoad(Bytes::from(LIBRARY), false),
+                    Command::FunctionDump,
+                ],
+            );
+            Box::new(dumped.and_then(move |responses| {
+                let payload = match responses[1] {
+                    Response::Bulk(ref payload) => payload.clone(),
+                    ref response => panic!("Unexpected response: {:?}", response),
+                };
+                let echo = || fcall(Command::FCall, "echo", &["key"], &[]);
+                transaction(
+                    &writer,
+                    vec![
+                        Command::FunctionFlush,
+                        echo(),
+                        Command::FunctionRestore(payload.clone(), RestorePolicy::Append),
+                        echo(),
+                        Command::FunctionRestore(payload, RestorePolicy::Append),
+                    ],
+                )
+            }))
+        });
+        assert_eq!(responses[0], Response::Ok);
+        assert_eq!(responses[1], Response::Error(Error::NoSuchFunction));
+        assert_eq!(responses[2], Response::Ok);
+        assert_eq!(responses[3], Response::Array(vec![bulk("key")]));
+        assert!(is_error(&responses[4]), "{:?}", responses[4]);
+    }
+
+    #[test]
+    fn functions_are_saved_to_the_functions_file() {
+        let root = std::env::temp_dir().join(format!("evredis-test-{}", Uuid::new_v4()));
+        let path = root.join("functions.dump");
+        let writer = Writer::default().with_functions_file(path.clone());
+        let responses = run(writer, |writer| {
+            send(
+                &writer,
+                vec![Command::FunctionLoad(Bytes::from(LIBRARY), false)],
+            )
+        });
+        let saved = functions::load_dump(&path);
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(responses[0], bulk("lib"));
+        assert_eq!(saved.unwrap(), vec![Bytes::from(LIBRARY)]);
+    }
+
+    #[test]
+    fn failing_to_save_functions_is_an_error() {
+        // The functions file can't be created inside a regular file
+        let root = std::env::temp_dir().join(format!("evredis-test-{}", Uuid::new_v4()));
+        std::fs::write(&root, b"").unwrap();
+        let writer = Writer::default().with_functions_file(root.join("functions.dump"));
+        let responses = run(writer, |writer| {
+            send(
+                &writer,
+                vec![Command::FunctionLoad(Bytes::from(LIBRARY), false)],
+            )
+        });
+        let _ = std::fs::remove_file(&root);
+
+        match responses[0] {
+            Response::Error(Error::Reply(ref msg)) => {
+                assert!(
+                    msg.starts_with(b"ERR Failed to save functions"),
+                    "{:?}",
+                    msg
+                )
+            }
+            ref response => panic!("Unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn scripts_are_killed_after_the_time_limit() {
+        let writer = Writer::default().with_script_time_limit(Duration::from_millis(50));
+        let responses = run(writer, |writer| {
+            send(
+                &writer,
+                vec![
+                    eval(
+                        "redis.call('set', KEYS[1], 'value') while true do end",
+                        &["key"],
+                        &[],
+                    ),
+                    eval("return redis.call('get', KEYS[1])", &["key"], &[]),
+                ],
+            )
+        });
+        match responses[0] {
+            Response::Error(Error::Reply(ref msg)) => assert!(
+                String::from_utf8_lossy(msg).contains("Script killed by timeout"),
+                "{:?}",
+                msg
+            ),
+            ref response => panic!("Unexpected response: {:?}", response),
+        }
+        assert_eq!(responses[1], Response::Nil);
+    }
+
+    #[test]
+    fn scripts_select_databases_of_their_own() {
+        let get = "return redis.call('get', KEYS[1])";
+        let responses = execute(vec![
+            eval(
+                "redis.call('select', 1) return redis.call('set', KEYS[1], 'value')",
+                &["key"],
+                &[],
+            ),
+            eval(get, &["key"], &[]),
+            eval(&format!("redis.call('select', 1) {}", get), &["key"], &[]),
+        ]);
+        assert_eq!(
+            responses,
+            vec![
+                Response::Status(Bytes::from("OK")),
+                Response::Nil,
+                bulk("value")
+            ]
+        );
+    }
+
+    #[test]
+    fn moved_and_swapped_keys_keep_their_expiration() {
+        let keyspace = run(Writer::default(), |writer| {
+            let ttl = Some(Duration::from_secs(100));
+            let info = writer.clone();
+            Box::new(
+                send(
+                    &writer,
+                    vec![
+                        set("moved", "value", ttl),
+                        Command::Move(Bytes::from("moved"), 2),
+                        set("swapped", "value", ttl),
+                        set("kept", "value", None),
+                        Command::SwapDB(0, 1),
+                    ],
+                )
+                .and_then(move |_| info.send(Info))
+                .map(|info| info.keyspace),
+            )
+        });
+        let counts: Vec<_> = keyspace[..3]
+            .iter()
+            .map(|info| (info.keys, info.expires))
+            .collect();
+        assert_eq!(counts, vec![(0, 0), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn renamed_and_copied_keys_keep_their_expiration() {
+        let (responses, keyspace) = run(Writer::default(), |writer| {
+            let ttl = Some(Duration::from_secs(100));
+            let key = Bytes::from;
+            let info = writer.clone();
+            Box::new(
+                send(
+                    &writer,
+                    vec![
+                        set("renamed", "value", ttl),
+                        Command::Rename(key("renamed"), key("destination")),
+                        set("renamednx", "value", ttl),
+                        Command::RenameNX(key("renamednx"), key("destinationnx")),
+                        set("copied", "value", ttl),
+                        Command::Copy(key("copied"), key("copy"), None, Conditional::IfNotExists),
+                        set("persistent", "value", None),
+                        set("overwritten", "value", ttl),
+                        Command::Rename(key("persistent"), key("overwritten")),
+                    ],
+                )
+                .and_then(move |responses| info.send(Info).map(|info| (responses, info.keyspace))),
+            )
+        });
+        assert_eq!(responses[1], Response::Ok);
+        assert_eq!(responses[3], Response::Integer(1));
+        assert_eq!(responses[5], Response::Integer(1));
+        assert_eq!(responses[8], Response::Ok);
+        // `destination`, `destinationnx`, `copied` and `copy` expire, `overwritten` doesn't
+        assert_eq!((keyspace[0].keys, keyspace[0].expires), (5, 4));
+    }
+
+    #[test]
+    fn swapping_databases_modifies_watched_keys() {
+        let client = Uuid::new_v4();
+        let aborted = run(Writer::default(), move |writer| {
+            writer.do_send(Watch {
+                client,
+                db: 1,
+                keys: vec![Bytes::from("key")],
+            });
+            let exec = writer.clone();
+            Box::new(
+                send(
+                    &writer,
+                    vec![set("key", "value", None), Command::SwapDB(0, 1)],
+                )
+                .and_then(move |_| {
+                    exec.send(Transaction {
+                        operations: vec![Operation::from(set("key", "other", None))],
+                        watcher: Some(client),
+                    })
+                })
+                .map(|responses| responses.expect("Storage error").is_none()),
+            )
+        });
+        assert!(aborted);
+    }
+
+    #[test]
+    fn scripts_cant_reach_the_system() {
+        let responses = execute(vec![
+            eval("return os.execute('id')", &[], &[]),
+            eval("return io.open('/etc/passwd'):read('*a')", &[], &[]),
+            eval("return loadstring('return 1')()", &[], &[]),
+            eval("string.rep = nil", &[], &[]),
+            eval("return string.rep('a', 2)", &[], &[]),
+        ]);
+        assert!(
+            responses[..4].iter().all(is_script_error),
+            "{:?}",
+            responses
+        );
+        assert_eq!(responses[4], Response::Bulk(Bytes::from("aa")));
+    }
+}
@@ -1,9 +1,11 @@
 //! Utilities related to configuration loading
 
+use std::path::PathBuf;
+
 use quick_error::quick_error;
 
 use app_dirs::*;
-use config::{Config, ConfigError, Environment, File};
+use config::{Config, ConfigError, Environment, File, FileFormat};
 use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 
@@ -30,6 +32,17 @@ quick_error! {
     }
 }
 
+/// Where to load a configuration from (besides the environment)
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    /// A file to load instead of the default locations
+    pub file: Option<PathBuf>,
+    /// Values taking precedence over all other layers, by dotted key (like `server.databases`)
+    ///
+    /// Values are parsed as TOML (like `4` or `["a", "b"]`), or taken as a string otherwise.
+    pub overrides: Vec<(String, String)>,
+}
+
 /// A configuration that can be loaded from multiple layers (files and environment)
 pub trait Configuration: DeserializeOwned {
     /// A semver version requirement on the loaded configuration
@@ -49,37 +62,60 @@ pub trait Configuration: DeserializeOwned {
         Ok(s.try_into()?)
     }
 
-    /// Load a configuration from the environment and several files
+    /// The configuration files merged by `load` (without extension), in order of increasing
+    /// precedence, and whether they are required
     ///
     /// Default locations include the system-wide and user-specific configuration dirs
     /// (different per OS), and (if the EVREDIS_DEBUG environment variable is set) the `config`
     /// directory in the current working dir.
-    fn load() -> Result<Self, ConfigurationError> {
-        let version_req =
-            VersionReq::parse(Self::VERSION_REQUIREMENT).expect("Invalid version requirement");
-
+    fn locations() -> Result<Vec<(PathBuf, bool)>, ConfigurationError> {
         let _ = dotenv::dotenv();
         let (has_debug, is_debug) = std::env::var("EVREDIS_DEBUG")
             .map(|x| (true, str::parse::<bool>(&x).unwrap_or(true)))
             .unwrap_or((false, false));
 
-        let mut s = Config::new();
+        let mut locations = Vec::new();
+        for data_type in &[AppDataType::SharedConfig, AppDataType::UserConfig] {
+            let mut root = get_data_root(*data_type)?;
+            root.push("evredis");
+            root.push("evredis");
+            locations.push((root, false));
+        }
+        if has_debug {
+            locations.push((PathBuf::from("config/evredis"), true));
+            if is_debug {
+                locations.push((PathBuf::from("config/evredis-debug"), true));
+            }
+        }
+        Ok(locations)
+    }
 
-        let mut root = get_data_root(AppDataType::SharedConfig)?;
-        root.push("evredis");
-        root.push("evredis");
-        s.merge(File::from(root).required(false))?;
+    /// The existing TOML file with the highest precedence among the ones merged by `load`
+    fn file() -> Result<Option<PathBuf>, ConfigurationError> {
+        Ok(Self::locations()?
+            .into_iter()
+            .map(|(path, _)| path.with_extension("toml"))
+            .filter(|path| path.exists())
+            .last())
+    }
 
-        root = get_data_root(AppDataType::UserConfig)?;
-        root.push("evredis");
-        root.push("evredis");
-        s.merge(File::from(root).required(false))?;
+    /// Load a configuration from the environment and several files (see `locations`)
+    fn load() -> Result<Self, ConfigurationError> {
+        Self::load_from(&Sources::default())
+    }
 
-        if has_debug {
-            s.merge(File::with_name("config/evredis").required(true))?;
+    /// Load a configuration from the environment and the given sources
+    fn load_from(sources: &Sources) -> Result<Self, ConfigurationError> {
+        let version_req =
+            VersionReq::parse(Self::VERSION_REQUIREMENT).expect("Invalid version requirement");
 
-            if is_debug {
-                s.merge(File::with_name("config/evredis-debug").required(true))?;
+        let mut s = Config::new();
+        if let Some(ref path) = sources.file {
+            let _ = dotenv::dotenv();
+            s.merge(File::from(path.clone()).format(FileFormat::Toml))?;
+        } else {
+            for (path, required) in Self::locations()? {
+                s.merge(File::from(path).required(required))?;
             }
         }
 
@@ -89,6 +125,13 @@ pub trait Configuration: DeserializeOwned {
                 .ignore_empty(true),
         )?;
 
+        for (key, value) in &sources.overrides {
+            s.merge(File::from_str(
+                &override_toml(key, value)?,
+                FileFormat::Toml,
+            ))?;
+        }
+
         #[derive(Default, Debug, Clone, Deserialize)]
         #[serde(default)]
         struct TestConfiguration {
@@ -110,9 +153,40 @@ pub trait Configuration: DeserializeOwned {
     }
 }
 
+/// A TOML document setting a single (dotted) key
+fn override_toml(key: &str, value: &str) -> Result<String, ConfigError> {
+    let value = toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()));
+    let document = key.rsplit('.').fold(value, |value, key| {
+        let mut table = toml::value::Table::new();
+        table.insert(key.to_owned(), value);
+        toml::Value::Table(table)
+    });
+    toml::to_string(&document).map_err(|err| ConfigError::Message(err.to_string()))
+}
+
 /// A configuration metadata section
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct MetaConfiguration {
     pub version: Option<Version>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_are_parsed_as_toml() {
+        assert_eq!(
+            override_toml("server.databases", "4").unwrap(),
+            "[server]\ndatabases = 4\n"
+        );
+        assert_eq!(
+            override_toml("logging.level", "debug").unwrap(),
+            "[logging]\nlevel = \"debug\"\n"
+        );
+    }
+}
@@ -0,0 +1,26 @@
+//! Comparisons that don't leak where secrets differ through their timing
+
+/// Check whether two byte strings are equal, in a time that only depends on their lengths
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).cloned().unwrap_or(0);
+        let y = b.get(i).cloned().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eq_compares_contents_and_lengths() {
+        assert!(eq(b"secret", b"secret"));
+        assert!(eq(b"", b""));
+        assert!(!eq(b"secret", b"secreT"));
+        assert!(!eq(b"secret", b"secret\0"));
+        assert!(!eq(b"", b"\0"));
+    }
+}
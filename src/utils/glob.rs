@@ -0,0 +1,141 @@
+//! Redis-style glob pattern matching
+
+/// Check whether a byte string matches a glob-style pattern
+///
+/// Supports `*` (any sequence), `?` (any single byte), `[...]` (character classes, with `^`
+/// negation and `a-z` ranges) and `\` escapes, just like Redis' `KEYS` and `PSUBSCRIBE`.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position to resume from after the last `*`, for backtracking
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((p, t));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                byte => {
+                    if byte == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match backtrack {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&x| x == b'*')
+}
+
+/// Match a single byte against the character class starting at `start`, returning whether it
+/// matched and the position after the class
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= low <= byte && byte <= high;
+            p += 3;
+        } else {
+            matched |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+
+    if p >= pattern.len() {
+        return None;
+    }
+
+    Some((matched != negate, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_literals() {
+        assert!(matches(b"hello", b"hello"));
+        assert!(!matches(b"hello", b"hell"));
+        assert!(!matches(b"hell", b"hello"));
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"h*o", b"hello"));
+        assert!(matches(b"h*l*o", b"hello"));
+        assert!(matches(b"h?llo", b"hallo"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(matches(b"news.*", b"news.art.figurative"));
+        assert!(!matches(b"news.*", b"sports.news"));
+    }
+
+    #[test]
+    fn glob_matches_classes() {
+        assert!(matches(b"h[ae]llo", b"hello"));
+        assert!(!matches(b"h[ae]llo", b"hillo"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-b]llo", b"hbllo"));
+        assert!(!matches(b"h[a-b]llo", b"hcllo"));
+    }
+
+    #[test]
+    fn glob_matches_escapes() {
+        assert!(matches(b"h\\*llo", b"h*llo"));
+        assert!(!matches(b"h\\*llo", b"hello"));
+    }
+}
@@ -0,0 +1,58 @@
+//! Redis Cluster hash slots
+
+/// The number of hash slots the keyspace is divided into
+pub const SLOTS: u16 = 16384;
+
+/// Compute the hash slot of a key (or sharded channel)
+///
+/// If the key contains a non-empty hash tag (`{...}`), only the tag is hashed, so that related
+/// keys can be forced into the same slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|&x| x == b'{')
+        .and_then(|start| {
+            key[start + 1..]
+                .iter()
+                .position(|&x| x == b'}')
+                .filter(|&len| len > 0)
+                .map(|len| &key[start + 1..start + 1 + len])
+        })
+        .unwrap_or(key);
+
+    crc16(hashed) % SLOTS
+}
+
+/// CRC16 (XMODEM variant), as used by Redis Cluster
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_matches_reference() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn key_slot_uses_hash_tags() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+}
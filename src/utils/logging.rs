@@ -1,10 +1,14 @@
 //! Utilities related to logging
 
-use serde_derive::Deserialize;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde_derive::{Deserialize, Serialize};
 use slog::{o, Drain};
 
 /// A logging output format
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum Format {
     /// Full (line-by-line) text
     Full,
@@ -15,7 +19,7 @@ pub enum Format {
 }
 
 /// A configuration to construct loggers from
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(default)]
 pub struct LoggingConfiguration {
     /// Output format
@@ -73,9 +77,7 @@ impl LoggingConfiguration {
         if let Some(ref level) = self.level {
             filter = filter.filter(
                 None,
-                level
-                    .parse::<slog::FilterLevel>()
-                    .unwrap_or(slog::FilterLevel::Warning),
+                parse_level(level).unwrap_or(slog::FilterLevel::Warning),
             );
         };
         if let Some(ref filter_expr) = self.filter {
@@ -85,29 +87,39 @@ impl LoggingConfiguration {
         slog_async::Async::new(filter.build().fuse()).build().fuse()
     }
 
-    /// Construct a new `Logger` that adheres to the configuration
-    pub fn create_logger(&self) -> slog::Logger {
+    /// Construct a root `Logger` for a drain, with the configured logging context
+    fn root<D>(&self, drain: D) -> slog::Logger
+    where
+        D: Drain<Ok = (), Err = slog::Never> + Send + Sync + UnwindSafe + RefUnwindSafe + 'static,
+    {
         let module = slog::FnValue(move |info| info.module());
         let filename = slog::FnValue(move |info| format!("{}:{}", info.file(), info.line()));
 
         match (self.with_filename, self.with_module) {
-            (false, false) => slog::Logger::root(self.build_format(), o!()),
-            (false, true) => slog::Logger::root(self.build_format(), o!("module" => module)),
-            (true, false) => slog::Logger::root(self.build_format(), o!("file" => filename)),
-            (true, true) => slog::Logger::root(
-                self.build_format(),
-                o!("module" => module, "file" => filename),
-            ),
+            (false, false) => slog::Logger::root(drain, o!()),
+            (false, true) => slog::Logger::root(drain, o!("module" => module)),
+            (true, false) => slog::Logger::root(drain, o!("file" => filename)),
+            (true, true) => slog::Logger::root(drain, o!("module" => module, "file" => filename)),
         }
     }
 
+    /// Construct a new `Logger` that adheres to the configuration
+    pub fn create_logger(&self) -> slog::Logger {
+        self.root(self.build_format())
+    }
+
     /// Set up a global logger that adheres to the configuration
     ///
-    /// This also includes initializing the `log` crate to use the logger.
+    /// This also includes initializing the `log` crate to use the logger. The returned handle
+    /// can change the format, level and filter of the logger later on.
     pub fn create_global_logger(
         &self,
-    ) -> Result<slog_scope::GlobalLoggerGuard, log::SetLoggerError> {
-        let logger = self.create_logger();
+    ) -> Result<(slog_scope::GlobalLoggerGuard, LoggingHandle), log::SetLoggerError> {
+        let handle = LoggingHandle {
+            drain: ReloadableDrain(Arc::new(RwLock::new(Box::new(self.build_format())))),
+            config: Arc::new(Mutex::new(self.clone())),
+        };
+        let logger = self.root(handle.drain.clone());
         let guard = slog_scope::set_global_logger(logger);
         if self.forward_stdlog {
             if let Some(ref level) = self.stdlog_level {
@@ -121,6 +133,57 @@ impl LoggingConfiguration {
             }
         }
 
-        Ok(guard)
+        Ok((guard, handle))
+    }
+}
+
+/// Parse a logging level, also accepting the level names of Redis
+pub fn parse_level(level: &str) -> Option<slog::FilterLevel> {
+    let level = match &level.to_ascii_lowercase()[..] {
+        "warning" => "warn",
+        "notice" => "info",
+        "verbose" => "debug",
+        "nothing" => "off",
+        _ => level,
+    };
+    level.parse().ok()
+}
+
+/// A drain that can be replaced while loggers are using it
+#[derive(Clone)]
+struct ReloadableDrain(Arc<RwLock<Box<Drain<Ok = (), Err = slog::Never> + Send + Sync>>>);
+impl Drain for ReloadableDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        self.0.read().unwrap().log(record, values)
+    }
+}
+
+/// A handle to reconfigure the global logger
+#[derive(Clone)]
+pub struct LoggingHandle {
+    drain: ReloadableDrain,
+    /// The configuration the logger currently adheres to
+    config: Arc<Mutex<LoggingConfiguration>>,
+}
+impl LoggingHandle {
+    /// The configuration the logger currently adheres to
+    pub fn config(&self) -> LoggingConfiguration {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Change the format, level and filter of the logger
+    ///
+    /// The logging context (`with_module` and `with_filename`) and the forwarding of `log` crate
+    /// messages are fixed when the logger is created.
+    pub fn reconfigure(&self, config: LoggingConfiguration) {
+        *self.drain.0.write().unwrap() = Box::new(config.build_format());
+        *self.config.lock().unwrap() = config;
     }
 }